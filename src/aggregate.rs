@@ -0,0 +1,535 @@
+use csv::{ReaderBuilder, StringRecord};
+use geojson::{Feature, FeatureCollection};
+use osm_admin_lookup::boundary::Boundary;
+use osm_admin_lookup::location::Location;
+use osm_admin_lookup::{load_tree, Tree};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "aggregate",
+    about = "count points from stdin per boundary and admin level"
+)]
+struct Opt {
+    /// rtree bin path
+    #[structopt(short = "b", long = "bin")]
+    bin_path: PathBuf,
+
+    /// input format, json lines with a `loc` (lng,lat) or csv with a header
+    #[structopt(long = "input-format", default_value = "json")]
+    input_format: Format,
+
+    /// csv column holding the longitude
+    #[structopt(long = "lng-col", default_value = "lng")]
+    lng_col: String,
+
+    /// csv column holding the latitude
+    #[structopt(long = "lat-col", default_value = "lat")]
+    lat_col: String,
+
+    /// numeric field or csv column to sum up per boundary
+    #[structopt(long = "sum")]
+    sum: Option<String>,
+
+    /// limit results to admin levels, repeat for several
+    #[structopt(short = "a", long = "admin-level")]
+    admin_level: Option<Vec<u8>>,
+
+    /// write a geojson feature collection of the boundaries, with counts
+    /// as properties
+    #[structopt(long = "geojson")]
+    geojson: bool,
+
+    /// lines read and resolved in parallel at once
+    #[structopt(long = "chunk-size", default_value = "10000")]
+    chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}, expected json or csv", s)),
+        }
+    }
+}
+
+/// A point to aggregate, with the value to sum up.
+#[derive(Debug, PartialEq)]
+struct Point {
+    loc: [f64; 2],
+    value: Option<f64>,
+}
+
+/// How to read points of an input line.
+enum Parser {
+    Json {
+        sum: Option<String>,
+    },
+    Csv {
+        lng: usize,
+        lat: usize,
+        sum: Option<usize>,
+    },
+}
+
+impl Parser {
+    fn csv(header: &StringRecord, opt: &Opt) -> Result<Self, String> {
+        let find = |name: &str| {
+            header
+                .iter()
+                .position(|column| column == name)
+                .ok_or(format!("missing csv column {}", name))
+        };
+        Ok(Parser::Csv {
+            lng: find(&opt.lng_col)?,
+            lat: find(&opt.lat_col)?,
+            sum: opt.sum.as_deref().map(find).transpose()?,
+        })
+    }
+
+    fn json(line: &str, sum: Option<&str>) -> Result<Point, String> {
+        let value: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let loc: [f64; 2] = value
+            .get("loc")
+            .map(|loc| serde_json::from_value(loc.clone()))
+            .ok_or("missing loc")?
+            .map_err(|e| format!("invalid loc: {}", e))?;
+        let value = match sum {
+            Some(field) => Some(
+                value
+                    .get(field)
+                    .and_then(|value| value.as_f64())
+                    .ok_or(format!("missing or invalid {}", field))?,
+            ),
+            None => None,
+        };
+        Ok(Point { loc, value })
+    }
+
+    fn parse(&self, record: &Record) -> Result<Point, String> {
+        let point = match (self, record) {
+            (Parser::Json { sum }, Record::Line(line)) => Self::json(line, sum.as_deref())?,
+            (Parser::Csv { lng, lat, sum }, Record::Row(row)) => {
+                let number = |i: usize| {
+                    let field = row.get(i).unwrap_or_default();
+                    field
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|e| format!("invalid number {:?}: {}", field, e))
+                };
+                Point {
+                    loc: [number(*lng)?, number(*lat)?],
+                    value: sum.map(number).transpose()?,
+                }
+            }
+            (_, Record::Unreadable(error)) => return Err(error.clone()),
+            _ => return Err("record does not match input format".to_string()),
+        };
+        let [lng, lat] = point.loc;
+        Location::new(lng, lat).map_err(|e| e.to_string())?;
+        Ok(point)
+    }
+}
+
+/// An input record, json line or csv row.
+enum Record {
+    Line(String),
+    Row(StringRecord),
+    /// a record which could not be read, e.g. a malformed csv row or a
+    /// line which is not utf-8
+    Unreadable(String),
+}
+
+/// A json line, without its line break.
+fn line_record(mut line: Vec<u8>) -> Record {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Record::Line(line),
+        Err(e) => Record::Unreadable(format!("invalid utf-8: {}", e.utf8_error())),
+    }
+}
+
+/// A csv row, only failing to read the input is an error of the run.
+fn row_record(row: csv::Result<StringRecord>) -> io::Result<Record> {
+    match row {
+        Ok(row) => Ok(Record::Row(row)),
+        Err(e) if e.is_io_error() => Err(e.into()),
+        Err(e) => Ok(Record::Unreadable(e.to_string())),
+    }
+}
+
+/// Identifies a boundary across shards.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Key {
+    admin_level: u8,
+    id: Option<i64>,
+    name: String,
+}
+
+impl From<&Boundary> for Key {
+    fn from(boundary: &Boundary) -> Self {
+        Key {
+            admin_level: boundary.admin_level,
+            id: boundary.osm_id,
+            name: boundary.name.clone(),
+        }
+    }
+}
+
+/// Points of one boundary.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Aggregate {
+    pub id: Option<i64>,
+    pub name: String,
+    pub admin_level: u8,
+    pub count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum: Option<f64>,
+}
+
+impl Aggregate {
+    fn key(&self) -> Key {
+        Key {
+            admin_level: self.admin_level,
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Aggregation {
+    boundaries: HashMap<Key, (u64, f64)>,
+    /// features of the boundaries, collected when they are first located,
+    /// if geojson is written
+    features: Option<HashMap<Key, Feature>>,
+    read: usize,
+    unmatched: usize,
+    failed: usize,
+}
+
+impl Aggregation {
+    /// Look up a chunk of records in parallel and add them up, invalid and
+    /// unreadable records are counted as failed.
+    fn add(
+        &mut self,
+        tree: &Tree,
+        parser: &Parser,
+        levels: Option<&[u8]>,
+        chunk: &[Record],
+    ) -> Result<(), io::Error> {
+        let results: Vec<Option<(Point, Vec<Key>)>> = chunk
+            .par_iter()
+            .map(|record| {
+                let point = match parser.parse(record) {
+                    Ok(point) => point,
+                    Err(_) => return Ok(None),
+                };
+                let keys = tree.locate(&point.loc, |boundaries| {
+                    boundaries
+                        .into_iter()
+                        .filter(|b| levels.is_none_or(|levels| levels.contains(&b.admin_level)))
+                        .map(Key::from)
+                        .collect()
+                })?;
+                Ok(Some((point, keys)))
+            })
+            .collect::<Result<_, io::Error>>()?;
+        for result in results {
+            self.read += 1;
+            let (point, keys) = match result {
+                Some(result) => result,
+                None => {
+                    self.failed += 1;
+                    continue;
+                }
+            };
+            if keys.is_empty() {
+                self.unmatched += 1;
+            }
+            if let Some(features) = &mut self.features {
+                // locate once more for boundaries seen the first time
+                if keys.iter().any(|key| !features.contains_key(key)) {
+                    tree.locate(&point.loc, |boundaries| {
+                        for boundary in boundaries {
+                            let key = Key::from(boundary);
+                            if keys.contains(&key) {
+                                features.entry(key).or_insert_with(|| boundary.to_feature());
+                            }
+                        }
+                    })?;
+                }
+            }
+            for key in keys {
+                let (count, sum) = self.boundaries.entry(key).or_default();
+                *count += 1;
+                *sum += point.value.unwrap_or_default();
+            }
+        }
+        Ok(())
+    }
+
+    /// Aggregates by admin level, largest counts first.
+    fn aggregates(&self, with_sum: bool) -> Vec<Aggregate> {
+        let mut aggregates: Vec<Aggregate> = self
+            .boundaries
+            .iter()
+            .map(|(key, (count, sum))| Aggregate {
+                id: key.id,
+                name: key.name.clone(),
+                admin_level: key.admin_level,
+                count: *count,
+                sum: with_sum.then_some(*sum),
+            })
+            .collect();
+        aggregates.sort_by(|a, b| {
+            (a.admin_level, b.count, &a.name).cmp(&(b.admin_level, a.count, &b.name))
+        });
+        aggregates
+    }
+}
+
+impl std::fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "lines read: {}, boundaries: {}, unmatched: {}, failed: {}",
+            self.read,
+            self.boundaries.len(),
+            self.unmatched,
+            self.failed
+        )
+    }
+}
+
+/// Boundary features of the aggregates, in their order, with count and
+/// sum added to their properties.
+fn features(mut features: HashMap<Key, Feature>, aggregates: &[Aggregate]) -> Vec<Feature> {
+    aggregates
+        .iter()
+        .filter_map(|aggregate| {
+            let mut feature = features.remove(&aggregate.key())?;
+            feature.set_property("count", aggregate.count);
+            if let Some(sum) = aggregate.sum {
+                feature.set_property("sum", sum);
+            }
+            Some(feature)
+        })
+        .collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let tree = load_tree(&opt.bin_path)?;
+    let (records, parser): (Box<dyn Iterator<Item = io::Result<Record>>>, Parser) =
+        match opt.input_format {
+            Format::Json => {
+                let lines = io::BufReader::new(io::stdin()).split(b'\n');
+                let parser = Parser::Json {
+                    sum: opt.sum.clone(),
+                };
+                (Box::new(lines.map(|line| line.map(line_record))), parser)
+            }
+            Format::Csv => {
+                let mut reader = ReaderBuilder::new().flexible(true).from_reader(io::stdin());
+                let parser = Parser::csv(reader.headers()?, &opt)?;
+                let rows = reader.into_records().map(row_record);
+                (Box::new(rows), parser)
+            }
+        };
+
+    let mut aggregation = Aggregation::default();
+    if opt.geojson {
+        aggregation.features = Some(HashMap::new());
+    }
+    let levels = opt.admin_level.as_deref();
+    let mut records = records.peekable();
+    while records.peek().is_some() {
+        let chunk = records
+            .by_ref()
+            .take(opt.chunk_size.max(1))
+            .collect::<io::Result<Vec<Record>>>()?;
+        aggregation.add(&tree, &parser, levels, &chunk)?;
+    }
+    eprintln!("{}", aggregation);
+
+    let aggregates = aggregation.aggregates(opt.sum.is_some());
+    let mut out = BufWriter::new(io::stdout());
+    if let Some(found) = aggregation.features.take() {
+        let collection = FeatureCollection {
+            bbox: None,
+            features: features(found, &aggregates),
+            foreign_members: None,
+        };
+        writeln!(out, "{}", collection)?;
+    } else {
+        for aggregate in &aggregates {
+            writeln!(out, "{}", serde_json::to_string(aggregate)?)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use osm_admin_lookup::RTree;
+
+    fn square(x: f64, name: &str, level: u8) -> Boundary {
+        let polygon = polygon![
+            (x: x, y: 0.),
+            (x: x + 1., y: 0.),
+            (x: x + 1., y: 1.),
+            (x: x, y: 1.),
+        ];
+        Boundary::new(polygon.into(), name, level)
+    }
+
+    #[test]
+    fn counts_and_sums_per_boundary() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west", 8),
+            square(2., "east", 8),
+            square(0., "state", 4),
+        ]));
+        let lines = [
+            r#"{"loc": [0.5, 0.5], "amount": 2}"#,
+            r#"{"loc": [0.6, 0.5], "amount": 3.5}"#,
+            r#"{"loc": [2.5, 0.5], "amount": 1}"#,
+            r#"{"loc": [5.5, 0.5], "amount": 1}"#,
+            r#"{"loc": [0.5, 0.5]}"#,
+            r#"{"loc": [0.5, 95]}"#,
+        ];
+        let records: Vec<Record> = lines.iter().map(|l| Record::Line(l.to_string())).collect();
+        let parser = Parser::Json {
+            sum: Some("amount".to_string()),
+        };
+        let mut aggregation = Aggregation::default();
+        aggregation.add(&tree, &parser, None, &records).unwrap();
+        let summary = "lines read: 6, boundaries: 3, unmatched: 1, failed: 2";
+        assert_eq!(aggregation.to_string(), summary);
+
+        let aggregates = aggregation.aggregates(true);
+        let aggregates: Vec<(u8, &str, u64, Option<f64>)> = aggregates
+            .iter()
+            .map(|a| (a.admin_level, a.name.as_str(), a.count, a.sum))
+            .collect();
+        assert_eq!(
+            aggregates,
+            [
+                (4, "state", 2, Some(5.5)),
+                (8, "west", 2, Some(5.5)),
+                (8, "east", 1, Some(1.)),
+            ]
+        );
+
+        let mut aggregation = Aggregation::default();
+        let parser = Parser::Json { sum: None };
+        aggregation
+            .add(&tree, &parser, Some(&[8]), &records)
+            .unwrap();
+        let aggregates = aggregation.aggregates(false);
+        assert_eq!(aggregates.len(), 2);
+        assert_eq!(aggregates[0].count, 3);
+        assert_eq!(aggregates[0].sum, None);
+    }
+
+    #[test]
+    fn collects_features_of_located_boundaries() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west", 8),
+            square(2., "east", 8),
+        ]));
+        let lines = [
+            r#"{"loc": [0.5, 0.5]}"#,
+            r#"{"loc": [0.6, 0.5]}"#,
+            r#"{"loc": [2.5, 0.5]}"#,
+        ];
+        let records: Vec<Record> = lines.iter().map(|l| Record::Line(l.to_string())).collect();
+        let parser = Parser::Json { sum: None };
+        let mut aggregation = Aggregation {
+            features: Some(HashMap::new()),
+            ..Default::default()
+        };
+        aggregation.add(&tree, &parser, None, &records).unwrap();
+        let aggregates = aggregation.aggregates(false);
+        let found = aggregation.features.take().unwrap();
+        let features = features(found, &aggregates);
+        let properties: Vec<_> = features
+            .iter()
+            .map(|f| (f.property("name").unwrap(), f.property("count").unwrap()))
+            .collect();
+        assert_eq!(
+            properties,
+            [(&"west".into(), &2.into()), (&"east".into(), &1.into())]
+        );
+    }
+
+    #[test]
+    fn reads_csv_columns() {
+        let header = StringRecord::from(vec!["name", "lon", "lat", "amount"]);
+        let opt = Opt::from_iter(["aggregate", "-b", "tree.bin", "--lng-col", "lon"]);
+        let parser = Parser::csv(&header, &opt).unwrap();
+        let row = Record::Row(StringRecord::from(vec!["a", "0.5", " 1.5", "x"]));
+        let expected = Point {
+            loc: [0.5, 1.5],
+            value: None,
+        };
+        assert_eq!(parser.parse(&row), Ok(expected));
+
+        let opt = Opt::from_iter(["aggregate", "-b", "tree.bin", "--sum", "total"]);
+        assert!(Parser::csv(&header, &opt).is_err());
+    }
+
+    #[test]
+    fn counts_unreadable_records_as_failed() {
+        let tree = Tree::from(RTree::bulk_load(vec![square(0., "west", 8)]));
+        let input = b"{\"loc\": [0.5, 0.5]}\r\n\xff\n{\"loc\": [0.6, 0.5]}";
+        let records = BufRead::split(&input[..], b'\n')
+            .map(|line| line.map(line_record))
+            .collect::<io::Result<Vec<Record>>>()
+            .unwrap();
+        let parser = Parser::Json { sum: None };
+        let mut aggregation = Aggregation::default();
+        aggregation.add(&tree, &parser, None, &records).unwrap();
+        let summary = "lines read: 3, boundaries: 1, unmatched: 0, failed: 1";
+        assert_eq!(aggregation.to_string(), summary);
+
+        let input = b"lng,lat\n0.5,0.5\n\xff,0.5\n";
+        let mut reader = ReaderBuilder::new().flexible(true).from_reader(&input[..]);
+        let header = reader.headers().unwrap().clone();
+        let opt = Opt::from_iter(["aggregate", "-b", "tree.bin"]);
+        let parser = Parser::csv(&header, &opt).unwrap();
+        let records = reader
+            .into_records()
+            .map(row_record)
+            .collect::<io::Result<Vec<Record>>>()
+            .unwrap();
+        assert!(matches!(records[1], Record::Unreadable(_)));
+        let mut aggregation = Aggregation::default();
+        aggregation.add(&tree, &parser, None, &records).unwrap();
+        let summary = "lines read: 2, boundaries: 1, unmatched: 0, failed: 1";
+        assert_eq!(aggregation.to_string(), summary);
+    }
+}
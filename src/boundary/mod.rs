@@ -1,52 +1,123 @@
+use crate::validation::{assemble_closing_rings, repair, Repair};
+use geo::algorithm::area::Area;
 use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::centroid::Centroid;
 use geo::algorithm::contains::Contains;
+use geo::algorithm::geodesic_area::GeodesicArea;
+use geo::algorithm::orient::{Direction, Orient};
 use geo_types::{MultiPolygon, Point};
+use indicatif::ProgressBar;
 use osm_boundaries_utils::build_boundary;
 use osmpbfreader::{OsmId, OsmObj, OsmPbfReader, Relation};
+use rayon::prelude::*;
 use rstar::primitives::Rectangle;
 use rstar::Envelope;
 use rstar::{PointDistance, RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
 use std::fs::File;
 use std::path::PathBuf;
 
+mod polylabel;
+pub use polylabel::pole_of_inaccessibility;
+
 type Point2D = [f64; 2];
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Roles of relation members resolved to a `MemberNode`.
+pub const MEMBER_ROLES: [&str; 2] = ["admin_centre", "label"];
+
+/// A node member of a boundary relation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemberNode {
+    pub osm_id: i64,
+    pub name: Option<String>,
+    pub point: Point<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Boundary {
     rect: Rectangle<Point2D>,
+    /// id of the osm relation, `None` for boundaries from other sources
+    pub osm_id: Option<i64>,
     pub name: String,
     pub admin_level: u8,
-    area: f64,
+    /// geodesic area in km²
+    pub area: f64,
+    pub centroid: Point<f64>,
+    /// interior point farthest from the boundary's border, e.g. to place
+    /// a label
+    pub pole: Point<f64>,
+    /// seat of administration, from the relation's `admin_centre` member
+    pub admin_centre: Option<MemberNode>,
+    /// label position, from the relation's `label` member
+    pub label: Option<MemberNode>,
+    /// tags of the osm relation in `KEPT_TAGS`, attributes for boundaries
+    /// from other sources
+    pub tags: BTreeMap<String, String>,
     pub mp: MultiPolygon<f64>,
 }
 
 impl Boundary {
     pub fn new(mp: MultiPolygon<f64>, name: &str, admin_level: u8) -> Self {
-        let rect = mp.bounding_rect().expect("yo");
-        let lower = [rect.min().x, rect.min().y];
-        let upper = [rect.max().x, rect.max().y];
-        let aabb = AABB::from_corners(lower, upper);
-        let area = aabb.area();
-        let rect = Rectangle::from_aabb(aabb);
+        let rect = envelope(&mp);
+        let (area, centroid, pole) = measures(&mp, &rect);
         let name = name.to_string();
         Boundary {
             rect,
+            osm_id: None,
             name,
-            area,
             admin_level,
+            area,
+            centroid,
+            pole,
+            admin_centre: None,
+            label: None,
+            tags: BTreeMap::new(),
             mp,
         }
     }
 
+    /// Replace the geometry, e.g. with a simplified one, and update the
+    /// envelope and measures accordingly.
+    pub fn set_geometry(&mut self, mp: MultiPolygon<f64>) {
+        self.rect = envelope(&mp);
+        let (area, centroid, pole) = measures(&mp, &self.rect);
+        self.area = area;
+        self.centroid = centroid;
+        self.pole = pole;
+        self.mp = mp;
+    }
+
     pub fn contains(&self, point: &Point2D) -> bool {
         let [x, y] = point;
         self.mp.contains(&Point::new(*x, *y))
     }
 }
 
+fn envelope(mp: &MultiPolygon<f64>) -> Rectangle<Point2D> {
+    let rect = mp.bounding_rect().expect("yo");
+    let lower = [rect.min().x, rect.min().y];
+    let upper = [rect.max().x, rect.max().y];
+    Rectangle::from_corners(lower, upper)
+}
+
+/// Geodesic area in km², regardless of the winding of the rings.
+pub fn geodesic_area_km2(mp: &MultiPolygon<f64>) -> f64 {
+    // winding matters on the sphere, a clockwise ring encloses the rest of
+    // the globe
+    mp.orient(Direction::Default).geodesic_area_unsigned() / 1e6
+}
+
+/// Geodesic area in km², centroid and pole of inaccessibility.
+fn measures(mp: &MultiPolygon<f64>, rect: &Rectangle<Point2D>) -> (f64, Point<f64>, Point<f64>) {
+    let area = geodesic_area_km2(mp);
+    let center = Point::from(rect.envelope().center());
+    let centroid = mp.centroid().unwrap_or(center);
+    let pole = pole_of_inaccessibility(mp).unwrap_or(centroid);
+    (area, centroid, pole)
+}
+
 impl RTreeObject for Boundary {
     type Envelope = AABB<Point2D>;
 
@@ -74,19 +145,15 @@ impl OsmObjExt for OsmObj {
     }
 }
 
-fn get_admin<'a>(obj: &'a OsmObj, admin_levels: &[u8]) -> Option<&'a Relation> {
+pub fn get_admin(obj: &OsmObj) -> Option<&Relation> {
     let rel = obj.get_relation()?;
     if !obj.tags().contains("boundary", "administrative") {
         return None;
     }
-    let level: u8 = obj.tags().get("admin_level")?.parse().ok()?;
-    if !admin_levels.contains(&level) {
-        return None;
-    }
     Some(rel)
 }
 
-type OsmMap = BTreeMap<OsmId, OsmObj>;
+pub type OsmMap = BTreeMap<OsmId, OsmObj>;
 fn get_btree(file: File) -> Result<OsmMap, Box<dyn Error>> {
     let mut pbf = OsmPbfReader::new(file);
 
@@ -98,31 +165,306 @@ fn get_btree(file: File) -> Result<OsmMap, Box<dyn Error>> {
     Ok(tuples)
 }
 
-pub fn get_osm_boundaries(
-    path: PathBuf,
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    MissingName,
+    InvalidAdminLevel,
+    BrokenGeometry,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkippedRelation {
+    pub id: i64,
+    pub name: Option<String>,
+    pub admin_level: Option<u8>,
+    pub reason: SkipReason,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RepairedRelation {
+    pub id: i64,
+    pub name: String,
+    pub admin_level: u8,
+    pub repairs: Vec<Repair>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct LevelSummary {
+    pub built: usize,
+    pub skipped: usize,
+    pub repaired: usize,
+}
+
+/// Relations which could not be turned into a `Boundary` or had their
+/// geometry repaired, and per level counts of built, skipped and repaired
+/// relations.
+#[derive(Serialize, Debug, Default)]
+pub struct BuildReport {
+    pub levels: BTreeMap<u8, LevelSummary>,
+    pub skipped: Vec<SkippedRelation>,
+    pub repaired: Vec<RepairedRelation>,
+}
+
+impl BuildReport {
+    fn add_built(&mut self, admin_level: u8) {
+        self.levels.entry(admin_level).or_default().built += 1;
+    }
+
+    fn add_repaired(&mut self, relation: RepairedRelation) {
+        self.levels
+            .entry(relation.admin_level)
+            .or_default()
+            .repaired += 1;
+        self.repaired.push(relation);
+    }
+
+    fn add_skipped(&mut self, relation: SkippedRelation) {
+        if let Some(admin_level) = relation.admin_level {
+            self.levels.entry(admin_level).or_default().skipped += 1;
+        }
+        self.skipped.push(relation);
+    }
+
+    /// Drop entries for relations which have been built from another input
+    /// or are listed more than once, and recount the levels.
+    fn dedup(&mut self, boundaries: &[Boundary]) {
+        let built: BTreeSet<i64> = boundaries.iter().filter_map(|b| b.osm_id).collect();
+        let mut skipped = BTreeSet::new();
+        self.skipped
+            .retain(|relation| !built.contains(&relation.id) && skipped.insert(relation.id));
+        let mut repaired = BTreeSet::new();
+        self.repaired
+            .retain(|relation| repaired.insert(relation.id));
+
+        self.levels.clear();
+        let levels = boundaries
+            .iter()
+            .filter(|boundary| boundary.osm_id.is_some())
+            .map(|boundary| boundary.admin_level);
+        for admin_level in levels {
+            self.add_built(admin_level);
+        }
+        for relation in &self.skipped {
+            if let Some(admin_level) = relation.admin_level {
+                self.levels.entry(admin_level).or_default().skipped += 1;
+            }
+        }
+        for relation in &self.repaired {
+            self.levels
+                .entry(relation.admin_level)
+                .or_default()
+                .repaired += 1;
+        }
+    }
+
+    /// Record the result of assembling relation `id`, yields the boundary
+    /// if it was built.
+    pub(crate) fn add(
+        &mut self,
+        id: i64,
+        result: Result<(Boundary, Vec<Repair>), SkippedRelation>,
+    ) -> Option<Boundary> {
+        match result {
+            Ok((boundary, repairs)) => {
+                self.add_built(boundary.admin_level);
+                if !repairs.is_empty() {
+                    self.add_repaired(RepairedRelation {
+                        id,
+                        name: boundary.name.clone(),
+                        admin_level: boundary.admin_level,
+                        repairs,
+                    });
+                }
+                Some(boundary)
+            }
+            Err(skipped) => {
+                self.add_skipped(skipped);
+                None
+            }
+        }
+    }
+}
+
+/// The first node member of `rel` with `role`, if it is present in `btree`.
+fn member_node(rel: &Relation, btree: &OsmMap, role: &str) -> Option<MemberNode> {
+    rel.refs
+        .iter()
+        .filter(|r| r.role == role)
+        .find_map(|r| btree.get(&r.member)?.node())
+        .map(|node| MemberNode {
+            osm_id: node.id.0,
+            name: node.tags.get("name").map(|name| name.to_string()),
+            point: Point::new(node.lon(), node.lat()),
+        })
+}
+
+/// Tags of osm relations stored with their boundaries, a trailing `*`
+/// matches any suffix. Names and levels are stored anyway, other tags
+/// would grow every bin.
+pub const KEPT_TAGS: &[&str] = &["boundary", "ref", "wikidata", "ISO3166-*"];
+
+fn is_kept_tag(key: &str) -> bool {
+    KEPT_TAGS.iter().any(|kept| match kept.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == *kept,
+    })
+}
+
+/// Assemble a boundary from an administrative relation. Relations on other
+/// levels than requested yield `None`. With `validate`, rings which cannot
+/// be assembled are closed and the geometry is repaired.
+pub(crate) fn assemble(
+    rel: &Relation,
+    btree: &OsmMap,
     admin_levels: &[u8],
-) -> Result<Vec<Boundary>, Box<dyn Error>> {
-    let file = File::open(path)?;
-    let btree = get_btree(file)?;
+    validate: bool,
+) -> Option<Result<(Boundary, Vec<Repair>), SkippedRelation>> {
+    let name = rel.tags.get("name");
+    let skipped = |admin_level, reason| SkippedRelation {
+        id: rel.id.0,
+        name: name.map(|name| name.to_string()),
+        admin_level,
+        reason,
+    };
+
+    let admin_level = rel.tags.get("admin_level").and_then(|l| l.parse().ok());
+    let admin_level = match admin_level {
+        Some(level) if admin_levels.contains(&level) => level,
+        Some(_) => return None,
+        None => return Some(Err(skipped(None, SkipReason::InvalidAdminLevel))),
+    };
+    let name = match name {
+        Some(name) => name,
+        None => return Some(Err(skipped(Some(admin_level), SkipReason::MissingName))),
+    };
+    let broken = || Some(Err(skipped(Some(admin_level), SkipReason::BrokenGeometry)));
+    let mut repairs = vec![];
+    let multi_polygon = match build_boundary(rel, btree) {
+        Some(multi_polygon) => multi_polygon,
+        None if validate => match assemble_closing_rings(rel, btree) {
+            Some((multi_polygon, closed)) => {
+                repairs.extend(closed);
+                multi_polygon
+            }
+            None => return broken(),
+        },
+        None => return broken(),
+    };
+    let multi_polygon = if validate {
+        let (multi_polygon, repaired) = repair(multi_polygon);
+        repairs.extend(repaired);
+        multi_polygon
+    } else {
+        multi_polygon
+    };
+    if multi_polygon.0.is_empty() {
+        return broken();
+    }
+    let mut boundary = Boundary::new(multi_polygon, name, admin_level);
+    boundary.osm_id = Some(rel.id.0);
+    boundary.admin_centre = member_node(rel, btree, "admin_centre");
+    boundary.label = member_node(rel, btree, "label");
+    boundary.tags = rel
+        .tags
+        .iter()
+        .filter(|(key, _)| is_kept_tag(key))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Some(Ok((boundary, repairs)))
+}
 
-    let boundaries = btree
-        .values()
-        .filter_map(|obj| get_admin(obj, admin_levels))
+pub fn get_boundaries(
+    btree: &OsmMap,
+    admin_levels: &[u8],
+    validate: bool,
+    report: &mut BuildReport,
+) -> Vec<Boundary> {
+    let progress = ProgressBar::hidden();
+    get_boundaries_with_progress(btree, admin_levels, validate, report, &progress)
+}
+
+/// Assemble boundaries in parallel on the current rayon thread pool, the
+/// progress bar is advanced per administrative relation.
+pub fn get_boundaries_with_progress(
+    btree: &OsmMap,
+    admin_levels: &[u8],
+    validate: bool,
+    report: &mut BuildReport,
+    progress: &ProgressBar,
+) -> Vec<Boundary> {
+    let relations: Vec<&Relation> = btree.values().filter_map(get_admin).collect();
+    progress.set_length(relations.len() as u64);
+    let results: Vec<_> = relations
+        .into_par_iter()
         .filter_map(|rel| {
-            let name = rel.tags.get("name")?;
-            let admin_level = rel.tags.get("admin_level")?.parse().ok()?;
-            let multi_polygon = build_boundary(rel, &btree)?;
-            let boundary = Boundary::new(multi_polygon, name, admin_level);
-            Some(boundary)
+            let result = assemble(rel, btree, admin_levels, validate);
+            progress.inc(1);
+            Some((rel.id.0, result?))
         })
         .collect();
-    Ok(boundaries)
+    progress.finish_and_clear();
+
+    let mut boundaries = vec![];
+    for (id, result) in results {
+        if let Some(boundary) = report.add(id, result) {
+            boundaries.push(boundary);
+        }
+    }
+    boundaries
+}
+
+/// Merge boundaries built from several inputs. Relations present in more
+/// than one input, e.g. national borders in neighbouring extracts, are
+/// kept once with the most complete geometry, the one covering the largest
+/// area.
+pub fn dedup_boundaries(boundaries: Vec<Boundary>, report: &mut BuildReport) -> Vec<Boundary> {
+    let mut merged: Vec<Boundary> = vec![];
+    let mut positions: HashMap<i64, usize> = HashMap::new();
+    for boundary in boundaries {
+        let id = match boundary.osm_id {
+            Some(id) => id,
+            None => {
+                merged.push(boundary);
+                continue;
+            }
+        };
+        match positions.get(&id) {
+            Some(&i) => {
+                if boundary.mp.unsigned_area() > merged[i].mp.unsigned_area() {
+                    merged[i] = boundary;
+                }
+            }
+            None => {
+                positions.insert(id, merged.len());
+                merged.push(boundary);
+            }
+        }
+    }
+    report.dedup(&merged);
+    merged
+}
+
+pub fn read_osm(path: PathBuf) -> Result<OsmMap, Box<dyn Error>> {
+    let file = File::open(path)?;
+    get_btree(file)
+}
+
+pub fn get_osm_boundaries(
+    path: PathBuf,
+    admin_levels: &[u8],
+    validate: bool,
+    report: &mut BuildReport,
+) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let btree = read_osm(path)?;
+    Ok(get_boundaries(&btree, admin_levels, validate, report))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use geo::polygon;
+    use osm_boundaries_utils::osm_builder::{named_node, OsmBuilder};
     use rstar::RTree;
 
     struct AABBWrapper(AABB<Point2D>);
@@ -142,7 +484,7 @@ mod tests {
     }
 
     fn get_test_boundaries() -> Vec<Boundary> {
-        let boundaries = vec![
+        let boundaries = [
             ([0.0, 0.0], [0.4, 1.0], "left"),
             ([0.0, 0.0], [0.3, 1.0], "small left"),
             ([0.6, 0.0], [1.0, 1.0], "right"),
@@ -167,6 +509,116 @@ mod tests {
             .collect()
     }
 
+    fn admin_relation(builder: &mut OsmBuilder, tags: &[(&str, &str)], closed: bool) {
+        let mut coords = vec![
+            named_node(0.0, 0.0, "a"),
+            named_node(0.0, 1.0, "b"),
+            named_node(1.0, 1.0, "c"),
+        ];
+        if closed {
+            coords.push(named_node(0.0, 0.0, "a"));
+        }
+        let id = builder.relation().outer(coords).relation_id;
+        if let Some(OsmObj::Relation(rel)) = builder.objects.get_mut(&id.into()) {
+            rel.tags.insert("boundary".into(), "administrative".into());
+            for (key, value) in tags {
+                rel.tags.insert((*key).into(), (*value).into());
+            }
+        }
+    }
+
+    #[test]
+    fn reports_skipped_relations() {
+        let mut builder = OsmBuilder::new();
+        admin_relation(&mut builder, &[("name", "ok"), ("admin_level", "8")], true);
+        admin_relation(
+            &mut builder,
+            &[("name", "other level"), ("admin_level", "2")],
+            true,
+        );
+        admin_relation(&mut builder, &[("admin_level", "8")], true);
+        admin_relation(&mut builder, &[("name", "no level")], true);
+        admin_relation(
+            &mut builder,
+            &[("name", "broken"), ("admin_level", "8")],
+            false,
+        );
+
+        let mut report = BuildReport::default();
+        let boundaries = get_boundaries(&builder.objects, &[8], false, &mut report);
+        assert_eq!(boundaries.len(), 1);
+        assert_eq!(boundaries[0].name, "ok");
+        let reasons: Vec<SkipReason> = report.skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                SkipReason::MissingName,
+                SkipReason::InvalidAdminLevel,
+                SkipReason::BrokenGeometry
+            ]
+        );
+        assert_eq!(report.skipped[2].name.as_deref(), Some("broken"));
+        assert_eq!(report.levels[&8].built, 1);
+        assert_eq!(report.levels[&8].skipped, 2);
+        assert!(!report.levels.contains_key(&2));
+    }
+
+    #[test]
+    fn reports_repaired_relations() {
+        let mut builder = OsmBuilder::new();
+        admin_relation(
+            &mut builder,
+            &[("name", "broken"), ("admin_level", "8")],
+            false,
+        );
+
+        let mut report = BuildReport::default();
+        let boundaries = get_boundaries(&builder.objects, &[8], true, &mut report);
+        assert_eq!(boundaries.len(), 1);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.repaired[0].name, "broken");
+        assert!(report.repaired[0].repairs.contains(&Repair::ClosedRing));
+        assert_eq!(report.levels[&8].repaired, 1);
+    }
+
+    #[test]
+    fn keeps_most_complete_duplicate() {
+        let tags = [("name", "border"), ("admin_level", "8")];
+        let mut complete = OsmBuilder::new();
+        admin_relation(&mut complete, &tags, true);
+        let mut broken = OsmBuilder::new();
+        admin_relation(&mut broken, &tags, false);
+        let mut clipped = OsmBuilder::new();
+        let id = clipped
+            .relation()
+            .outer(vec![
+                named_node(0.0, 0.0, "a"),
+                named_node(0.0, 0.5, "b"),
+                named_node(0.5, 0.5, "c"),
+                named_node(0.0, 0.0, "a"),
+            ])
+            .relation_id;
+        if let Some(OsmObj::Relation(rel)) = clipped.objects.get_mut(&id.into()) {
+            rel.tags.insert("boundary".into(), "administrative".into());
+            for (key, value) in &tags {
+                rel.tags.insert((*key).into(), (*value).into());
+            }
+        }
+
+        let mut report = BuildReport::default();
+        let mut boundaries = vec![];
+        for builder in &[clipped, broken, complete] {
+            boundaries.extend(get_boundaries(&builder.objects, &[8], false, &mut report));
+        }
+        assert_eq!(boundaries.len(), 2);
+        let boundaries = dedup_boundaries(boundaries, &mut report);
+        assert_eq!(boundaries.len(), 1);
+        assert_eq!(boundaries[0].mp.unsigned_area(), 0.5);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.levels[&8].built, 1);
+        assert_eq!(report.levels[&8].skipped, 0);
+    }
+
     #[test]
     fn locates_points_in_boundaries() {
         let boundaries = get_test_boundaries();
@@ -181,4 +633,53 @@ mod tests {
         let empty: Vec<String> = vec![];
         assert_eq!(names, empty);
     }
+
+    #[test]
+    fn measures_boundaries() {
+        let boundaries = get_test_boundaries();
+        let huge = boundaries.iter().find(|b| b.name == "huge").unwrap();
+        // a square degree at the equator
+        assert!((huge.area - 12_308.).abs() < 10., "area {}", huge.area);
+        assert_eq!(huge.centroid, Point::new(0.5, 0.5));
+        assert!((huge.pole.x() - 0.5).abs() < 1e-3);
+        assert!((huge.pole.y() - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn resolves_member_nodes() {
+        let mut builder = OsmBuilder::new();
+        admin_relation(
+            &mut builder,
+            &[("admin_level", "8"), ("name", "square")],
+            true,
+        );
+        let node = builder.node(Point::new(0.25, 0.5), Some("centre".into()));
+        if let Some(OsmObj::Node(node)) = builder.objects.get_mut(&node.into()) {
+            node.tags.insert("name".into(), "Town".into());
+        }
+        if let Some(OsmObj::Relation(rel)) = builder.objects.values_mut().find(|o| o.is_relation())
+        {
+            rel.refs.push(osmpbfreader::Ref {
+                member: node.into(),
+                role: "admin_centre".into(),
+            });
+        }
+        let mut report = BuildReport::default();
+        let boundaries = get_boundaries(&builder.objects, &[8], false, &mut report);
+        let admin_centre = boundaries[0].admin_centre.as_ref().unwrap();
+        assert_eq!(admin_centre.osm_id, node.0);
+        assert_eq!(admin_centre.name.as_deref(), Some("Town"));
+        assert_eq!(admin_centre.point, Point::new(0.25, 0.5));
+        assert_eq!(boundaries[0].label, None);
+    }
+
+    #[test]
+    fn keeps_listed_tags() {
+        for key in &["boundary", "ref", "wikidata", "ISO3166-1", "ISO3166-2"] {
+            assert!(is_kept_tag(key), "{}", key);
+        }
+        for key in &["name", "name:de", "admin_level", "source", "references"] {
+            assert!(!is_kept_tag(key), "{}", key);
+        }
+    }
 }
@@ -0,0 +1,217 @@
+use geo::algorithm::area::Area;
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::centroid::Centroid;
+use geo_types::{Coord, LineString, MultiPolygon, Point, Polygon};
+use rstar::primitives::Line;
+use rstar::{PointDistance, RTree, AABB};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Precision relative to the size of the polygon.
+const PRECISION: f64 = 1e-3;
+/// Upper bound of cells to probe, the search stops early for huge inputs.
+const MAX_CELLS: usize = 10_000;
+
+struct Cell {
+    center: Coord<f64>,
+    half: f64,
+    distance: f64,
+    max: f64,
+}
+
+impl Cell {
+    fn new(center: Coord<f64>, half: f64, segments: &Segments) -> Self {
+        let distance = segments.signed_distance(center);
+        let max = distance + half * std::f64::consts::SQRT_2;
+        Cell {
+            center,
+            half,
+            distance,
+            max,
+        }
+    }
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max
+    }
+}
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.max.total_cmp(&other.max)
+    }
+}
+
+fn scale_x(polygon: &Polygon<f64>, scale: f64) -> Polygon<f64> {
+    let ring = |ring: &LineString<f64>| {
+        ring.coords()
+            .map(|c| Coord {
+                x: c.x * scale,
+                y: c.y,
+            })
+            .collect()
+    };
+    Polygon::new(
+        ring(polygon.exterior()),
+        polygon.interiors().iter().map(ring).collect(),
+    )
+}
+
+/// Ring segments of a polygon in an rtree, so probing a cell does not scan
+/// every segment of large rings.
+struct Segments {
+    tree: RTree<Line<[f64; 2]>>,
+    max_x: f64,
+}
+
+impl Segments {
+    fn new(polygon: &Polygon<f64>) -> Self {
+        let rings = std::iter::once(polygon.exterior()).chain(polygon.interiors());
+        let lines: Vec<Line<[f64; 2]>> = rings
+            .flat_map(LineString::lines)
+            .map(|line| Line::new(line.start.into(), line.end.into()))
+            .collect();
+        let max_x = lines
+            .iter()
+            .map(|line| line.from[0].max(line.to[0]))
+            .fold(f64::NEG_INFINITY, f64::max);
+        Segments {
+            tree: RTree::bulk_load(lines),
+            max_x,
+        }
+    }
+
+    /// Distance to the closest ring, negative outside of the polygon.
+    fn signed_distance(&self, p: Coord<f64>) -> f64 {
+        let point = [p.x, p.y];
+        let min_2 = match self.tree.nearest_neighbor(&point) {
+            Some(line) => line.distance_2(&point),
+            None => return f64::NEG_INFINITY,
+        };
+        // count the segments crossed by a ray to the right of `p`
+        let ray = AABB::from_corners(point, [self.max_x.max(p.x), p.y]);
+        let crossings = self
+            .tree
+            .locate_in_envelope_intersecting(&ray)
+            .filter(|line| {
+                let (a, b) = (line.from, line.to);
+                (a[1] > p.y) != (b[1] > p.y)
+                    && p.x < (b[0] - a[0]) * (p.y - a[1]) / (b[1] - a[1]) + a[0]
+            })
+            .count();
+        match crossings % 2 == 1 {
+            true => min_2.sqrt(),
+            false => -min_2.sqrt(),
+        }
+    }
+}
+
+/// Pole of inaccessibility of a polygon, the interior point farthest from
+/// its rings, found with the polylabel algorithm. Longitudes are scaled by
+/// the cosine of the latitude, so distances are roughly isotropic.
+fn polygon_pole(polygon: &Polygon<f64>) -> Option<Point<f64>> {
+    let rect = polygon.bounding_rect()?;
+    let scale = rect.center().y.to_radians().cos().max(1e-6);
+    let scaled = scale_x(polygon, scale);
+    let rect = scaled.bounding_rect()?;
+    let segments = Segments::new(&scaled);
+    let size = rect.width().min(rect.height());
+    if size <= 0. {
+        return Some(rect.min().into());
+    }
+    let precision = rect.width().max(rect.height()) * PRECISION;
+
+    let half = size / 2.;
+    let mut queue = BinaryHeap::new();
+    let mut x = rect.min().x;
+    while x < rect.max().x {
+        let mut y = rect.min().y;
+        while y < rect.max().y {
+            let center = Coord {
+                x: x + half,
+                y: y + half,
+            };
+            queue.push(Cell::new(center, half, &segments));
+            y += size;
+        }
+        x += size;
+    }
+
+    let start = scaled.centroid().map_or(rect.center(), |c| c.0);
+    let mut best = Cell::new(start, 0., &segments);
+    let mut probed = queue.len();
+    while let Some(cell) = queue.pop() {
+        if cell.distance > best.distance {
+            best = Cell::new(cell.center, 0., &segments);
+        }
+        if cell.max - best.distance <= precision || probed >= MAX_CELLS {
+            continue;
+        }
+        let half = cell.half / 2.;
+        for (dx, dy) in &[(-1., -1.), (1., -1.), (-1., 1.), (1., 1.)] {
+            let center = Coord {
+                x: cell.center.x + dx * half,
+                y: cell.center.y + dy * half,
+            };
+            queue.push(Cell::new(center, half, &segments));
+        }
+        probed += 4;
+    }
+    Some(Point::new(best.center.x / scale, best.center.y))
+}
+
+/// Pole of inaccessibility of the largest polygon.
+pub fn pole_of_inaccessibility(mp: &MultiPolygon<f64>) -> Option<Point<f64>> {
+    let largest =
+        mp.0.iter()
+            .max_by(|a, b| a.unsigned_area().total_cmp(&b.unsigned_area()))?;
+    polygon_pole(largest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+
+    #[test]
+    fn finds_pole_of_l_shape() {
+        // the centroid of an L shape is outside of it
+        let l_shape: MultiPolygon<f64> = polygon![
+            (x: 0., y: 0.),
+            (x: 0.04, y: 0.),
+            (x: 0.04, y: 0.01),
+            (x: 0.01, y: 0.01),
+            (x: 0.01, y: 0.04),
+            (x: 0., y: 0.04),
+        ]
+        .into();
+        let pole = pole_of_inaccessibility(&l_shape).unwrap();
+        assert!(pole.x() < 0.01 && pole.y() < 0.01);
+        let segments = Segments::new(&l_shape.0[0]);
+        assert!(segments.signed_distance(pole.0) > 0.004);
+        assert!(segments.signed_distance(Coord { x: 0.02, y: 0.02 }) < 0.);
+    }
+
+    #[test]
+    fn finds_pole_of_large_rings() {
+        let ring: LineString<f64> = (0..=20_000)
+            .map(|i| {
+                let angle = i as f64 / 20_000. * std::f64::consts::TAU;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        let circle = MultiPolygon(vec![Polygon::new(ring, vec![])]);
+        let pole = pole_of_inaccessibility(&circle).unwrap();
+        assert!(pole.x().abs() < 0.01 && pole.y().abs() < 0.01, "{:?}", pole);
+    }
+}
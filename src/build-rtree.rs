@@ -1,34 +1,251 @@
-use osm_admin_lookup::build_rtree;
+use indicatif::{ProgressBar, ProgressStyle};
+use osm_admin_lookup::boundary::{
+    dedup_boundaries, get_boundaries_with_progress, read_osm, BuildReport, OsmMap,
+};
+use osm_admin_lookup::import::{get_fgb_boundaries, get_shp_boundaries, Crs, FieldMapping};
+use osm_admin_lookup::nodes::NodeStoreKind;
+use osm_admin_lookup::shard::index::write_sharded;
+use osm_admin_lookup::shard::{build_shards, merge_shards};
+use osm_admin_lookup::simplify::simplify_boundaries;
+use osm_admin_lookup::update::source_objects;
+use osm_admin_lookup::{write_index, Index, Metadata, RTree};
 use std::error::Error;
-use std::fs::write;
-use std::path::PathBuf;
+use std::fs::{create_dir, File};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
-pub mod boundary;
-
 #[derive(Debug, StructOpt)]
 #[structopt(name = "build-rtree", about = "build rtree binary")]
 struct Opt {
-    /// input osm PBF path
+    /// input osm PBF path, repeat to merge several extracts
     #[structopt(short = "p", long = "pbf")]
-    pbf_path: PathBuf,
+    pbf_paths: Vec<PathBuf>,
+
+    /// input ESRI shapefile path (.dbf and .prj are expected alongside)
+    #[structopt(long = "shp")]
+    shp_paths: Vec<PathBuf>,
+
+    /// input FlatGeobuf path
+    #[structopt(long = "fgb")]
+    fgb_paths: Vec<PathBuf>,
 
     /// output bin path
     #[structopt(short = "b", long = "bin")]
     bin_path: PathBuf,
 
+    /// output path for a json report on relations which could not be built
+    #[structopt(short = "r", long = "report")]
+    report_path: Option<PathBuf>,
+
+    /// repair invalid osm geometries, repairs are listed in the report
+    #[structopt(long = "validate")]
+    validate: bool,
+
+    /// simplify geometries with a tolerance in metres, shared borders are kept consistent
+    #[structopt(long = "simplify", parse(try_from_str = parse_tolerance))]
+    simplify: Option<f64>,
+
+    /// keep osm source objects in the bin, so it can be updated with update-rtree
+    #[structopt(long = "updatable")]
+    updatable: bool,
+
+    /// read PBF inputs in passes with node locations in an on-disk store
+    /// (dense or sparse) and per-region shards, for planet-scale builds
+    #[structopt(long = "node-store")]
+    node_store: Option<NodeStoreKind>,
+
+    /// directory for node stores and shards, default is the system temp dir
+    #[structopt(long = "work-dir")]
+    work_dir: Option<PathBuf>,
+
+    /// write a sharded bin with grid tiles of the given size in degrees,
+    /// shards are loaded on demand by locate, bulk and the service
+    #[structopt(long = "shard-size")]
+    shard_size: Option<f64>,
+
+    /// number of threads for boundary assembly, default is one per core
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+
     /// admin level to consider
     #[structopt(short = "a", long = "admin-level")]
     admin_level: Option<Vec<u8>>,
+
+    /// shp/fgb attribute holding the boundary name
+    #[structopt(long = "name-field", default_value = "name")]
+    name_field: String,
+
+    /// shp/fgb attribute holding the admin level
+    #[structopt(long = "level-field")]
+    level_field: Option<String>,
+
+    /// admin level assigned to all shp/fgb features, if there is no level field
+    #[structopt(long = "level")]
+    level: Option<u8>,
+
+    /// crs of shp/fgb inputs (e.g. EPSG:25832), default is read from the input
+    #[structopt(long = "crs")]
+    crs: Option<Crs>,
+}
+
+/// A simplification tolerance, a positive number of metres.
+fn parse_tolerance(s: &str) -> Result<f64, String> {
+    let tolerance: f64 = s
+        .parse()
+        .map_err(|e| format!("invalid tolerance {}: {}", s, e))?;
+    match tolerance.is_finite() && tolerance > 0. {
+        true => Ok(tolerance),
+        false => Err(format!("expected a positive number of metres, got {}", s)),
+    }
+}
+
+fn log_summary(report: &BuildReport) {
+    for (level, summary) in &report.levels {
+        eprintln!(
+            "level {}: {} built, {} skipped, {} repaired",
+            level, summary.built, summary.skipped, summary.repaired
+        );
+    }
+    if !report.skipped.is_empty() {
+        eprintln!("{} relations skipped", report.skipped.len());
+    }
+}
+
+fn progress_bar(path: &Path) -> Result<ProgressBar, Box<dyn Error>> {
+    let style = ProgressStyle::with_template(
+        "{msg} [{elapsed_precise}] [{bar:40}] {pos}/{len} relations, eta {eta}",
+    )?
+    .progress_chars("=> ");
+    let progress = ProgressBar::new(0).with_style(style);
+    progress.set_message(path.display().to_string());
+    Ok(progress)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
-    let rtree = build_rtree(
-        opt.pbf_path,
-        &opt.admin_level.unwrap_or_else(|| vec![4, 6, 8, 9, 10]),
-    )?;
-    let encoded: Vec<u8> = bincode::serialize(&rtree)?;
-    write(opt.bin_path, encoded)?;
+    if opt.pbf_paths.is_empty() && opt.shp_paths.is_empty() && opt.fgb_paths.is_empty() {
+        return Err("at least one of --pbf, --shp or --fgb is required".into());
+    }
+    if opt.updatable && opt.node_store.is_some() {
+        return Err("--updatable cannot be combined with --node-store".into());
+    }
+    if opt.updatable && opt.shard_size.is_some() {
+        return Err("--updatable cannot be combined with --shard-size".into());
+    }
+    if let Some(threads) = opt.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+    let admin_levels = opt.admin_level.unwrap_or_else(|| vec![4, 6, 8, 9, 10]);
+    let mapping = FieldMapping {
+        name_field: opt.name_field,
+        level_field: opt.level_field,
+        level: opt.level,
+    };
+
+    let mut boundaries = vec![];
+    let mut report = BuildReport::default();
+    let mut osm = None;
+    if let Some(kind) = opt.node_store {
+        let work_dir = opt.work_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let work_dir = tempfile::tempdir_in(work_dir)?;
+        let mut shards = vec![];
+        for (i, path) in opt.pbf_paths.iter().enumerate() {
+            let dir = work_dir.path().join(i.to_string());
+            create_dir(&dir)?;
+            let progress = progress_bar(path)?;
+            let paths = build_shards(
+                path,
+                &dir,
+                kind,
+                &admin_levels,
+                opt.validate,
+                &mut report,
+                &progress,
+            )?;
+            shards.extend(paths);
+        }
+        boundaries.extend(merge_shards(&shards)?);
+    }
+    let in_memory = opt.node_store.is_none();
+    for path in opt.pbf_paths.iter().filter(|_| in_memory) {
+        let objects = read_osm(path.clone())?;
+        boundaries.extend(get_boundaries_with_progress(
+            &objects,
+            &admin_levels,
+            opt.validate,
+            &mut report,
+            &progress_bar(path)?,
+        ));
+        if opt.updatable {
+            let source = osm.get_or_insert_with(OsmMap::new);
+            source.extend(source_objects(&objects, &admin_levels));
+        }
+    }
+    if opt.pbf_paths.len() > 1 {
+        let count = boundaries.len();
+        boundaries = dedup_boundaries(boundaries, &mut report);
+        eprintln!("{} duplicate relations removed", count - boundaries.len());
+    }
+    for path in opt.shp_paths {
+        let shp_boundaries = get_shp_boundaries(path, &mapping, opt.crs.as_ref(), &admin_levels)?;
+        boundaries.extend(shp_boundaries);
+    }
+    for path in opt.fgb_paths {
+        let fgb_boundaries = get_fgb_boundaries(path, &mapping, opt.crs.as_ref(), &admin_levels)?;
+        boundaries.extend(fgb_boundaries);
+    }
+
+    log_summary(&report);
+    if let Some(path) = opt.report_path {
+        serde_json::to_writer_pretty(File::create(path)?, &report)?;
+    }
+
+    if let Some(tolerance) = opt.simplify {
+        let stats = simplify_boundaries(&mut boundaries, tolerance);
+        let reduction = 1. - stats.vertices_after as f64 / stats.vertices_before.max(1) as f64;
+        eprintln!(
+            "simplified with {}m tolerance: {} -> {} vertices ({:.1}% reduction), {} arcs with lower tolerance",
+            tolerance,
+            stats.vertices_before,
+            stats.vertices_after,
+            reduction * 100.,
+            stats.reduced_arcs
+        );
+    }
+
+    let metadata = Metadata {
+        admin_levels,
+        validate: opt.validate,
+        simplify_tolerance: opt.simplify,
+    };
+    if let Some(shard_size) = opt.shard_size {
+        let manifest = write_sharded(&opt.bin_path, metadata, boundaries, shard_size)?;
+        eprintln!("{} shards written", manifest.shards.len());
+        return Ok(());
+    }
+    let index = Index {
+        metadata,
+        tree: RTree::bulk_load(boundaries),
+        osm,
+    };
+    write_index(&opt.bin_path, &index)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_simplify_tolerance() {
+        let parse = |tolerance| {
+            Opt::from_iter_safe(["build-rtree", "-b", "tree.bin", "--simplify", tolerance])
+        };
+        assert_eq!(parse("25").unwrap().simplify, Some(25.));
+        for tolerance in ["0", "-5", "NaN", "inf", "a"] {
+            assert!(parse(tolerance).is_err(), "{}", tolerance);
+        }
+    }
+}
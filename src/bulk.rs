@@ -1,21 +1,83 @@
-use boundary::Boundary;
-use rstar::RTree;
+use arrow::array::{Array, ArrayRef, AsArray, BooleanArray, StringBuilder};
+use arrow::compute::{cast, filter_record_batch};
+use arrow::datatypes::{DataType, Field, Float64Type, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use osm_admin_lookup::boundary::Boundary;
+use osm_admin_lookup::location::{AxisOrder, Location};
+use osm_admin_lookup::{load_tree, Tree};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use rayon::prelude::*;
+use rstar::AABB;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
 use std::fs::File;
-use std::io::Write;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufWriter};
+use std::io::{Seek, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
-pub mod boundary;
-pub mod geojson;
+#[derive(Serialize, Deserialize)]
+struct JsonInput {
+    id: String,
+    loc: JsonLocation,
+    /// unix timestamp in seconds, required for trajectories
+    #[serde(default)]
+    timestamp: Option<f64>,
+}
 
+/// Location of a json line, as lng,lat array or text in any notation
+/// `Location::parse` accepts.
 #[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonLocation {
+    Pair([f64; 2]),
+    Text(String),
+}
+
+/// An input record, as json line or csv row.
+enum Raw {
+    Json(String),
+    Csv(StringRecord),
+    /// a record which could not be read, e.g. a malformed csv row or a
+    /// line which is not utf-8
+    Unreadable(String),
+}
+
+/// A json line, without its line break.
+fn json_record(mut line: Vec<u8>) -> Raw {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Raw::Json(line),
+        Err(e) => Raw::Unreadable(format!("invalid utf-8: {}", e.utf8_error())),
+    }
+}
+
+/// A csv row, only failing to read the input is an error of the run.
+fn csv_record(row: csv::Result<StringRecord>) -> io::Result<Raw> {
+    match row {
+        Ok(row) => Ok(Raw::Csv(row)),
+        Err(e) if e.is_io_error() => Err(e.into()),
+        Err(e) => Ok(Raw::Unreadable(e.to_string())),
+    }
+}
+
+/// A parsed input record, `columns` are passed through to csv output.
 struct Input {
     id: String,
     loc: [f64; 2],
+    timestamp: Option<f64>,
+    columns: Vec<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -24,6 +86,177 @@ struct Opt {
     /// output bin path
     #[structopt(short = "b", long = "bin")]
     bin_path: PathBuf,
+
+    /// treat lines as timestamped fixes of tracks grouped by id, and emit
+    /// enter and leave events instead of one line per boundary and fix
+    #[structopt(long = "trajectory")]
+    trajectory: bool,
+
+    /// write exactly one json line per input, with the boundaries found
+    /// grouped by admin level
+    #[structopt(long = "wide")]
+    wide: bool,
+
+    /// write results as soon as they are resolved, not in input order
+    #[structopt(long = "unordered")]
+    unordered: bool,
+
+    /// lines read and resolved in parallel at once, when keeping order
+    #[structopt(long = "chunk-size", default_value = "10000")]
+    chunk_size: usize,
+
+    /// number of threads for lookups, default is one per core
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+
+    /// what to do with invalid lines: skip them, fail the run, or emit
+    /// error records
+    #[structopt(long = "on-error", default_value = "fail")]
+    on_error: OnError,
+
+    /// input format, json lines, csv with a header, parquet or arrow ipc
+    #[structopt(long = "input-format", default_value = "json")]
+    input_format: Format,
+
+    /// output format, csv, parquet and arrow write one row per input with
+    /// boundary columns per admin level
+    #[structopt(long = "output-format", default_value = "json")]
+    output_format: Format,
+
+    /// parquet or arrow ipc file to read, default is stdin
+    #[structopt(short = "i", long = "input")]
+    input: Option<PathBuf>,
+
+    /// csv column holding the id, rows are numbered if it is missing
+    #[structopt(long = "id-col", default_value = "id")]
+    id_col: String,
+
+    /// csv, parquet or arrow column holding the longitude
+    #[structopt(long = "lng-col", default_value = "lng")]
+    lng_col: String,
+
+    /// csv, parquet or arrow column holding the latitude
+    #[structopt(long = "lat-col", default_value = "lat")]
+    lat_col: String,
+
+    /// csv column holding the location as text, e.g. `52.52 13.405`, a
+    /// geohash or a wkt point, instead of lng and lat columns
+    #[structopt(long = "loc-col")]
+    loc_col: Option<String>,
+
+    /// read decimal pairs and degrees without hemispheres of text locations
+    /// as lat,lng
+    #[structopt(long = "lat-lng")]
+    lat_lng: bool,
+
+    /// csv column holding the unix timestamp, for trajectories
+    #[structopt(long = "timestamp-col", default_value = "timestamp")]
+    timestamp_col: String,
+
+    /// admin levels of the csv, parquet or arrow output columns, default are
+    /// the levels the tree was built for
+    #[structopt(short = "a", long = "admin-level")]
+    admin_level: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+    Parquet,
+    Arrow,
+}
+
+impl Format {
+    /// Formats read and written in record batches.
+    fn is_columnar(self) -> bool {
+        matches!(self, Format::Parquet | Format::Arrow)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            "arrow" => Ok(Format::Arrow),
+            _ => Err(format!(
+                "unknown format {}, expected json, csv, parquet or arrow",
+                s
+            )),
+        }
+    }
+}
+
+/// Positions of the columns of csv input, by default those of `JSON_COLUMNS`.
+struct CsvColumns {
+    id: Option<usize>,
+    loc: LocationColumns,
+    timestamp: Option<usize>,
+}
+
+/// Columns holding the location of csv input.
+enum LocationColumns {
+    LngLat(usize, usize),
+    Text(usize),
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        CsvColumns {
+            id: Some(0),
+            loc: LocationColumns::LngLat(1, 2),
+            timestamp: None,
+        }
+    }
+}
+
+impl CsvColumns {
+    fn new(header: &StringRecord, opt: &Opt) -> Result<Self, String> {
+        let find = |name: &str| header.iter().position(|column| column == name);
+        let require = |name: &str| find(name).ok_or(format!("missing csv column {}", name));
+        let loc = match &opt.loc_col {
+            Some(loc_col) => LocationColumns::Text(require(loc_col)?),
+            None => LocationColumns::LngLat(require(&opt.lng_col)?, require(&opt.lat_col)?),
+        };
+        Ok(CsvColumns {
+            id: find(&opt.id_col),
+            loc,
+            timestamp: find(&opt.timestamp_col),
+        })
+    }
+}
+
+/// Input records of a run.
+type Records = Box<dyn Iterator<Item = io::Result<Raw>> + Send>;
+
+/// Header of the passed through columns of json input in csv output.
+const JSON_COLUMNS: [&str; 3] = ["id", "lng", "lat"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnError {
+    Skip,
+    Fail,
+    Emit,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnError::Skip),
+            "fail" => Ok(OnError::Fail),
+            "emit" => Ok(OnError::Emit),
+            _ => Err(format!(
+                "unknown error handling {}, expected skip, fail or emit",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,31 +266,1311 @@ struct Output {
     pub admin_level: u8,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let opt = Opt::from_args();
-    let file = File::open(opt.bin_path)?;
-    let tree: RTree<Boundary> = bincode::deserialize_from(file)?;
-    let lines = io::BufReader::new(io::stdin()).lines();
-    let stdout = io::stdout();
-    let mut out = stdout.lock();
-    for line in lines {
-        let line = line?;
-        let input: Input = serde_json::from_str(&line)?;
-        let candidates = tree.locate_all_at_point(&input.loc);
-        let boundaries = candidates.filter_map(|boundary| {
-            if !boundary.contains(&input.loc) {
-                return None;
-            }
-            let output = json!({
-                "id": input.id,
-                "boundary_name": boundary.name,
-                "admin_level": boundary.admin_level,
+/// All boundaries found for an input, by admin level.
+#[derive(Serialize, Deserialize)]
+struct WideOutput {
+    pub id: String,
+    pub levels: BTreeMap<u8, Vec<WideBoundary>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct WideBoundary {
+    pub id: Option<i64>,
+    pub name: String,
+}
+
+/// An invalid input line, with its id if it could be read.
+#[derive(Serialize, Deserialize)]
+struct LineError {
+    pub id: Option<String>,
+    pub error: String,
+    /// columns passed through to csv output
+    #[serde(skip)]
+    pub columns: Vec<String>,
+}
+
+impl LineError {
+    fn new(line: &str, error: impl ToString) -> Self {
+        let value: Option<serde_json::Value> = serde_json::from_str(line).ok();
+        let id = value.as_ref().and_then(|value| value.get("id")?.as_str());
+        LineError {
+            id: id.map(String::from),
+            error: error.to_string(),
+            columns: vec![id.unwrap_or_default().to_string()],
+        }
+    }
+
+    fn of(input: &Input, error: impl ToString) -> Self {
+        LineError {
+            id: Some(input.id.clone()),
+            error: error.to_string(),
+            columns: input.columns.clone(),
+        }
+    }
+}
+
+/// A boundary found for an input.
+struct Found {
+    id: Option<i64>,
+    name: String,
+    level: u8,
+}
+
+/// Line counts, printed on stderr when the run ends.
+#[derive(Default)]
+struct Summary {
+    read: AtomicUsize,
+    resolved: AtomicUsize,
+    unmatched: AtomicUsize,
+    failed: AtomicUsize,
+    /// fixes looked up, counted for trajectories
+    lookups: Option<AtomicUsize>,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let count = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "lines read: {}, resolved: {}, unmatched: {}, failed: {}",
+            count(&self.read),
+            count(&self.resolved),
+            count(&self.unmatched),
+            count(&self.failed),
+        )?;
+        if let Some(lookups) = &self.lookups {
+            write!(f, ", lookups: {}", count(lookups))?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a csv row, terminated by a newline.
+fn csv_row<T: AsRef<[u8]>>(fields: impl IntoIterator<Item = T>) -> Result<String, String> {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    writer.write_record(fields).map_err(|e| e.to_string())?;
+    let row = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(row).map_err(|e| e.to_string())
+}
+
+/// Applies the error handling to lines, renders their output and counts
+/// them.
+struct Handler {
+    on_error: OnError,
+    summary: Summary,
+    output: Format,
+    /// admin levels of csv output columns
+    levels: Vec<u8>,
+    /// number of passed through columns in csv output
+    width: usize,
+    /// columns of csv input
+    columns: Option<CsvColumns>,
+    /// one json line per input
+    wide: bool,
+    /// axis order of text locations
+    order: AxisOrder,
+}
+
+impl Handler {
+    fn new(on_error: OnError) -> Self {
+        Handler {
+            on_error,
+            summary: Summary::default(),
+            output: Format::Json,
+            levels: vec![],
+            width: JSON_COLUMNS.len(),
+            columns: None,
+            wide: false,
+            order: AxisOrder::LngLat,
+        }
+    }
+
+    /// Write one json line per input.
+    fn with_wide(self, wide: bool) -> Self {
+        Handler { wide, ..self }
+    }
+
+    /// Read text locations in the given axis order.
+    fn with_order(self, order: AxisOrder) -> Self {
+        Handler { order, ..self }
+    }
+
+    /// Count the fixes of trajectories which are looked up.
+    fn with_lookups(mut self) -> Self {
+        self.summary.lookups = Some(AtomicUsize::new(0));
+        self
+    }
+
+    /// Read csv input with the given columns.
+    fn with_columns(self, columns: CsvColumns) -> Self {
+        Handler {
+            columns: Some(columns),
+            ..self
+        }
+    }
+
+    /// Parse an input record, checking the range of its location. Records
+    /// are numbered from 1, csv rows without id column are identified by
+    /// their number.
+    fn parse(&self, raw: &Raw, number: usize) -> Result<Input, LineError> {
+        let input = match (raw, &self.columns) {
+            (Raw::Csv(row), Some(columns)) => parse_csv(row, number, columns, self.order)?,
+            (Raw::Csv(row), None) => parse_csv(row, number, &CsvColumns::default(), self.order)?,
+            (Raw::Json(line), _) => parse_json(line, self.order)?,
+            (Raw::Unreadable(error), _) => {
+                return Err(LineError {
+                    id: None,
+                    error: error.clone(),
+                    columns: vec![],
+                })
+            }
+        };
+        let [lng, lat] = input.loc;
+        Location::new(lng, lat).map_err(|e| LineError::of(&input, e))?;
+        Ok(input)
+    }
+
+    /// Handler writing csv with the passed through `columns`, followed by a
+    /// name column per level.
+    fn csv(on_error: OnError, columns: &[String], levels: Vec<u8>) -> Self {
+        Handler {
+            output: Format::Csv,
+            levels,
+            width: columns.len(),
+            ..Handler::new(on_error)
+        }
+    }
+
+    /// Header of csv output.
+    fn header(&self, columns: &[String]) -> Result<String, String> {
+        let levels = self
+            .levels
+            .iter()
+            .map(|level| format!("level_{}_name", level));
+        let error = (self.on_error == OnError::Emit).then(|| "error".to_string());
+        csv_row(columns.iter().cloned().chain(levels).chain(error))
+    }
+
+    fn csv_row(&self, columns: &[String], found: &[Found], error: &str) -> Result<String, String> {
+        let mut columns = columns.to_vec();
+        columns.resize(self.width, String::new());
+        for level in &self.levels {
+            let names: Vec<&str> = found
+                .iter()
+                .filter(|found| found.level == *level)
+                .map(|found| found.name.as_str())
+                .collect();
+            columns.push(names.join(";"));
+        }
+        if self.on_error == OnError::Emit {
+            columns.push(error.to_string());
+        }
+        csv_row(columns)
+    }
+
+    fn count(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Output for an invalid line, an error if the run has to fail.
+    fn failed(&self, number: usize, error: LineError) -> Result<String, String> {
+        Self::count(&self.summary.failed);
+        match self.on_error {
+            OnError::Skip => Ok(String::new()),
+            OnError::Fail => Err(format!("line {}: {}", number, error.error)),
+            OnError::Emit if self.output == Format::Csv => {
+                self.csv_row(&error.columns, &[], &error.error)
+            }
+            OnError::Emit => {
+                let mut line = serde_json::to_string(&error).map_err(|e| e.to_string())?;
+                line.push('\n');
+                Ok(line)
+            }
+        }
+    }
+
+    /// Output lines for an input line, each terminated by a newline.
+    fn handle(
+        &self,
+        number: usize,
+        result: Result<(Input, Vec<Found>), LineError>,
+    ) -> Result<String, String> {
+        Self::count(&self.summary.read);
+        let (input, found) = match result {
+            Ok(result) => result,
+            Err(error) => return self.failed(number, error),
+        };
+        match found.is_empty() {
+            true => Self::count(&self.summary.unmatched),
+            false => Self::count(&self.summary.resolved),
+        }
+        if self.output == Format::Csv {
+            return self.csv_row(&input.columns, &found, "");
+        }
+        if self.wide {
+            let mut levels: BTreeMap<u8, Vec<WideBoundary>> = BTreeMap::new();
+            for found in found {
+                let boundary = WideBoundary {
+                    id: found.id,
+                    name: found.name,
+                };
+                levels.entry(found.level).or_default().push(boundary);
+            }
+            let output = WideOutput {
+                id: input.id,
+                levels,
+            };
+            let mut line = serde_json::to_string(&output).map_err(|e| e.to_string())?;
+            line.push('\n');
+            return Ok(line);
+        }
+        let mut lines = String::new();
+        for found in found {
+            let output = Output {
+                id: input.id.clone(),
+                boundary_name: found.name,
+                admin_level: found.level,
+            };
+            lines.push_str(&serde_json::to_string(&output).map_err(|e| e.to_string())?);
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+}
+
+fn parse_json(line: &str, order: AxisOrder) -> Result<Input, LineError> {
+    let input: JsonInput = serde_json::from_str(line).map_err(|e| LineError::new(line, e))?;
+    let loc = match input.loc {
+        JsonLocation::Pair(loc) => loc,
+        JsonLocation::Text(text) => Location::parse(&text, order)
+            .map_err(|e| LineError::new(line, e))?
+            .into(),
+    };
+    let [lng, lat] = loc;
+    Ok(Input {
+        columns: vec![input.id.clone(), lng.to_string(), lat.to_string()],
+        id: input.id,
+        loc,
+        timestamp: input.timestamp,
+    })
+}
+
+fn parse_csv(
+    row: &StringRecord,
+    number: usize,
+    columns: &CsvColumns,
+    order: AxisOrder,
+) -> Result<Input, LineError> {
+    let field = |i: usize| row.get(i).unwrap_or_default();
+    let id = match columns.id {
+        Some(i) => field(i).to_string(),
+        None => number.to_string(),
+    };
+    let error = |error: String| LineError {
+        id: Some(id.clone()),
+        error,
+        columns: row.iter().map(String::from).collect(),
+    };
+    let number = |i: usize, name: &str| {
+        field(i)
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| error(format!("invalid {} {:?}: {}", name, field(i), e)))
+    };
+    let loc = match columns.loc {
+        LocationColumns::LngLat(lng, lat) => [number(lng, "lng")?, number(lat, "lat")?],
+        LocationColumns::Text(i) => Location::parse(field(i), order)
+            .map_err(|e| error(format!("invalid location {:?}: {}", field(i), e)))?
+            .into(),
+    };
+    let timestamp = match columns.timestamp {
+        Some(i) if !field(i).is_empty() => Some(number(i, "timestamp")?),
+        _ => None,
+    };
+    Ok(Input {
+        id: id.clone(),
+        loc,
+        timestamp,
+        columns: row.iter().map(String::from).collect(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    Enter,
+    Leave,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Event {
+    pub id: String,
+    pub event: EventKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boundary_id: Option<i64>,
+    pub boundary_name: String,
+    pub admin_level: u8,
+    pub timestamp: f64,
+    /// seconds spent inside the boundary, on leave events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dwell: Option<f64>,
+}
+
+/// Fixes of a track, in order of appearance of the track's id.
+struct Tracks {
+    ids: Vec<String>,
+    fixes: HashMap<String, Vec<(f64, [f64; 2])>>,
+}
+
+/// Read the fixes of all tracks, error records of invalid lines are
+/// written to `out`.
+fn read_tracks(
+    lines: impl Iterator<Item = io::Result<Raw>>,
+    handler: &Handler,
+    out: &mut impl Write,
+) -> Result<Tracks, Box<dyn Error>> {
+    let mut tracks = Tracks {
+        ids: vec![],
+        fixes: HashMap::new(),
+    };
+    for (i, raw) in lines.enumerate() {
+        Handler::count(&handler.summary.read);
+        let parsed = handler
+            .parse(&raw?, i + 1)
+            .and_then(|input| match input.timestamp {
+                Some(timestamp) => Ok((input, timestamp)),
+                None => Err(LineError::of(&input, "missing timestamp")),
             });
-            Some(output)
-        });
-        for boundary in boundaries {
-            writeln!(out, "{}", boundary)?;
+        let (input, timestamp) = match parsed {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                out.write_all(handler.failed(i + 1, error)?.as_bytes())?;
+                continue;
+            }
+        };
+        if !tracks.fixes.contains_key(&input.id) {
+            tracks.ids.push(input.id.clone());
+        }
+        let fixes = tracks.fixes.entry(input.id).or_default();
+        fixes.push((timestamp, input.loc));
+    }
+    for fixes in tracks.fixes.values_mut() {
+        fixes.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+    Ok(tracks)
+}
+
+fn event(id: &str, kind: EventKind, boundary: &Boundary, timestamp: f64, since: f64) -> Event {
+    Event {
+        id: id.to_string(),
+        dwell: (kind == EventKind::Leave).then_some(timestamp - since),
+        event: kind,
+        boundary_id: boundary.osm_id,
+        boundary_name: boundary.name.clone(),
+        admin_level: boundary.admin_level,
+        timestamp,
+    }
+}
+
+fn same(a: &Boundary, b: &Boundary) -> bool {
+    (a.osm_id, a.admin_level, &a.name) == (b.osm_id, b.admin_level, &b.name)
+}
+
+/// Enter and leave events of a track, sorted by timestamp. Boundaries
+/// still entered at the end of the track are left at its last fix. A level
+/// is not looked up again while the fixes stay inside the boundary entered
+/// on it, boundaries on one level are expected not to overlap. Fixes inside
+/// a boundary on every level near the track are not looked up at all. Fixes
+/// inside a boundary are counted as resolved, others as unmatched.
+fn track_events(
+    tree: &Tree,
+    id: &str,
+    fixes: &[(f64, [f64; 2])],
+    summary: &Summary,
+) -> Result<Vec<Event>, Box<dyn Error>> {
+    let envelope = AABB::from_points(fixes.iter().map(|(_, loc)| loc));
+    let trees = tree.trees(&envelope)?;
+    let levels: BTreeSet<u8> = trees
+        .iter()
+        .flat_map(|tree| tree.locate_in_envelope_intersecting(&envelope))
+        .map(|boundary| boundary.admin_level)
+        .collect();
+
+    let mut events = vec![];
+    let mut current: Vec<(&Boundary, f64)> = vec![];
+    for (timestamp, loc) in fixes {
+        let mut stays: BTreeSet<u8> = current.iter().map(|(b, _)| b.admin_level).collect();
+        for (boundary, _) in &current {
+            if !boundary.contains(loc) {
+                stays.remove(&boundary.admin_level);
+            }
+        }
+        if stays != levels {
+            if let Some(lookups) = &summary.lookups {
+                Handler::count(lookups);
+            }
+            let found: Vec<&Boundary> = trees
+                .iter()
+                .flat_map(|tree| tree.locate_all_at_point(loc))
+                .filter(|boundary| !stays.contains(&boundary.admin_level))
+                .filter(|boundary| boundary.contains(loc))
+                .collect();
+            current.retain(|(boundary, since)| {
+                let kept = stays.contains(&boundary.admin_level)
+                    || found.iter().any(|b| same(b, boundary));
+                if !kept {
+                    events.push(event(id, EventKind::Leave, boundary, *timestamp, *since));
+                }
+                kept
+            });
+            for boundary in found {
+                if !current.iter().any(|(b, _)| same(b, boundary)) {
+                    events.push(event(
+                        id,
+                        EventKind::Enter,
+                        boundary,
+                        *timestamp,
+                        *timestamp,
+                    ));
+                    current.push((boundary, *timestamp));
+                }
+            }
+        }
+        match current.is_empty() {
+            true => Handler::count(&summary.unmatched),
+            false => Handler::count(&summary.resolved),
         }
     }
+    if let Some((last, _)) = fixes.last() {
+        for (boundary, since) in current {
+            events.push(event(id, EventKind::Leave, boundary, *last, since));
+        }
+    }
+    Ok(events)
+}
+
+fn trajectories(
+    tree: &Tree,
+    lines: impl Iterator<Item = io::Result<Raw>>,
+    handler: &Handler,
+    out: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let tracks = read_tracks(lines, handler, out)?;
+    for id in &tracks.ids {
+        let events = track_events(tree, id, &tracks.fixes[id], &handler.summary)?;
+        for event in events {
+            writeln!(out, "{}", serde_json::to_string(&event)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn resolve(
+    tree: &Tree,
+    handler: &Handler,
+    raw: &Raw,
+    number: usize,
+) -> Result<(Input, Vec<Found>), LineError> {
+    let input = handler.parse(raw, number)?;
+    let found = tree.locate(&input.loc, |boundaries| {
+        boundaries
+            .into_iter()
+            .map(|boundary| Found {
+                id: boundary.osm_id,
+                name: boundary.name.clone(),
+                level: boundary.admin_level,
+            })
+            .collect()
+    });
+    match found {
+        Ok(found) => Ok((input, found)),
+        Err(e) => Err(LineError::of(&input, e)),
+    }
+}
+
+/// Resolve chunks of lines in parallel, writing results in input order.
+fn resolve_ordered(
+    tree: &Tree,
+    mut lines: impl Iterator<Item = io::Result<Raw>>,
+    chunk_size: usize,
+    handler: &Handler,
+    out: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut offset = 0;
+    loop {
+        let chunk = lines
+            .by_ref()
+            .take(chunk_size.max(1))
+            .collect::<io::Result<Vec<Raw>>>()?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let results: Vec<Result<String, String>> = chunk
+            .par_iter()
+            .enumerate()
+            .map(|(i, raw)| {
+                let number = offset + i + 1;
+                handler.handle(number, resolve(tree, handler, raw, number))
+            })
+            .collect();
+        for result in results {
+            out.write_all(result?.as_bytes())?;
+        }
+        offset += chunk.len();
+    }
+}
+
+/// Resolve lines in parallel, writing results as soon as they are ready.
+fn resolve_unordered(
+    tree: &Tree,
+    lines: impl Iterator<Item = io::Result<Raw>> + Send,
+    handler: &Handler,
+    out: impl Write + Send,
+) -> Result<(), Box<dyn Error>> {
+    let out = Mutex::new(out);
+    lines.enumerate().par_bridge().try_for_each(|(i, raw)| {
+        let raw = raw.map_err(|e| e.to_string())?;
+        let lines = handler.handle(i + 1, resolve(tree, handler, &raw, i + 1))?;
+        let mut out = out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(lines.as_bytes()).map_err(|e| e.to_string())
+    })?;
+    let mut out = out.into_inner().unwrap_or_else(|e| e.into_inner());
+    out.flush()?;
     Ok(())
 }
+
+/// Record batches of parquet or arrow ipc input.
+type Batches = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>> + Send>;
+
+/// Open columnar input, stdin is buffered in a temporary file since both
+/// formats are read from their footer.
+fn open_input(path: Option<&PathBuf>) -> io::Result<File> {
+    match path {
+        Some(path) => File::open(path),
+        None => {
+            let mut file = tempfile::tempfile()?;
+            io::copy(&mut io::stdin().lock(), &mut file)?;
+            file.rewind()?;
+            Ok(file)
+        }
+    }
+}
+
+/// Read parquet in batches of `batch_size` rows, arrow ipc in the batches
+/// it was written in.
+fn read_batches(
+    format: Format,
+    file: File,
+    batch_size: usize,
+) -> Result<(SchemaRef, Batches), Box<dyn Error>> {
+    match format {
+        Format::Parquet => {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file)?
+                .with_batch_size(batch_size.max(1))
+                .build()?;
+            Ok((reader.schema(), Box::new(reader)))
+        }
+        Format::Arrow => {
+            let reader = FileReader::try_new(file, None)?;
+            Ok((reader.schema(), Box::new(reader)))
+        }
+        _ => Err(format!("{:?} is not a columnar format", format).into()),
+    }
+}
+
+enum BatchWriter<W: Write + Send> {
+    Parquet(ArrowWriter<W>),
+    Arrow(FileWriter<W>),
+}
+
+impl<W: Write + Send> BatchWriter<W> {
+    fn new(format: Format, out: W, schema: &SchemaRef) -> Result<Self, Box<dyn Error>> {
+        match format {
+            Format::Parquet => Ok(BatchWriter::Parquet(ArrowWriter::try_new(
+                out,
+                schema.clone(),
+                None,
+            )?)),
+            Format::Arrow => Ok(BatchWriter::Arrow(FileWriter::try_new(out, schema)?)),
+            _ => Err(format!("{:?} is not a columnar format", format).into()),
+        }
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), Box<dyn Error>> {
+        match self {
+            BatchWriter::Parquet(writer) => writer.write(batch)?,
+            BatchWriter::Arrow(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+
+    /// Write the footer.
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            BatchWriter::Parquet(writer) => {
+                writer.close()?;
+            }
+            BatchWriter::Arrow(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Positions of the location columns of columnar input.
+struct BatchColumns {
+    lng: usize,
+    lat: usize,
+}
+
+impl BatchColumns {
+    fn new(schema: &Schema, opt: &Opt) -> Result<Self, String> {
+        let find = |name: &str| {
+            schema
+                .index_of(name)
+                .map_err(|_| format!("missing column {}", name))
+        };
+        Ok(BatchColumns {
+            lng: find(&opt.lng_col)?,
+            lat: find(&opt.lat_col)?,
+        })
+    }
+}
+
+/// Schema of enriched batches, the input columns followed by id and name
+/// columns per level. Several boundaries on a level are joined by `;` in
+/// both columns, in the same order, ids of boundaries without osm id are
+/// left empty.
+fn enriched_schema(schema: &Schema, handler: &Handler) -> SchemaRef {
+    let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+    for level in &handler.levels {
+        fields.push(Field::new(
+            format!("level_{}_id", level),
+            DataType::Utf8,
+            true,
+        ));
+        fields.push(Field::new(
+            format!("level_{}_name", level),
+            DataType::Utf8,
+            true,
+        ));
+    }
+    if handler.on_error == OnError::Emit {
+        fields.push(Field::new("error", DataType::Utf8, true));
+    }
+    Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()))
+}
+
+fn locate_row(
+    tree: &Tree,
+    lng: &dyn Array,
+    lat: &dyn Array,
+    row: usize,
+) -> Result<Vec<Found>, String> {
+    let value = |array: &dyn Array, name: &str| match array.is_valid(row) {
+        true => Ok(array.as_primitive::<Float64Type>().value(row)),
+        false => Err(format!("missing or invalid {}", name)),
+    };
+    let loc = [value(lng, "lng")?, value(lat, "lat")?];
+    Location::new(loc[0], loc[1]).map_err(|e| e.to_string())?;
+    tree.locate(&loc, |boundaries| {
+        boundaries
+            .into_iter()
+            .map(|boundary| Found {
+                id: boundary.osm_id,
+                name: boundary.name.clone(),
+                level: boundary.admin_level,
+            })
+            .collect()
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Append boundary columns to a batch, rows are looked up in parallel.
+/// `offset` is the number of rows of previous batches.
+fn enrich_batch(
+    tree: &Tree,
+    handler: &Handler,
+    columns: &BatchColumns,
+    schema: &SchemaRef,
+    batch: &RecordBatch,
+    offset: usize,
+) -> Result<RecordBatch, Box<dyn Error>> {
+    let lng = cast(batch.column(columns.lng), &DataType::Float64)?;
+    let lat = cast(batch.column(columns.lat), &DataType::Float64)?;
+    let results: Vec<Result<Vec<Found>, String>> = (0..batch.num_rows())
+        .into_par_iter()
+        .map(|row| locate_row(tree, &lng, &lat, row))
+        .collect();
+
+    let mut keep = Vec::with_capacity(results.len());
+    let mut ids: Vec<StringBuilder> = handler
+        .levels
+        .iter()
+        .map(|_| StringBuilder::new())
+        .collect();
+    let mut names: Vec<StringBuilder> = handler
+        .levels
+        .iter()
+        .map(|_| StringBuilder::new())
+        .collect();
+    let mut errors = StringBuilder::new();
+    for (row, result) in results.into_iter().enumerate() {
+        Handler::count(&handler.summary.read);
+        let (found, error) = match result {
+            Ok(found) if found.is_empty() => {
+                Handler::count(&handler.summary.unmatched);
+                (found, None)
+            }
+            Ok(found) => {
+                Handler::count(&handler.summary.resolved);
+                (found, None)
+            }
+            Err(error) => {
+                Handler::count(&handler.summary.failed);
+                if handler.on_error == OnError::Fail {
+                    return Err(format!("row {}: {}", offset + row + 1, error).into());
+                }
+                (vec![], Some(error))
+            }
+        };
+        keep.push(error.is_none() || handler.on_error != OnError::Skip);
+        errors.append_option(error);
+        for (i, level) in handler.levels.iter().enumerate() {
+            let on_level: Vec<&Found> = found.iter().filter(|f| f.level == *level).collect();
+            if on_level.is_empty() {
+                ids[i].append_null();
+                names[i].append_null();
+                continue;
+            }
+            let level_ids: Vec<String> = on_level
+                .iter()
+                .map(|f| f.id.map(|id| id.to_string()).unwrap_or_default())
+                .collect();
+            let level_names: Vec<&str> = on_level.iter().map(|f| f.name.as_str()).collect();
+            ids[i].append_value(level_ids.join(";"));
+            names[i].append_value(level_names.join(";"));
+        }
+    }
+
+    let mut arrays: Vec<ArrayRef> = batch.columns().to_vec();
+    for (ids, names) in ids.iter_mut().zip(names.iter_mut()) {
+        arrays.push(Arc::new(ids.finish()));
+        arrays.push(Arc::new(names.finish()));
+    }
+    if handler.on_error == OnError::Emit {
+        arrays.push(Arc::new(errors.finish()));
+    }
+    let enriched = RecordBatch::try_new(schema.clone(), arrays)?;
+    match keep.iter().all(|keep| *keep) {
+        true => Ok(enriched),
+        false => Ok(filter_record_batch(&enriched, &BooleanArray::from(keep))?),
+    }
+}
+
+/// Enrich parquet or arrow ipc input batch by batch.
+fn enrich<W: Write + Send>(
+    tree: &Tree,
+    handler: &Handler,
+    batches: Batches,
+    columns: &BatchColumns,
+    writer: &mut BatchWriter<W>,
+    schema: &SchemaRef,
+) -> Result<(), Box<dyn Error>> {
+    let mut offset = 0;
+    for batch in batches {
+        let batch = batch?;
+        let enriched = enrich_batch(tree, handler, columns, schema, &batch, offset)?;
+        writer.write(&enriched)?;
+        offset += batch.num_rows();
+    }
+    Ok(())
+}
+
+/// Admin levels of the boundary columns of csv, parquet and arrow output.
+fn output_levels(opt: &Opt, tree: &Tree) -> Result<Vec<u8>, String> {
+    let levels = match &opt.admin_level {
+        Some(levels) => levels.clone(),
+        None => tree.metadata().admin_levels.clone(),
+    };
+    match levels.is_empty() {
+        true => Err("no admin levels for output columns, pass -a".to_string()),
+        false => Ok(levels),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    if let Some(threads) = opt.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+    if opt.loc_col.is_some() && opt.input_format != Format::Csv {
+        return Err("--loc-col applies to csv input only".into());
+    }
+    let tree = load_tree(&opt.bin_path)?;
+    let mut out = BufWriter::new(io::stdout());
+    if opt.input_format.is_columnar() || opt.output_format.is_columnar() {
+        if !opt.input_format.is_columnar() || !opt.output_format.is_columnar() {
+            return Err(
+                "parquet and arrow input and output can only be combined with each other".into(),
+            );
+        }
+        if opt.trajectory {
+            return Err("trajectories are written as json only".into());
+        }
+        if opt.wide || opt.unordered {
+            return Err(
+                "--wide and --unordered don't apply to parquet and arrow, rows keep their order"
+                    .into(),
+            );
+        }
+        let mut handler = Handler::new(opt.on_error);
+        handler.levels = output_levels(&opt, &tree)?;
+        let file = open_input(opt.input.as_ref())?;
+        let (schema, batches) = read_batches(opt.input_format, file, opt.chunk_size)?;
+        let columns = BatchColumns::new(&schema, &opt)?;
+        let schema = enriched_schema(&schema, &handler);
+        let mut writer = BatchWriter::new(opt.output_format, &mut out, &schema)?;
+        let result = enrich(&tree, &handler, batches, &columns, &mut writer, &schema);
+        writer.finish()?;
+        out.flush()?;
+        eprintln!("{}", handler.summary);
+        return result;
+    }
+    let (lines, columns): (Records, Vec<String>) = match opt.input_format {
+        Format::Json => {
+            let lines = io::BufReader::new(io::stdin()).split(b'\n');
+            let records = lines.map(|line| line.map(json_record));
+            let columns = JSON_COLUMNS.iter().map(|c| c.to_string()).collect();
+            (Box::new(records), columns)
+        }
+        Format::Csv => {
+            let mut reader = ReaderBuilder::new().flexible(true).from_reader(io::stdin());
+            let header = reader.headers()?.clone();
+            let records = reader.into_records().map(csv_record);
+            (Box::new(records), header.iter().map(String::from).collect())
+        }
+        Format::Parquet | Format::Arrow => unreachable!("columnar input is read in batches"),
+    };
+    let mut handler = match opt.output_format {
+        Format::Json if opt.trajectory && opt.wide => {
+            return Err("--wide does not apply to trajectories".into());
+        }
+        Format::Json => Handler::new(opt.on_error).with_wide(opt.wide),
+        Format::Csv if opt.trajectory => {
+            return Err("trajectories are written as json only".into());
+        }
+        Format::Csv if opt.wide => {
+            return Err("--wide does not apply to csv, which has one row per input".into());
+        }
+        Format::Csv => {
+            let levels = output_levels(&opt, &tree)?;
+            let handler = Handler::csv(opt.on_error, &columns, levels);
+            out.write_all(handler.header(&columns)?.as_bytes())?;
+            handler
+        }
+        Format::Parquet | Format::Arrow => unreachable!("columnar output is written in batches"),
+    };
+    if opt.input_format == Format::Csv {
+        let header = StringRecord::from(columns);
+        handler = handler.with_columns(CsvColumns::new(&header, &opt)?);
+    }
+    if opt.lat_lng {
+        handler = handler.with_order(AxisOrder::LatLng);
+    }
+    if opt.trajectory {
+        handler = handler.with_lookups();
+    }
+    if opt.trajectory && opt.unordered {
+        return Err("--unordered does not apply to trajectories".into());
+    }
+    let result = if opt.trajectory {
+        trajectories(&tree, lines, &handler, &mut out)
+    } else if opt.unordered {
+        resolve_unordered(&tree, lines, &handler, &mut out)
+    } else {
+        resolve_ordered(&tree, lines, opt.chunk_size, &handler, &mut out)
+    };
+    out.flush()?;
+    eprintln!("{}", handler.summary);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use osm_admin_lookup::RTree;
+
+    fn square(x: f64, name: &str) -> Boundary {
+        let polygon = polygon![
+            (x: x, y: 0.),
+            (x: x + 1., y: 0.),
+            (x: x + 1., y: 1.),
+            (x: x, y: 1.),
+        ];
+        Boundary::new(polygon.into(), name, 8)
+    }
+
+    #[test]
+    fn emits_enter_and_leave_events() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(2., "east"),
+        ]));
+        let lines = [
+            r#"{"id": "a", "loc": [2.5, 0.5], "timestamp": 30}"#,
+            r#"{"id": "a", "loc": [0.5, 0.5], "timestamp": 10}"#,
+            r#"{"id": "a", "loc": [1.5, 0.5], "timestamp": 20}"#,
+            r#"{"id": "a", "loc": [2.6, 0.5], "timestamp": 40}"#,
+            r#"{"id": "a", "loc": [2.7, 0.5]}"#,
+        ];
+        let lines = lines.iter().map(|line| Ok(Raw::Json(line.to_string())));
+        let mut out = vec![];
+        let handler = Handler::new(OnError::Skip).with_lookups();
+        trajectories(&tree, lines, &handler, &mut out).unwrap();
+        let events: Vec<Event> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<(&EventKind, &str, f64, Option<f64>)> = events
+            .iter()
+            .map(|e| (&e.event, e.boundary_name.as_str(), e.timestamp, e.dwell))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (&EventKind::Enter, "west", 10., None),
+                (&EventKind::Leave, "west", 20., Some(10.)),
+                (&EventKind::Enter, "east", 30., None),
+                (&EventKind::Leave, "east", 40., Some(10.)),
+            ]
+        );
+        let summary = "lines read: 5, resolved: 3, unmatched: 1, failed: 1, lookups: 3";
+        assert_eq!(handler.summary.to_string(), summary);
+    }
+
+    #[test]
+    fn skips_lookups_inside_boundaries() {
+        // a level 9 boundary covers part of west, there is none elsewhere
+        let inner = polygon![
+            (x: 0.5, y: 0.),
+            (x: 0.7, y: 0.),
+            (x: 0.7, y: 1.),
+            (x: 0.5, y: 1.),
+        ];
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(2., "east"),
+            Boundary::new(inner.into(), "inner", 9),
+        ]));
+        let fixes: Vec<(f64, [f64; 2])> = [0.1, 0.6, 0.62, 0.65, 0.9, 5.]
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (i as f64, [*x, 0.5]))
+            .collect();
+        let summary = Summary {
+            lookups: Some(AtomicUsize::new(0)),
+            ..Default::default()
+        };
+        let events = track_events(&tree, "a", &fixes, &summary).unwrap();
+        // not looked up while inside west and inner
+        assert_eq!(summary.lookups.as_ref().unwrap().load(Ordering::Relaxed), 4);
+        let events: Vec<(&EventKind, &str, f64)> = events
+            .iter()
+            .map(|e| (&e.event, e.boundary_name.as_str(), e.timestamp))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (&EventKind::Enter, "west", 0.),
+                (&EventKind::Enter, "inner", 1.),
+                (&EventKind::Leave, "inner", 4.),
+                (&EventKind::Leave, "west", 5.),
+            ]
+        );
+        assert_eq!(summary.resolved.load(Ordering::Relaxed), 5);
+        assert_eq!(summary.unmatched.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn resolves_in_input_order() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(2., "east"),
+        ]));
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!(r#"{{"id": "{}", "loc": [{}.5, 0.5]}}"#, i, (i % 2) * 2))
+            .collect();
+        let mut ordered = vec![];
+        let input = lines.iter().cloned().map(|line| Ok(Raw::Json(line)));
+        let handler = Handler::new(OnError::Fail);
+        resolve_ordered(&tree, input, 7, &handler, &mut ordered).unwrap();
+        let ordered = String::from_utf8(ordered).unwrap();
+        let ids: Vec<usize> = ordered
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Output>(line)
+                    .unwrap()
+                    .id
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+
+        let mut unordered = vec![];
+        let input = lines.into_iter().map(|line| Ok(Raw::Json(line)));
+        resolve_unordered(&tree, input, &handler, &mut unordered).unwrap();
+        let mut unordered: Vec<&str> = std::str::from_utf8(&unordered).unwrap().lines().collect();
+        let mut expected: Vec<&str> = ordered.lines().collect();
+        unordered.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(unordered, expected);
+    }
+
+    #[test]
+    fn handles_invalid_lines() {
+        let tree = Tree::from(RTree::bulk_load(vec![square(0., "west")]));
+        let lines = [
+            r#"{"id": "a", "loc": [0.5, 0.5]}"#,
+            r#"{"id": "b", "loc": [0.5]}"#,
+            r#"{"id": "c", "loc": [200, 0.5]}"#,
+            r#"{"id": "d", "loc": [1.5, 0.5]}"#,
+            "not json",
+        ];
+        let run = |on_error| {
+            let handler = Handler::new(on_error);
+            let input = lines.iter().map(|line| Ok(Raw::Json(line.to_string())));
+            let mut out = vec![];
+            let result = resolve_ordered(&tree, input, 2, &handler, &mut out);
+            (result, String::from_utf8(out).unwrap(), handler.summary)
+        };
+
+        let (result, out, summary) = run(OnError::Skip);
+        assert!(result.is_ok());
+        assert_eq!(out.lines().count(), 1);
+        let expected = "lines read: 5, resolved: 1, unmatched: 1, failed: 3";
+        assert_eq!(summary.to_string(), expected);
+
+        let (result, out, _) = run(OnError::Emit);
+        assert!(result.is_ok());
+        let errors: Vec<LineError> = out
+            .lines()
+            .skip(1)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let ids: Vec<Option<&str>> = errors.iter().map(|e| e.id.as_deref()).collect();
+        assert_eq!(ids, [Some("b"), Some("c"), None]);
+
+        let (result, out, _) = run(OnError::Fail);
+        assert!(result.unwrap_err().to_string().starts_with("line 2:"));
+        assert_eq!(out.lines().count(), 1);
+    }
+
+    #[test]
+    fn handles_unreadable_records() {
+        let tree = Tree::from(RTree::bulk_load(vec![square(0., "west")]));
+        let handler = Handler::new(OnError::Emit);
+        let input =
+            b"{\"id\": \"a\", \"loc\": [0.5, 0.5]}\r\n\xff\n{\"id\": \"b\", \"loc\": [0.5, 0.5]}";
+        let lines = BufRead::split(&input[..], b'\n').map(|line| line.map(json_record));
+        let mut out = vec![];
+        resolve_ordered(&tree, lines, 10, &handler, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("invalid utf-8"), "{}", lines[1]);
+        assert!(lines[2].contains(r#""id":"b""#));
+
+        let input = b"id,lng,lat\na,0.5,0.5\nb,\xff,0.5\nc,0.5,0.5\n";
+        let mut reader = ReaderBuilder::new().from_reader(&input[..]);
+        let columns: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let handler = Handler::csv(OnError::Skip, &columns, vec![8]);
+        let rows = reader.into_records().map(csv_record);
+        let mut out = vec![];
+        resolve_ordered(&tree, rows, 10, &handler, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a,0.5,0.5,west\nc,0.5,0.5,west\n"
+        );
+        let summary = "lines read: 3, resolved: 2, unmatched: 0, failed: 1";
+        assert_eq!(handler.summary.to_string(), summary);
+    }
+
+    #[test]
+    fn writes_one_line_per_input() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(0., "overlapping"),
+        ]));
+        let lines = [
+            r#"{"id": "a", "loc": [0.5, 0.5]}"#,
+            r#"{"id": "b", "loc": [5.5, 0.5]}"#,
+        ];
+        let input = lines.iter().map(|line| Ok(Raw::Json(line.to_string())));
+        let handler = Handler::new(OnError::Fail).with_wide(true);
+        let mut out = vec![];
+        resolve_ordered(&tree, input, 10, &handler, &mut out).unwrap();
+        let outputs: Vec<WideOutput> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(outputs.len(), 2);
+        let mut names: Vec<&str> = outputs[0].levels[&8]
+            .iter()
+            .map(|b| b.name.as_str())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, ["overlapping", "west"]);
+        assert_eq!(outputs[1].id, "b");
+        assert!(outputs[1].levels.is_empty());
+    }
+
+    #[test]
+    fn reads_and_writes_csv() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(2., "east"),
+        ]));
+        let input = "name,lat,lon\nfirst,0.5,0.5\nsecond,0.5,\"2,5\"\nthird,0.5,9.5\n";
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let columns: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let opt = Opt::from_iter(["bulk", "-b", "tree.bin", "--lng-col", "lon"]);
+        let header = StringRecord::from(columns.clone());
+        let handler = Handler::csv(OnError::Emit, &columns, vec![4, 8])
+            .with_columns(CsvColumns::new(&header, &opt).unwrap());
+        let rows = reader.into_records().map(|row| Ok(Raw::Csv(row.unwrap())));
+        let mut out = handler.header(&columns).unwrap().into_bytes();
+        resolve_ordered(&tree, rows, 2, &handler, &mut out).unwrap();
+        let expected = "name,lat,lon,level_4_name,level_8_name,error\n\
+                        first,0.5,0.5,,west,\n\
+                        second,0.5,\"2,5\",,,\"invalid lng \"\"2,5\"\": invalid float literal\"\n\
+                        third,0.5,9.5,,,\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let opt = Opt::from_iter(["bulk", "-b", "tree.bin"]);
+        assert!(CsvColumns::new(&header, &opt).is_err());
+    }
+
+    #[test]
+    fn reads_text_locations() {
+        let tree = Tree::from(RTree::bulk_load(vec![
+            square(0., "west"),
+            square(2., "east"),
+        ]));
+        let lines = [
+            r#"{"id": "a", "loc": "0.5 2.5"}"#,
+            r#"{"id": "b", "loc": "POINT(0.5 0.5)"}"#,
+            r#"{"id": "c", "loc": "0°30'N 0°30'E"}"#,
+        ];
+        let input = lines.iter().map(|line| Ok(Raw::Json(line.to_string())));
+        let handler = Handler::new(OnError::Fail)
+            .with_wide(true)
+            .with_order(AxisOrder::LatLng);
+        let mut out = vec![];
+        resolve_ordered(&tree, input, 10, &handler, &mut out).unwrap();
+        let names: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let output: WideOutput = serde_json::from_str(line).unwrap();
+                output.levels[&8][0].name.clone()
+            })
+            .collect();
+        assert_eq!(names, ["east", "west", "west"]);
+
+        let input = "name,position\nfirst,\"0.5, 2.5\"\nsecond,nowhere\n";
+        let mut reader = ReaderBuilder::new().from_reader(input.as_bytes());
+        let columns: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let opt = Opt::from_iter(["bulk", "-b", "tree.bin", "--loc-col", "position"]);
+        let header = StringRecord::from(columns.clone());
+        let handler = Handler::csv(OnError::Emit, &columns, vec![8])
+            .with_columns(CsvColumns::new(&header, &opt).unwrap())
+            .with_order(AxisOrder::LatLng);
+        let rows = reader.into_records().map(|row| Ok(Raw::Csv(row.unwrap())));
+        let mut out = vec![];
+        resolve_ordered(&tree, rows, 2, &handler, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "first,\"0.5, 2.5\",east,");
+        assert!(lines[1].starts_with("second,nowhere,,\"invalid location"));
+    }
+
+    #[test]
+    fn enriches_record_batches() {
+        use arrow::array::{Float32Array, Float64Array, StringArray};
+
+        let mut county = square(0., "county");
+        county.osm_id = Some(7);
+        let tree = Tree::from(RTree::bulk_load(vec![square(0., "west"), county]));
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("lon", DataType::Float32, true),
+            Field::new("lat", DataType::Float64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["first", "second", "third"])),
+                Arc::new(Float32Array::from(vec![Some(0.5), Some(5.5), None])),
+                Arc::new(Float64Array::from(vec![0.5, 0.5, 0.5])),
+            ],
+        )
+        .unwrap();
+        let opt = Opt::from_iter(["bulk", "-b", "tree.bin", "--lng-col", "lon"]);
+        let columns = BatchColumns::new(&schema, &opt).unwrap();
+
+        for (format, on_error) in [
+            (Format::Parquet, OnError::Emit),
+            (Format::Arrow, OnError::Skip),
+        ] {
+            let mut handler = Handler::new(on_error);
+            handler.levels = vec![8];
+            let enriched_schema = enriched_schema(&schema, &handler);
+            let mut file = tempfile::tempfile().unwrap();
+            let mut writer = BatchWriter::new(format, &mut file, &enriched_schema).unwrap();
+            let batches: Batches = Box::new(vec![Ok(batch.clone())].into_iter());
+            enrich(
+                &tree,
+                &handler,
+                batches,
+                &columns,
+                &mut writer,
+                &enriched_schema,
+            )
+            .unwrap();
+            writer.finish().unwrap();
+            file.rewind().unwrap();
+
+            let (read_schema, batches) = read_batches(format, file, 10).unwrap();
+            assert_eq!(read_schema.field(4).name(), "level_8_name");
+            let batches: Vec<RecordBatch> = batches.map(Result::unwrap).collect();
+            let names = batches[0].column(4).as_string::<i32>();
+            let ids = batches[0].column(3).as_string::<i32>();
+            let mut pairs: Vec<(&str, &str)> = ids
+                .value(0)
+                .split(';')
+                .zip(names.value(0).split(';'))
+                .collect();
+            pairs.sort_unstable();
+            assert_eq!(pairs, [("", "west"), ("7", "county")]);
+            assert!(ids.is_null(1));
+            assert!(names.is_null(1));
+            match on_error {
+                OnError::Emit => {
+                    assert_eq!(batches[0].num_rows(), 3);
+                    let errors = batches[0].column(5).as_string::<i32>();
+                    assert!(errors.is_null(0));
+                    assert_eq!(errors.value(2), "missing or invalid lng");
+                }
+                _ => assert_eq!(batches[0].num_rows(), 2),
+            }
+            let summary = "lines read: 3, resolved: 1, unmatched: 1, failed: 1";
+            assert_eq!(handler.summary.to_string(), summary);
+        }
+    }
+}
@@ -1,19 +1,35 @@
 use super::boundary::Boundary;
+use geo_types::Point;
 use geojson::{Feature, Geometry, Value};
+use serde_json::json;
 use serde_json::map::Map;
-use serde_json::to_value;
 use std::io::Write;
 
 impl Boundary {
     pub fn to_feature(&self) -> Feature {
-        let properties = match to_value(&self.name) {
-            Ok(value) => {
-                let mut map = Map::new();
-                map.insert("name".to_string(), value);
-                Some(map)
+        let mut properties = Map::new();
+        properties.insert("name".to_string(), self.name.clone().into());
+        if let Some(id) = self.osm_id {
+            properties.insert("id".to_string(), id.into());
+        }
+        properties.insert("admin_level".to_string(), self.admin_level.into());
+        properties.insert("area_km2".to_string(), self.area.into());
+        let point = |p: Point<f64>| vec![p.x(), p.y()].into();
+        properties.insert("centroid".to_string(), point(self.centroid));
+        properties.insert("pole".to_string(), point(self.pole));
+        let members = [("admin_centre", &self.admin_centre), ("label", &self.label)];
+        for (role, member) in members {
+            if let Some(member) = member {
+                let value = json!({
+                    "id": member.osm_id,
+                    "name": member.name,
+                    "coordinates": [member.point.x(), member.point.y()],
+                });
+                properties.insert(role.to_string(), value);
             }
-            _ => None,
-        };
+        }
+        properties.insert("tags".to_string(), json!(self.tags));
+        let properties = Some(properties);
 
         let value = Value::from(&self.mp);
         let geometry = Geometry::new(value);
@@ -28,13 +44,30 @@ impl Boundary {
     }
 }
 
+/// A point feature for a queried location, marked by a `query` property.
+pub fn query_feature(point: Point<f64>) -> Feature {
+    let mut properties = Map::new();
+    properties.insert("query".to_string(), true.into());
+    Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(Value::from(&point))),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+/// Write boundaries as feature collection, followed by a point feature for
+/// each of the `queries` locations.
 pub fn write_geojson(
     mut writer: impl Write,
     boundaries: Vec<&Boundary>,
+    queries: &[Point<f64>],
 ) -> Result<(), std::io::Error> {
     let features = boundaries
         .iter()
         .map(|boundary| boundary.to_feature())
+        .chain(queries.iter().copied().map(query_feature))
         .collect();
 
     let feature_collection = geojson::FeatureCollection {
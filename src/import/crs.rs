@@ -0,0 +1,185 @@
+use geo::algorithm::map_coords::MapCoords;
+use geo_types::{Coord, MultiPolygon};
+use lazy_static::lazy_static;
+use proj4rs::transform::transform;
+use proj4rs::Proj;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+const WGS84: &str = "+proj=longlat +datum=WGS84 +no_defs";
+
+lazy_static! {
+    /// Target of every conversion, set up once.
+    static ref WGS84_PROJ: Proj =
+        Proj::from_proj_string(WGS84).expect("WGS84 is a valid proj string");
+}
+
+/// Coordinate reference system of an imported dataset. Geographic systems
+/// (EPSG:4326, EPSG:4258) are passed through, projected ones are converted
+/// to WGS84.
+pub struct Crs {
+    code: u32,
+    proj: Option<Proj>,
+}
+
+impl fmt::Debug for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EPSG:{}", self.code)
+    }
+}
+
+fn proj_string(code: u32) -> Result<Option<String>, String> {
+    let proj = match code {
+        4326 | 4258 => return Ok(None),
+        3035 => "+proj=laea +lat_0=52 +lon_0=10 +x_0=4321000 +y_0=3210000 +ellps=GRS80 +units=m +no_defs".to_string(),
+        3857 => "+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m +no_defs".to_string(),
+        25828..=25838 => format!("+proj=utm +zone={} +ellps=GRS80 +units=m +no_defs", code - 25800),
+        32601..=32660 => format!("+proj=utm +zone={} +datum=WGS84 +units=m +no_defs", code - 32600),
+        _ => return Err(format!("unsupported crs EPSG:{}", code)),
+    };
+    Ok(Some(proj))
+}
+
+impl Crs {
+    pub fn from_epsg(code: u32) -> Result<Crs, String> {
+        let proj = match proj_string(code)? {
+            Some(s) => Some(Proj::from_proj_string(&s).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        Ok(Crs { code, proj })
+    }
+
+    pub fn wgs84() -> Crs {
+        Crs {
+            code: 4326,
+            proj: None,
+        }
+    }
+
+    /// Guess the crs from the WKT in a shapefile's `.prj` sidecar. Only the
+    /// systems supported by `from_epsg` are recognized.
+    pub fn from_prj(wkt: &str) -> Option<Crs> {
+        let normalized = wkt.to_uppercase().replace(' ', "_");
+        if let Some(code) = authority_code(&normalized) {
+            return Crs::from_epsg(code).ok();
+        }
+        if !normalized.starts_with("PROJCS") {
+            return Some(Crs::wgs84());
+        }
+        if normalized.contains("LAEA") || normalized.contains("LAMBERT_AZIMUTHAL_EQUAL_AREA") {
+            return Crs::from_epsg(3035).ok();
+        }
+        if normalized.contains("MERCATOR_AUXILIARY_SPHERE")
+            || normalized.contains("PSEUDO-MERCATOR")
+        {
+            return Crs::from_epsg(3857).ok();
+        }
+        let zone_pos = normalized.find("UTM_ZONE_")? + "UTM_ZONE_".len();
+        let zone: String = normalized[zone_pos..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        if !normalized[zone_pos + zone.len()..].starts_with('N') {
+            return None;
+        }
+        let zone: u32 = zone.parse().ok()?;
+        let base = if normalized.contains("ETRS") {
+            25800
+        } else {
+            32600
+        };
+        Crs::from_epsg(base + zone).ok()
+    }
+
+    pub fn to_wgs84(&self, mp: MultiPolygon<f64>) -> Result<MultiPolygon<f64>, Box<dyn Error>> {
+        let src = match &self.proj {
+            Some(proj) => proj,
+            None => return Ok(mp),
+        };
+        let mp = mp.try_map_coords(|Coord { x, y }| {
+            let mut point = (x, y, 0.0);
+            transform(src, &WGS84_PROJ, &mut point).map_err(|e| e.to_string())?;
+            Ok::<_, String>(Coord {
+                x: point.0.to_degrees(),
+                y: point.1.to_degrees(),
+            })
+        })?;
+        Ok(mp)
+    }
+}
+
+/// The outermost `AUTHORITY["EPSG","..."]` node is the last one in the WKT.
+fn authority_code(wkt: &str) -> Option<u32> {
+    let pos = wkt.rfind("AUTHORITY[\"EPSG\",\"")? + "AUTHORITY[\"EPSG\",\"".len();
+    let code: String = wkt[pos..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    code.parse().ok()
+}
+
+impl FromStr for Crs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s
+            .trim()
+            .trim_start_matches("EPSG:")
+            .trim_start_matches("epsg:");
+        let code = code
+            .parse()
+            .map_err(|_| format!("crs must be given as EPSG:<code>, got {}", s))?;
+        Crs::from_epsg(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+
+    fn first_coord(mp: &MultiPolygon<f64>) -> Coord<f64> {
+        mp.0[0].exterior().0[0]
+    }
+
+    #[test]
+    fn reprojects_laea_origin() {
+        let crs: Crs = "EPSG:3035".parse().unwrap();
+        let mp: MultiPolygon<f64> = polygon![
+            (x: 4321000., y: 3210000.),
+            (x: 4321100., y: 3210000.),
+            (x: 4321100., y: 3210100.),
+            (x: 4321000., y: 3210000.),
+        ]
+        .into();
+        let coord = first_coord(&crs.to_wgs84(mp).unwrap());
+        assert!((coord.x - 10.).abs() < 1e-9);
+        assert!((coord.y - 52.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reprojects_utm_central_meridian() {
+        let crs: Crs = "25832".parse().unwrap();
+        let mp: MultiPolygon<f64> = polygon![
+            (x: 500000., y: 5800000.),
+            (x: 500100., y: 5800000.),
+            (x: 500100., y: 5800100.),
+            (x: 500000., y: 5800000.),
+        ]
+        .into();
+        let coord = first_coord(&crs.to_wgs84(mp).unwrap());
+        assert!((coord.x - 9.).abs() < 1e-9);
+        assert!(coord.y > 52. && coord.y < 52.5);
+    }
+
+    #[test]
+    fn detects_crs_from_prj() {
+        let utm = r#"PROJCS["ETRS_1989_UTM_Zone_32N",GEOGCS["GCS_ETRS_1989"]]"#;
+        assert_eq!(Crs::from_prj(utm).unwrap().code, 25832);
+        let geographic = r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984"]]"#;
+        assert_eq!(Crs::from_prj(geographic).unwrap().code, 4326);
+        let authority = r#"PROJCS["ETRS89-extended / LAEA Europe",AUTHORITY["EPSG","3035"]]"#;
+        assert_eq!(Crs::from_prj(authority).unwrap().code, 3035);
+    }
+}
@@ -0,0 +1,156 @@
+use super::{invalid_data, le_u16, le_u32, to_usize};
+use std::collections::HashMap;
+use std::io;
+
+const DESCRIPTOR_LEN: usize = 32;
+const HEADER_TERMINATOR: u8 = 0x0d;
+const DELETED: u8 = b'*';
+
+struct Field {
+    name: String,
+    len: usize,
+}
+
+pub struct Table {
+    pub columns: Vec<String>,
+    /// attributes by column name, `None` for deleted records
+    pub records: Vec<Option<HashMap<String, String>>>,
+}
+
+/// Decode as UTF-8, falling back to Latin-1 which older tables commonly use.
+fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Parse a dBASE `.dbf` attribute table, values are returned as trimmed
+/// strings regardless of the field type.
+pub fn parse(buf: &[u8]) -> io::Result<Table> {
+    let num_records = to_usize(le_u32(buf, 4)?)?;
+    let header_len = usize::from(le_u16(buf, 8)?);
+    let record_len = usize::from(le_u16(buf, 10)?);
+
+    let mut fields = vec![];
+    let mut pos = DESCRIPTOR_LEN;
+    while buf.get(pos) != Some(&HEADER_TERMINATOR) && pos + DESCRIPTOR_LEN <= header_len {
+        let descriptor = buf
+            .get(pos..pos + DESCRIPTOR_LEN)
+            .ok_or_else(|| invalid_data("truncated dbf header"))?;
+        let name_len = descriptor[..11].iter().position(|&b| b == 0).unwrap_or(11);
+        fields.push(Field {
+            name: decode(&descriptor[..name_len]),
+            len: usize::from(descriptor[16]),
+        });
+        pos += DESCRIPTOR_LEN;
+    }
+
+    // checked up front, as the record count is used to size the table
+    num_records
+        .checked_mul(record_len)
+        .and_then(|len| len.checked_add(header_len))
+        .filter(|&len| len <= buf.len())
+        .ok_or_else(|| invalid_data("truncated dbf records"))?;
+    let records = (0..num_records)
+        .map(|i| {
+            let start = header_len + i * record_len;
+            let record = buf
+                .get(start..start + record_len)
+                .ok_or_else(|| invalid_data("truncated dbf record"))?;
+            let flag = record
+                .first()
+                .ok_or_else(|| invalid_data("empty dbf record"))?;
+            if *flag == DELETED {
+                return Ok(None);
+            }
+            let mut attributes = HashMap::new();
+            let mut offset = 1;
+            for field in &fields {
+                let value = record
+                    .get(offset..offset + field.len)
+                    .ok_or_else(|| invalid_data("dbf field exceeds record"))?;
+                let value = decode(value)
+                    .trim_matches(|c| c == ' ' || c == '\0')
+                    .to_string();
+                attributes.insert(field.name.clone(), value);
+                offset += field.len;
+            }
+            Ok(Some(attributes))
+        })
+        .collect::<io::Result<_>>()?;
+
+    let columns = fields.into_iter().map(|field| field.name).collect();
+    Ok(Table { columns, records })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(name: &str, len: u8) -> Vec<u8> {
+        let mut descriptor = vec![0u8; DESCRIPTOR_LEN];
+        descriptor[..name.len()].copy_from_slice(name.as_bytes());
+        descriptor[11] = b'C';
+        descriptor[16] = len;
+        descriptor
+    }
+
+    #[test]
+    fn parses_records() {
+        let header_len = DESCRIPTOR_LEN * 3 + 1;
+        let record_len = 1 + 10 + 2;
+        let mut buf = vec![0u8; DESCRIPTOR_LEN];
+        buf[4..8].copy_from_slice(&2u32.to_le_bytes());
+        buf[8..10].copy_from_slice(&(header_len as u16).to_le_bytes());
+        buf[10..12].copy_from_slice(&(record_len as u16).to_le_bytes());
+        buf.extend(descriptor("GEN", 10));
+        buf.extend(descriptor("LEVEL", 2));
+        buf.push(HEADER_TERMINATOR);
+        buf.extend(b" M\xfcnster    8");
+        buf.extend(b"*Deleted    6");
+
+        let table = parse(&buf).unwrap();
+        assert_eq!(table.columns, ["GEN", "LEVEL"]);
+        let record = table.records[0].as_ref().unwrap();
+        assert_eq!(record["GEN"], "Münster");
+        assert_eq!(record["LEVEL"], "8");
+        assert!(table.records[1].is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let mut buf = vec![0u8; DESCRIPTOR_LEN];
+        buf[4..8].copy_from_slice(&1u32.to_le_bytes());
+        buf[8..10].copy_from_slice(&(DESCRIPTOR_LEN as u16 + 1).to_le_bytes());
+        buf.push(HEADER_TERMINATOR);
+
+        let cases = [
+            (&buf[..8], "unexpected end of data"),
+            (&buf[..], "empty dbf record"),
+        ];
+        for (buf, message) in cases {
+            let error = parse(buf).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+
+        let mut header = buf[..DESCRIPTOR_LEN].to_vec();
+        header.extend(descriptor("GEN", 10));
+        header.truncate(DESCRIPTOR_LEN + 8);
+        header[8..10].copy_from_slice(&(DESCRIPTOR_LEN as u16 * 3).to_le_bytes());
+        let error = parse(&header).err().unwrap();
+        assert_eq!(error.to_string(), "truncated dbf header");
+
+        let mut truncated = buf.clone();
+        truncated[8..10].copy_from_slice(&(DESCRIPTOR_LEN as u16 * 3).to_le_bytes());
+        truncated[10..12].copy_from_slice(&4u16.to_le_bytes());
+        let error = parse(&truncated).err().unwrap();
+        assert_eq!(error.to_string(), "truncated dbf records");
+
+        truncated[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        truncated[8..10].copy_from_slice(&(DESCRIPTOR_LEN as u16 + 1).to_le_bytes());
+        let error = parse(&truncated).err().unwrap();
+        assert_eq!(error.to_string(), "truncated dbf records");
+    }
+}
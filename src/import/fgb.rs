@@ -0,0 +1,391 @@
+use super::crs::Crs;
+use super::{invalid_data, le_f64, le_i32, le_u16, le_u32, read_bytes, to_usize};
+use super::{Dataset, Feature};
+use geo_types::{Coord, LineString, MultiPolygon, Polygon};
+use std::collections::HashMap;
+use std::io;
+
+const MAGIC: &[u8] = b"fgb";
+const MAGIC_LEN: usize = 8;
+const NODE_ITEM_LEN: usize = 40;
+
+const POLYGON: u8 = 3;
+const MULTI_POLYGON: u8 = 6;
+
+// field slots as declared in the FlatGeobuf schema
+mod slot {
+    pub const HEADER_GEOMETRY_TYPE: usize = 2;
+    pub const HEADER_COLUMNS: usize = 7;
+    pub const HEADER_FEATURES_COUNT: usize = 8;
+    pub const HEADER_INDEX_NODE_SIZE: usize = 9;
+    pub const HEADER_CRS: usize = 10;
+    pub const COLUMN_NAME: usize = 0;
+    pub const COLUMN_TYPE: usize = 1;
+    pub const CRS_CODE: usize = 1;
+    pub const FEATURE_GEOMETRY: usize = 0;
+    pub const FEATURE_PROPERTIES: usize = 1;
+    pub const GEOMETRY_ENDS: usize = 0;
+    pub const GEOMETRY_XY: usize = 1;
+    pub const GEOMETRY_TYPE: usize = 6;
+    pub const GEOMETRY_PARTS: usize = 7;
+}
+
+/// Minimal read-only view on a flatbuffers table.
+struct Table<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    vtable: usize,
+    vtable_len: usize,
+}
+
+impl<'a> Table<'a> {
+    fn root(buf: &'a [u8]) -> io::Result<Table<'a>> {
+        Table::at(buf, to_usize(le_u32(buf, 0)?)?)
+    }
+
+    fn at(buf: &'a [u8], pos: usize) -> io::Result<Table<'a>> {
+        let vtable = to_usize(pos as i64 - i64::from(le_i32(buf, pos)?))?;
+        let vtable_len = usize::from(le_u16(buf, vtable)?);
+        Ok(Table {
+            buf,
+            pos,
+            vtable,
+            vtable_len,
+        })
+    }
+
+    fn field(&self, slot: usize) -> io::Result<Option<usize>> {
+        let entry = 4 + 2 * slot;
+        if entry + 2 > self.vtable_len {
+            return Ok(None);
+        }
+        let offset = usize::from(le_u16(self.buf, self.vtable + entry)?);
+        Ok(if offset == 0 {
+            None
+        } else {
+            Some(self.pos + offset)
+        })
+    }
+
+    fn scalar<const N: usize>(&self, slot: usize) -> io::Result<Option<[u8; N]>> {
+        self.field(slot)?
+            .map(|pos| read_bytes(self.buf, pos))
+            .transpose()
+    }
+
+    fn indirect(&self, slot: usize) -> io::Result<Option<usize>> {
+        match self.field(slot)? {
+            Some(pos) => Ok(Some(pos + to_usize(le_u32(self.buf, pos)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn table(&self, slot: usize) -> io::Result<Option<Table<'a>>> {
+        self.indirect(slot)?
+            .map(|pos| Table::at(self.buf, pos))
+            .transpose()
+    }
+
+    /// Start position and length of a vector field.
+    fn vector(&self, slot: usize) -> io::Result<Option<(usize, usize)>> {
+        match self.indirect(slot)? {
+            Some(pos) => Ok(Some((pos + 4, to_usize(le_u32(self.buf, pos)?)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn bytes(&self, slot: usize) -> io::Result<&'a [u8]> {
+        match self.vector(slot)? {
+            Some((start, len)) => self
+                .buf
+                .get(start..start + len)
+                .ok_or_else(|| invalid_data("vector exceeds buffer")),
+            None => Ok(&[]),
+        }
+    }
+
+    fn string(&self, slot: usize) -> io::Result<Option<String>> {
+        match self.vector(slot)? {
+            Some(_) => Ok(Some(
+                String::from_utf8_lossy(self.bytes(slot)?).into_owned(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn tables(&self, slot: usize) -> io::Result<Vec<Table<'a>>> {
+        let (start, len) = match self.vector(slot)? {
+            Some(vector) => vector,
+            None => return Ok(vec![]),
+        };
+        (0..len)
+            .map(|i| {
+                let pos = start + 4 * i;
+                Table::at(self.buf, pos + to_usize(le_u32(self.buf, pos)?)?)
+            })
+            .collect()
+    }
+}
+
+/// Parse a FlatGeobuf file. Features with non-polygonal geometries are
+/// skipped.
+pub fn parse(buf: &[u8]) -> io::Result<Dataset> {
+    if buf.get(..MAGIC.len()) != Some(MAGIC) {
+        return Err(invalid_data("not a flatgeobuf file"));
+    }
+    let header_len = to_usize(le_u32(buf, MAGIC_LEN)?)?;
+    let header_buf = buf
+        .get(MAGIC_LEN + 4..MAGIC_LEN + 4 + header_len)
+        .ok_or_else(|| invalid_data("truncated flatgeobuf header"))?;
+    let header = Table::root(header_buf)?;
+
+    let geometry_type = header
+        .scalar::<1>(slot::HEADER_GEOMETRY_TYPE)?
+        .map_or(0, |[t]| t);
+    let columns = header
+        .tables(slot::HEADER_COLUMNS)?
+        .iter()
+        .map(|column| {
+            let name = column.string(slot::COLUMN_NAME)?.unwrap_or_default();
+            let column_type = column.scalar::<1>(slot::COLUMN_TYPE)?.map_or(0, |[t]| t);
+            Ok((name, column_type))
+        })
+        .collect::<io::Result<Vec<(String, u8)>>>()?;
+    let crs = match header.table(slot::HEADER_CRS)? {
+        Some(crs) => match crs.scalar(slot::CRS_CODE)?.map(i32::from_le_bytes) {
+            Some(code) if code > 0 => Some(Crs::from_epsg(code as u32).map_err(invalid_data)?),
+            _ => None,
+        },
+        None => None,
+    };
+
+    let features_count = header
+        .scalar(slot::HEADER_FEATURES_COUNT)?
+        .map_or(0, u64::from_le_bytes);
+    let node_size = header
+        .scalar(slot::HEADER_INDEX_NODE_SIZE)?
+        .map_or(16, u16::from_le_bytes);
+    let mut pos = MAGIC_LEN + 4 + header_len;
+    if node_size > 0 && features_count > 0 {
+        pos += index_len(to_usize(features_count)?, usize::from(node_size));
+    }
+
+    let mut features = vec![];
+    while pos < buf.len() {
+        let len = to_usize(le_u32(buf, pos)?)?;
+        let feature_buf = buf
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| invalid_data("truncated flatgeobuf feature"))?;
+        let feature = Table::root(feature_buf)?;
+        if let Some(mp) = parse_geometry(&feature, geometry_type)? {
+            let attributes = parse_properties(feature.bytes(slot::FEATURE_PROPERTIES)?, &columns)?;
+            features.push(Feature { attributes, mp });
+        }
+        pos += 4 + len;
+    }
+
+    let columns = columns.into_iter().map(|(name, _)| name).collect();
+    Ok(Dataset {
+        columns,
+        crs,
+        features,
+    })
+}
+
+/// Size of the packed hilbert r-tree which precedes the features.
+fn index_len(num_items: usize, node_size: usize) -> usize {
+    let node_size = node_size.clamp(2, 65535);
+    let mut n = num_items;
+    let mut num_nodes = n;
+    loop {
+        n = n.div_ceil(node_size);
+        num_nodes += n;
+        if n == 1 {
+            break;
+        }
+    }
+    num_nodes * NODE_ITEM_LEN
+}
+
+fn parse_geometry(feature: &Table, header_type: u8) -> io::Result<Option<MultiPolygon<f64>>> {
+    let geometry = match feature.table(slot::FEATURE_GEOMETRY)? {
+        Some(geometry) => geometry,
+        None => return Ok(None),
+    };
+    let geometry_type = match header_type {
+        0 => geometry
+            .scalar::<1>(slot::GEOMETRY_TYPE)?
+            .map_or(0, |[t]| t),
+        t => t,
+    };
+    match geometry_type {
+        POLYGON => Ok(Some(MultiPolygon(vec![parse_polygon(&geometry)?]))),
+        MULTI_POLYGON => {
+            let polygons = geometry
+                .tables(slot::GEOMETRY_PARTS)?
+                .iter()
+                .map(parse_polygon)
+                .collect::<io::Result<_>>()?;
+            Ok(Some(MultiPolygon(polygons)))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn parse_polygon(geometry: &Table) -> io::Result<Polygon<f64>> {
+    let (start, len) = geometry
+        .vector(slot::GEOMETRY_XY)?
+        .ok_or_else(|| invalid_data("polygon without coordinates"))?;
+    let coords = (0..len / 2)
+        .map(|i| {
+            let x = le_f64(geometry.buf, start + 16 * i)?;
+            let y = le_f64(geometry.buf, start + 16 * i + 8)?;
+            Ok(Coord { x, y })
+        })
+        .collect::<io::Result<Vec<Coord<f64>>>>()?;
+
+    // ring ends are given as point indices, a single ring may omit them
+    let mut ends = match geometry.vector(slot::GEOMETRY_ENDS)? {
+        Some((start, len)) => (0..len)
+            .map(|i| to_usize(le_u32(geometry.buf, start + 4 * i)?))
+            .collect::<io::Result<Vec<usize>>>()?,
+        None => vec![],
+    };
+    if ends.is_empty() {
+        ends.push(coords.len());
+    }
+    let mut rings = vec![];
+    let mut ring_start = 0;
+    for end in ends {
+        let ring = coords
+            .get(ring_start..end)
+            .ok_or_else(|| invalid_data("invalid ring ends"))?;
+        rings.push(LineString(ring.to_vec()));
+        ring_start = end;
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+fn parse_properties(buf: &[u8], columns: &[(String, u8)]) -> io::Result<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let index = usize::from(le_u16(buf, pos)?);
+        let (name, column_type) = columns
+            .get(index)
+            .ok_or_else(|| invalid_data("property references unknown column"))?;
+        pos += 2;
+        let (value, len) = match column_type {
+            0 => ((read_bytes::<1>(buf, pos)?[0] as i8).to_string(), 1),
+            1 | 2 => (read_bytes::<1>(buf, pos)?[0].to_string(), 1),
+            3 => (i16::from_le_bytes(read_bytes(buf, pos)?).to_string(), 2),
+            4 => (le_u16(buf, pos)?.to_string(), 2),
+            5 => (le_i32(buf, pos)?.to_string(), 4),
+            6 => (le_u32(buf, pos)?.to_string(), 4),
+            7 => (i64::from_le_bytes(read_bytes(buf, pos)?).to_string(), 8),
+            8 => (u64::from_le_bytes(read_bytes(buf, pos)?).to_string(), 8),
+            9 => (f32::from_le_bytes(read_bytes(buf, pos)?).to_string(), 4),
+            10 => (le_f64(buf, pos)?.to_string(), 8),
+            _ => {
+                let len = to_usize(le_u32(buf, pos)?)?;
+                let bytes = buf
+                    .get(pos + 4..pos + 4 + len)
+                    .ok_or_else(|| invalid_data("truncated property"))?;
+                (String::from_utf8_lossy(bytes).into_owned(), 4 + len)
+            }
+        };
+        attributes.insert(name.clone(), value);
+        pos += len;
+    }
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flatbuffers::{FlatBufferBuilder, WIPOffset};
+
+    const STRING: u8 = 11;
+    const INT: u8 = 5;
+
+    fn slot_offset(slot: usize) -> u16 {
+        (4 + 2 * slot) as u16
+    }
+
+    fn header() -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let columns: Vec<_> = [("GEN", STRING), ("LEVEL", INT)]
+            .iter()
+            .map(|(name, column_type)| {
+                let name = fbb.create_string(name);
+                let start = fbb.start_table();
+                fbb.push_slot_always(slot_offset(slot::COLUMN_NAME), name);
+                fbb.push_slot_always(slot_offset(slot::COLUMN_TYPE), *column_type);
+                fbb.end_table(start)
+            })
+            .collect();
+        let columns = fbb.create_vector(&columns);
+        let start = fbb.start_table();
+        let crs = fbb.end_table(start);
+        let start = fbb.start_table();
+        fbb.push_slot_always(slot_offset(slot::HEADER_COLUMNS), columns);
+        fbb.push_slot_always(slot_offset(slot::HEADER_CRS), crs);
+        fbb.push_slot_always(slot_offset(slot::HEADER_GEOMETRY_TYPE), MULTI_POLYGON);
+        fbb.push_slot_always(slot_offset(slot::HEADER_FEATURES_COUNT), 1u64);
+        fbb.push_slot_always(slot_offset(slot::HEADER_INDEX_NODE_SIZE), 0u16);
+        let root: WIPOffset<flatbuffers::TableFinishedWIPOffset> = fbb.end_table(start);
+        fbb.finish_size_prefixed(root, None);
+        fbb.finished_data().to_vec()
+    }
+
+    fn feature() -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let xy = fbb.create_vector(&[0., 0., 0., 1., 1., 1., 1., 0., 0., 0.]);
+        let start = fbb.start_table();
+        fbb.push_slot_always(slot_offset(slot::GEOMETRY_XY), xy);
+        let polygon = fbb.end_table(start);
+        let parts = fbb.create_vector(&[polygon]);
+        let start = fbb.start_table();
+        fbb.push_slot_always(slot_offset(slot::GEOMETRY_PARTS), parts);
+        let geometry = fbb.end_table(start);
+
+        let mut properties: Vec<u8> = vec![];
+        properties.extend(&0u16.to_le_bytes());
+        properties.extend(&6u32.to_le_bytes());
+        properties.extend(b"Bremen");
+        properties.extend(&1u16.to_le_bytes());
+        properties.extend(&4i32.to_le_bytes());
+        let properties = fbb.create_vector(&properties);
+
+        let start = fbb.start_table();
+        fbb.push_slot_always(slot_offset(slot::FEATURE_GEOMETRY), geometry);
+        fbb.push_slot_always(slot_offset(slot::FEATURE_PROPERTIES), properties);
+        let root: WIPOffset<flatbuffers::TableFinishedWIPOffset> = fbb.end_table(start);
+        fbb.finish_size_prefixed(root, None);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn parses_multi_polygon_features() {
+        let mut buf = b"fgb\x03fgb\x00".to_vec();
+        buf.extend(header());
+        buf.extend(feature());
+
+        let dataset = parse(&buf).unwrap();
+        assert_eq!(dataset.columns, ["GEN", "LEVEL"]);
+        assert!(dataset.crs.is_none());
+        assert_eq!(dataset.features.len(), 1);
+        let feature = &dataset.features[0];
+        assert_eq!(feature.attributes["GEN"], "Bremen");
+        assert_eq!(feature.attributes["LEVEL"], "4");
+        assert_eq!(feature.mp.0.len(), 1);
+        assert_eq!(feature.mp.0[0].exterior().0.len(), 5);
+    }
+
+    #[test]
+    fn computes_index_len() {
+        assert_eq!(index_len(1, 16), 2 * NODE_ITEM_LEN);
+        assert_eq!(index_len(100, 16), (100 + 7 + 1) * NODE_ITEM_LEN);
+    }
+}
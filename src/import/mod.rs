@@ -0,0 +1,235 @@
+use crate::boundary::Boundary;
+use geo::algorithm::intersects::Intersects;
+use geo::algorithm::winding_order::Winding;
+use geo_types::{LineString, MultiPolygon, Point, Polygon};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub mod crs;
+// Shapefiles and FlatGeobuf are read by readers of their own. Imports need
+// polygons and plain attributes only, which takes a few hundred lines, the
+// `shapefile` and `flatgeobuf` crates would bring dbase, flatbuffers and
+// geozero into every build, the service included.
+mod dbf;
+mod fgb;
+mod shp;
+
+pub use crs::Crs;
+
+/// A feature read from a dataset, geometry still in the dataset's crs.
+struct Feature {
+    attributes: HashMap<String, String>,
+    mp: MultiPolygon<f64>,
+}
+
+struct Dataset {
+    columns: Vec<String>,
+    crs: Option<Crs>,
+    features: Vec<Feature>,
+}
+
+/// Maps dataset attributes onto `Boundary` fields. The level is read from
+/// `level_field` if given, otherwise every feature gets the fixed `level`.
+#[derive(Debug, Clone)]
+pub struct FieldMapping {
+    pub name_field: String,
+    pub level_field: Option<String>,
+    pub level: Option<u8>,
+}
+
+impl FieldMapping {
+    fn validate(&self, columns: &[String]) -> Result<(), Box<dyn Error>> {
+        let mut fields = vec![&self.name_field];
+        fields.extend(&self.level_field);
+        for field in fields {
+            if !columns.contains(field) {
+                let msg = format!(
+                    "attribute {} not found, available: {}",
+                    field,
+                    columns.join(", ")
+                );
+                return Err(msg.into());
+            }
+        }
+        if self.level_field.is_none() && self.level.is_none() {
+            return Err("either a level field or a fixed level is required".into());
+        }
+        Ok(())
+    }
+
+    fn level(&self, attributes: &HashMap<String, String>) -> Option<u8> {
+        let field = match &self.level_field {
+            Some(field) => field,
+            None => return self.level,
+        };
+        let value = attributes.get(field)?.trim();
+        if let Ok(level) = value.parse() {
+            return Some(level);
+        }
+        let level: f64 = value.parse().ok()?;
+        if level.fract() != 0. || !(0. ..=255.).contains(&level) {
+            return None;
+        }
+        Some(level as u8)
+    }
+}
+
+fn get_boundaries(
+    dataset: Dataset,
+    mapping: &FieldMapping,
+    crs: Option<&Crs>,
+    admin_levels: &[u8],
+) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let Dataset {
+        columns,
+        crs: dataset_crs,
+        features,
+    } = dataset;
+    mapping.validate(&columns)?;
+    let wgs84 = Crs::wgs84();
+    let crs = crs.or(dataset_crs.as_ref()).unwrap_or(&wgs84);
+
+    let mut boundaries = vec![];
+    for feature in features {
+        let name = match feature.attributes.get(&mapping.name_field) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        let admin_level = match mapping.level(&feature.attributes) {
+            Some(level) if admin_levels.contains(&level) => level,
+            _ => continue,
+        };
+        if feature.mp.0.is_empty() {
+            continue;
+        }
+        let mp = crs.to_wgs84(feature.mp)?;
+        let mut boundary = Boundary::new(mp, name, admin_level);
+        boundary.tags = feature
+            .attributes
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        boundaries.push(boundary);
+    }
+    Ok(boundaries)
+}
+
+/// Read polygon features from an ESRI shapefile. The `.dbf` attribute table
+/// and the optional `.prj` are expected next to the `.shp` file.
+pub fn get_shp_boundaries(
+    path: PathBuf,
+    mapping: &FieldMapping,
+    crs: Option<&Crs>,
+    admin_levels: &[u8],
+) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let geometries = shp::parse(&fs::read(&path)?)?;
+    let table = dbf::parse(&fs::read(path.with_extension("dbf"))?)?;
+    if geometries.len() != table.records.len() {
+        let msg = format!(
+            "shp has {} records, dbf has {}",
+            geometries.len(),
+            table.records.len()
+        );
+        return Err(msg.into());
+    }
+    let prj_crs = read_prj(&path.with_extension("prj"))?;
+
+    let features = geometries
+        .into_iter()
+        .zip(table.records)
+        .filter_map(|(mp, attributes)| {
+            Some(Feature {
+                attributes: attributes?,
+                mp: mp?,
+            })
+        })
+        .collect();
+    let dataset = Dataset {
+        columns: table.columns,
+        crs: prj_crs,
+        features,
+    };
+    get_boundaries(dataset, mapping, crs, admin_levels)
+}
+
+fn read_prj(path: &Path) -> Result<Option<Crs>, Box<dyn Error>> {
+    let wkt = match fs::read_to_string(path) {
+        Ok(wkt) => wkt,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match Crs::from_prj(&wkt) {
+        Some(crs) => Ok(Some(crs)),
+        None => Err(format!("unsupported crs in {:?}, specify it explicitly", path).into()),
+    }
+}
+
+/// Read polygon features from a FlatGeobuf file.
+pub fn get_fgb_boundaries(
+    path: PathBuf,
+    mapping: &FieldMapping,
+    crs: Option<&Crs>,
+    admin_levels: &[u8],
+) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let dataset = fgb::parse(&fs::read(path)?)?;
+    get_boundaries(dataset, mapping, crs, admin_levels)
+}
+
+/// Assemble polygons from rings, outer rings being clockwise and holes
+/// counter-clockwise. Holes are assigned to the first outer ring they touch.
+fn polygons_from_rings(rings: Vec<LineString<f64>>) -> MultiPolygon<f64> {
+    let (outers, holes): (Vec<_>, Vec<_>) = rings.into_iter().partition(|ring| ring.is_cw());
+    let mut polygons: Vec<Polygon<f64>> = outers
+        .into_iter()
+        .map(|ring| Polygon::new(ring, vec![]))
+        .collect();
+    for hole in holes {
+        let outer = hole.0.first().and_then(|coord| {
+            let point = Point::from(*coord);
+            polygons
+                .iter()
+                .position(|polygon| polygon.intersects(&point))
+        });
+        match outer {
+            Some(i) => polygons[i].interiors_push(hole),
+            None => polygons.push(Polygon::new(hole, vec![])),
+        }
+    }
+    MultiPolygon(polygons)
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+fn read_bytes<const N: usize>(buf: &[u8], pos: usize) -> io::Result<[u8; N]> {
+    buf.get(pos..pos + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid_data("unexpected end of data"))
+}
+
+fn le_u16(buf: &[u8], pos: usize) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(buf, pos)?))
+}
+
+fn le_u32(buf: &[u8], pos: usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(buf, pos)?))
+}
+
+fn le_i32(buf: &[u8], pos: usize) -> io::Result<i32> {
+    Ok(i32::from_le_bytes(read_bytes(buf, pos)?))
+}
+
+fn le_f64(buf: &[u8], pos: usize) -> io::Result<f64> {
+    Ok(f64::from_le_bytes(read_bytes(buf, pos)?))
+}
+
+fn to_usize(value: impl TryInto<usize>) -> io::Result<usize> {
+    value
+        .try_into()
+        .map_err(|_| invalid_data("invalid offset or count"))
+}
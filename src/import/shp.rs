@@ -0,0 +1,124 @@
+use super::{invalid_data, le_f64, le_i32, polygons_from_rings, read_bytes, to_usize};
+use geo_types::{Coord, LineString, MultiPolygon};
+use std::io;
+
+const FILE_CODE: i32 = 9994;
+const HEADER_LEN: usize = 100;
+const NULL_SHAPE: i32 = 0;
+// Polygon, PolygonZ and PolygonM share the 2d layout, z & m values are
+// appended after the points and ignored.
+const POLYGON_SHAPES: [i32; 3] = [5, 15, 25];
+
+fn be_i32(buf: &[u8], pos: usize) -> io::Result<i32> {
+    Ok(i32::from_be_bytes(read_bytes(buf, pos)?))
+}
+
+/// Parse the records of a `.shp` file, `None` for null shapes.
+pub fn parse(buf: &[u8]) -> io::Result<Vec<Option<MultiPolygon<f64>>>> {
+    if be_i32(buf, 0)? != FILE_CODE {
+        return Err(invalid_data("not a shapefile"));
+    }
+    let shape_type = le_i32(buf, 32)?;
+    if shape_type != NULL_SHAPE && !POLYGON_SHAPES.contains(&shape_type) {
+        let msg = format!("unsupported shape type {}, expected polygons", shape_type);
+        return Err(invalid_data(msg));
+    }
+
+    let mut shapes = vec![];
+    let mut pos = HEADER_LEN;
+    while pos < buf.len() {
+        // content length is given in 16-bit words
+        let content_len = to_usize(be_i32(buf, pos + 4)?)? * 2;
+        let content = buf
+            .get(pos + 8..pos + 8 + content_len)
+            .ok_or_else(|| invalid_data("truncated shapefile record"))?;
+        shapes.push(parse_polygon(content)?);
+        pos += 8 + content_len;
+    }
+    Ok(shapes)
+}
+
+fn parse_polygon(content: &[u8]) -> io::Result<Option<MultiPolygon<f64>>> {
+    let shape_type = le_i32(content, 0)?;
+    if shape_type == NULL_SHAPE {
+        return Ok(None);
+    }
+    if !POLYGON_SHAPES.contains(&shape_type) {
+        let msg = format!("unsupported shape type {} in record", shape_type);
+        return Err(invalid_data(msg));
+    }
+
+    // skip the bounding box (4 doubles)
+    let num_parts = to_usize(le_i32(content, 36)?)?;
+    let num_points = to_usize(le_i32(content, 40)?)?;
+    let parts = (0..num_parts)
+        .map(|i| to_usize(le_i32(content, 44 + 4 * i)?))
+        .collect::<io::Result<Vec<usize>>>()?;
+    let points_pos = 44 + 4 * num_parts;
+    let coords = (0..num_points)
+        .map(|i| {
+            let x = le_f64(content, points_pos + 16 * i)?;
+            let y = le_f64(content, points_pos + 16 * i + 8)?;
+            Ok(Coord { x, y })
+        })
+        .collect::<io::Result<Vec<Coord<f64>>>>()?;
+
+    let rings = parts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let end = parts.get(i + 1).copied().unwrap_or(num_points);
+            let ring = coords
+                .get(*start..end)
+                .ok_or_else(|| invalid_data("invalid ring offsets"))?;
+            Ok(LineString(ring.to_vec()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(Some(polygons_from_rings(rings)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(rings: &[&[(f64, f64)]]) -> Vec<u8> {
+        let num_points: usize = rings.iter().map(|ring| ring.len()).sum();
+        let mut content: Vec<u8> = vec![];
+        content.extend(&5i32.to_le_bytes());
+        content.extend(&[0u8; 32]);
+        content.extend(&(rings.len() as i32).to_le_bytes());
+        content.extend(&(num_points as i32).to_le_bytes());
+        let mut start = 0;
+        for ring in rings {
+            content.extend(&(start as i32).to_le_bytes());
+            start += ring.len();
+        }
+        for (x, y) in rings.iter().flat_map(|ring| ring.iter()) {
+            content.extend(&x.to_le_bytes());
+            content.extend(&y.to_le_bytes());
+        }
+        let mut record: Vec<u8> = vec![];
+        record.extend(&1i32.to_be_bytes());
+        record.extend(&((content.len() / 2) as i32).to_be_bytes());
+        record.extend(content);
+        record
+    }
+
+    #[test]
+    fn parses_polygon_with_hole() {
+        let outer = [(0., 0.), (0., 4.), (4., 4.), (4., 0.), (0., 0.)];
+        let hole = [(1., 1.), (2., 1.), (2., 2.), (1., 2.), (1., 1.)];
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf[0..4].copy_from_slice(&FILE_CODE.to_be_bytes());
+        buf[32..36].copy_from_slice(&5i32.to_le_bytes());
+        buf.extend(record(&[&outer, &hole]));
+        buf.extend(record(&[&outer]));
+
+        let shapes = parse(&buf).unwrap();
+        assert_eq!(shapes.len(), 2);
+        let mp = shapes[0].as_ref().unwrap();
+        assert_eq!(mp.0.len(), 1);
+        assert_eq!(mp.0[0].interiors().len(), 1);
+        assert_eq!(mp.0[0].exterior().0.len(), 5);
+    }
+}
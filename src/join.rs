@@ -0,0 +1,235 @@
+use osm_admin_lookup::boundary::Boundary;
+use osm_admin_lookup::{load_index, load_tree, Tree};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "join",
+    about = "crosswalk of overlapping boundaries of two rtree bins"
+)]
+struct Opt {
+    /// rtree bin whose boundaries are mapped
+    #[structopt(long = "left")]
+    left: PathBuf,
+
+    /// rtree bin whose boundaries are mapped to
+    #[structopt(long = "right")]
+    right: PathBuf,
+
+    /// limit boundaries of the left bin to admin levels, repeat for several
+    #[structopt(long = "left-level")]
+    left_level: Option<Vec<u8>>,
+
+    /// limit boundaries of the right bin to admin levels, repeat for several
+    #[structopt(long = "right-level")]
+    right_level: Option<Vec<u8>>,
+
+    /// drop pairs sharing less than this fraction of the left boundary,
+    /// e.g. slivers of borders mapped differently
+    #[structopt(long = "min-fraction", default_value = "0")]
+    min_fraction: f64,
+
+    /// output format, csv or json lines
+    #[structopt(long = "format", default_value = "csv")]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}, expected json or csv", s)),
+        }
+    }
+}
+
+/// A pair of overlapping boundaries of the left and right bin.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Pair {
+    pub left_id: Option<i64>,
+    pub left_name: String,
+    pub left_level: u8,
+    pub right_id: Option<i64>,
+    pub right_name: String,
+    pub right_level: u8,
+    /// geodesic area of the intersection in km²
+    pub area_km2: f64,
+    /// share of the left boundary's area
+    pub left_fraction: f64,
+    /// share of the right boundary's area
+    pub right_fraction: f64,
+}
+
+fn in_levels(levels: Option<&[u8]>, boundary: &Boundary) -> bool {
+    levels.is_none_or(|levels| levels.contains(&boundary.admin_level))
+}
+
+/// Boundaries of `right` overlapping `left`, largest share first.
+fn pairs(
+    left: &Boundary,
+    right: &Tree,
+    levels: Option<&[u8]>,
+    min_fraction: f64,
+) -> Result<Vec<Pair>, io::Error> {
+    let mut pairs: Vec<Pair> = right.overlaps(&left.mp, |overlaps| {
+        overlaps
+            .into_iter()
+            .filter(|overlap| {
+                in_levels(levels, overlap.boundary) && overlap.fraction >= min_fraction
+            })
+            .map(|overlap| Pair {
+                left_id: left.osm_id,
+                left_name: left.name.clone(),
+                left_level: left.admin_level,
+                right_id: overlap.boundary.osm_id,
+                right_name: overlap.boundary.name.clone(),
+                right_level: overlap.boundary.admin_level,
+                area_km2: overlap.area,
+                left_fraction: overlap.fraction,
+                right_fraction: match overlap.boundary.area > 0. {
+                    true => (overlap.area / overlap.boundary.area).min(1.),
+                    false => 0.,
+                },
+            })
+            .collect()
+    })?;
+    pairs.sort_by(|a, b| {
+        a.right_level
+            .cmp(&b.right_level)
+            .then(b.left_fraction.total_cmp(&a.left_fraction))
+    });
+    Ok(pairs)
+}
+
+/// Crosswalk of all boundaries of `left` to those of `right`, ordered by
+/// level and name of the left boundaries.
+fn crosswalk(left: &[&Boundary], right: &Tree, opt: &Opt) -> Result<Vec<Pair>, io::Error> {
+    let mut left: Vec<&Boundary> = left
+        .iter()
+        .copied()
+        .filter(|boundary| in_levels(opt.left_level.as_deref(), boundary))
+        .collect();
+    left.sort_by(|a, b| {
+        (a.admin_level, &a.name, a.osm_id).cmp(&(b.admin_level, &b.name, b.osm_id))
+    });
+    let pairs = left
+        .par_iter()
+        .map(|boundary| {
+            pairs(
+                boundary,
+                right,
+                opt.right_level.as_deref(),
+                opt.min_fraction,
+            )
+        })
+        .collect::<Result<Vec<Vec<Pair>>, io::Error>>()?;
+    Ok(pairs.into_iter().flatten().collect())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let left = load_index(&opt.left)?.tree;
+    let right = load_tree(&opt.right)?;
+    let boundaries: Vec<&Boundary> = left.iter().collect();
+    let pairs = crosswalk(&boundaries, &right, &opt)?;
+
+    let mut out = BufWriter::new(io::stdout());
+    match opt.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            for pair in &pairs {
+                writer.serialize(pair)?;
+            }
+            writer.flush()?;
+        }
+        Format::Json => {
+            for pair in &pairs {
+                writeln!(out, "{}", serde_json::to_string(pair)?)?;
+            }
+        }
+    }
+    out.flush()?;
+    eprintln!(
+        "left boundaries: {}, pairs: {}",
+        boundaries.len(),
+        pairs.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use osm_admin_lookup::RTree;
+
+    fn rect(min_x: f64, max_x: f64, name: &str, level: u8) -> Boundary {
+        let polygon = polygon![
+            (x: min_x, y: 0.),
+            (x: max_x, y: 0.),
+            (x: max_x, y: 1.),
+            (x: min_x, y: 1.),
+        ];
+        Boundary::new(polygon.into(), name, level)
+    }
+
+    #[test]
+    fn maps_boundaries_with_area_shares() {
+        let left = [rect(0., 1., "postcode", 8), rect(5., 6., "remote", 8)];
+        let right = Tree::from(RTree::bulk_load(vec![
+            rect(0., 0.25, "west", 8),
+            rect(0.25, 2., "east", 8),
+            rect(-1., 3., "county", 6),
+        ]));
+        let opt = Opt::from_iter(["join", "--left", "l.bin", "--right", "r.bin"]);
+        let left: Vec<&Boundary> = left.iter().collect();
+        let pairs = crosswalk(&left, &right, &opt).unwrap();
+        let shares: Vec<(&str, f64, f64)> = pairs
+            .iter()
+            .map(|p| {
+                let round = |f: f64| (f * 100.).round() / 100.;
+                let (left, right) = (round(p.left_fraction), round(p.right_fraction));
+                (p.right_name.as_str(), left, right)
+            })
+            .collect();
+        assert_eq!(
+            shares,
+            [
+                ("county", 1., 0.25),
+                ("east", 0.75, 0.43),
+                ("west", 0.25, 1.),
+            ]
+        );
+        assert!(pairs.iter().all(|p| p.left_name == "postcode"));
+
+        let opt = Opt::from_iter([
+            "join",
+            "--left",
+            "l.bin",
+            "--right",
+            "r.bin",
+            "--right-level",
+            "8",
+            "--min-fraction",
+            "0.5",
+        ]);
+        let pairs = crosswalk(&left, &right, &opt).unwrap();
+        let names: Vec<&str> = pairs.iter().map(|p| p.right_name.as_str()).collect();
+        assert_eq!(names, ["east"]);
+    }
+}
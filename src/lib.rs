@@ -1,17 +1,223 @@
-use boundary::get_osm_boundaries;
-use boundary::Boundary;
+use boundary::{get_osm_boundaries, Boundary, BuildReport, OsmMap};
+use geo_types::{Geometry, LineString, MultiPolygon, Rect};
 use location::Location;
+use query::{geometry_envelope, Mode, Overlap};
+use route::LevelCrossings;
+use rstar::{RTreeObject, AABB};
+use serde::{Deserialize, Serialize};
+use shard::index::{is_sharded, ShardedTree};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub mod boundary;
+pub mod geojson;
+pub mod import;
 pub mod location;
+pub mod nodes;
+pub mod query;
+pub mod route;
 pub mod service;
+pub mod shard;
+pub mod simplify;
+pub mod update;
+pub mod validation;
 
 pub type RTree = rstar::RTree<Boundary>;
 
+/// Build parameters stored alongside the tree.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Metadata {
+    /// admin levels the tree was built for
+    pub admin_levels: Vec<u8>,
+    /// whether osm geometries were repaired
+    pub validate: bool,
+    /// simplification tolerance in metres, if geometries were simplified
+    pub simplify_tolerance: Option<f64>,
+}
+
+/// Contents of an rtree bin file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Index {
+    pub metadata: Metadata,
+    pub tree: RTree,
+    /// osm objects the boundaries were assembled from, kept for updates
+    pub osm: Option<OsmMap>,
+}
+
+/// Leading bytes of rtree binaries, followed by the format version.
+pub const MAGIC: &[u8; 8] = b"OALINDEX";
+
+/// Version of the binary layout, to be increased whenever `Index`,
+/// `Boundary` or the shard manifest change.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Shards kept in memory by `load_tree` for sharded binaries.
+pub const DEFAULT_MAX_SHARDS: usize = 32;
+
+/// An rtree with the envelopes of its boundaries by osm id, so boundaries
+/// are found by id without scanning the tree.
+pub struct IdTree {
+    tree: RTree,
+    envelopes: HashMap<i64, AABB<[f64; 2]>>,
+}
+
+impl From<RTree> for IdTree {
+    fn from(tree: RTree) -> Self {
+        let envelopes = tree
+            .iter()
+            .filter_map(|boundary| Some((boundary.osm_id?, boundary.envelope())))
+            .collect();
+        IdTree { tree, envelopes }
+    }
+}
+
+impl Deref for IdTree {
+    type Target = RTree;
+
+    fn deref(&self) -> &RTree {
+        &self.tree
+    }
+}
+
+impl IdTree {
+    /// The boundary of the osm relation `id`, if there is one.
+    pub fn find(&self, id: i64) -> Option<&Boundary> {
+        let envelope = self.envelopes.get(&id)?;
+        self.tree
+            .locate_in_envelope(envelope)
+            .find(|boundary| boundary.osm_id == Some(id))
+    }
+}
+
+/// An rtree binary loaded for lookups. Sharded binaries are loaded shard by
+/// shard, when a query first touches them.
+pub enum Tree {
+    Loaded {
+        metadata: Metadata,
+        tree: Arc<IdTree>,
+    },
+    Sharded(ShardedTree),
+}
+
+impl From<RTree> for Tree {
+    fn from(tree: RTree) -> Self {
+        Tree::Loaded {
+            metadata: Metadata::default(),
+            tree: Arc::new(tree.into()),
+        }
+    }
+}
+
+impl Tree {
+    pub fn metadata(&self) -> &Metadata {
+        match self {
+            Tree::Loaded { metadata, .. } => metadata,
+            Tree::Sharded(sharded) => &sharded.manifest.metadata,
+        }
+    }
+
+    /// Trees holding all boundaries intersecting `envelope`.
+    pub fn trees(&self, envelope: &AABB<[f64; 2]>) -> Result<Vec<Arc<IdTree>>, std::io::Error> {
+        match self {
+            Tree::Loaded { tree, .. } => Ok(vec![tree.clone()]),
+            Tree::Sharded(sharded) => sharded.trees(envelope),
+        }
+    }
+
+    /// Pass the boundaries containing `point` to `f`, they are borrowed from
+    /// the shards, which might be dropped from memory afterwards.
+    pub fn locate<T>(
+        &self,
+        point: &[f64; 2],
+        f: impl FnOnce(Vec<&Boundary>) -> T,
+    ) -> Result<T, std::io::Error> {
+        let trees = self.trees(&AABB::from_point(*point))?;
+        let boundaries = trees
+            .iter()
+            .flat_map(|tree| tree.locate_all_at_point(point))
+            .filter(|boundary| boundary.contains(point))
+            .collect();
+        Ok(f(boundaries))
+    }
+
+    /// Pass the boundaries relating to `geometry` as given by `mode` to `f`.
+    pub fn boundaries_intersecting<T>(
+        &self,
+        geometry: &Geometry<f64>,
+        mode: Mode,
+        f: impl FnOnce(Vec<&Boundary>) -> T,
+    ) -> Result<T, std::io::Error> {
+        let trees = match geometry_envelope(geometry) {
+            Some(envelope) => self.trees(&envelope)?,
+            None => vec![],
+        };
+        let boundaries = trees
+            .iter()
+            .flat_map(|tree| query::boundaries_intersecting(tree, geometry, mode))
+            .collect();
+        Ok(f(boundaries))
+    }
+
+    /// Pass the boundaries relating to `bbox` as given by `mode` to `f`.
+    pub fn boundaries_in_bbox<T>(
+        &self,
+        bbox: Rect<f64>,
+        mode: Mode,
+        f: impl FnOnce(Vec<&Boundary>) -> T,
+    ) -> Result<T, std::io::Error> {
+        self.boundaries_intersecting(&bbox.to_polygon().into(), mode, f)
+    }
+
+    /// Pass the boundaries intersecting `mp` to `f`, with the area of the
+    /// intersection and its share of the area of `mp`.
+    pub fn overlaps<T>(
+        &self,
+        mp: &MultiPolygon<f64>,
+        f: impl FnOnce(Vec<Overlap>) -> T,
+    ) -> Result<T, std::io::Error> {
+        let trees = match geometry_envelope(&mp.clone().into()) {
+            Some(envelope) => self.trees(&envelope)?,
+            None => vec![],
+        };
+        let overlaps = trees
+            .iter()
+            .flat_map(|tree| query::overlaps(tree, mp))
+            .collect();
+        Ok(f(overlaps))
+    }
+
+    /// Pass the boundaries `line` passes through to `f`, grouped by admin
+    /// level and ordered along the route.
+    pub fn crossings<T>(
+        &self,
+        line: &LineString<f64>,
+        f: impl FnOnce(Vec<LevelCrossings>) -> T,
+    ) -> Result<T, std::io::Error> {
+        let geometry = line.clone().into();
+        self.boundaries_intersecting(&geometry, Mode::Intersects, |boundaries| {
+            f(route::crossings(boundaries, line))
+        })
+    }
+
+    /// Pass the boundary of the osm relation `id` to `f`, if there is one.
+    pub fn find<T>(
+        &self,
+        id: i64,
+        f: impl FnOnce(Option<&Boundary>) -> T,
+    ) -> Result<T, std::io::Error> {
+        let tree = match self {
+            Tree::Loaded { tree, .. } => Some(tree.clone()),
+            Tree::Sharded(sharded) => sharded.find(id)?,
+        };
+        Ok(f(tree.as_ref().and_then(|tree| tree.find(id))))
+    }
+}
+
 pub fn boundaries<'b>(loc: &Location, tree: &'b RTree) -> Vec<&'b Boundary> {
     let point = loc.clone().into();
     let candidates: Vec<&Boundary> = tree
@@ -22,17 +228,139 @@ pub fn boundaries<'b>(loc: &Location, tree: &'b RTree) -> Vec<&'b Boundary> {
 }
 
 pub fn build_rtree(path: PathBuf, admin_levels: &[u8]) -> Result<RTree, Box<dyn Error>> {
-    let boundaries = get_osm_boundaries(path, admin_levels)?;
+    let boundaries = get_osm_boundaries(path, admin_levels, false, &mut BuildReport::default())?;
     Ok(rstar::RTree::<Boundary>::bulk_load(boundaries))
 }
 
-pub fn load_tree(path: &PathBuf) -> Result<RTree, std::io::Error> {
-    let file = File::open(path)?;
-    let tree: RTree = bincode::deserialize_from(file).map_err(|e| {
+/// Load a binary completely, the shards of sharded binaries are merged
+/// into a single tree.
+pub fn load_index(path: &PathBuf) -> Result<Index, std::io::Error> {
+    if is_sharded(path)? {
+        let sharded = ShardedTree::open(path, 1)?;
+        return Ok(Index {
+            metadata: sharded.manifest.metadata.clone(),
+            tree: sharded.load_all()?,
+            osm: None,
+        });
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    read_header(&mut file, MAGIC)?;
+    let index: Index = bincode::deserialize_from(file).map_err(|e| {
         std::io::Error::new(
             ErrorKind::InvalidData,
             format!("could not deserialize rtree binary: {}", e),
         )
     })?;
-    Ok(tree)
+    Ok(index)
+}
+
+/// Load a binary for lookups, of sharded binaries only the manifest is
+/// loaded upfront.
+pub fn load_tree(path: &PathBuf) -> Result<Tree, std::io::Error> {
+    open_tree(path, DEFAULT_MAX_SHARDS)
+}
+
+/// Like `load_tree`, keeping at most `max_shards` shards in memory.
+pub fn open_tree(path: &PathBuf, max_shards: usize) -> Result<Tree, std::io::Error> {
+    if is_sharded(path)? {
+        return Ok(Tree::Sharded(ShardedTree::open(path, max_shards)?));
+    }
+    let index = load_index(path)?;
+    Ok(Tree::Loaded {
+        metadata: index.metadata,
+        tree: Arc::new(index.tree.into()),
+    })
+}
+
+pub fn write_index(path: &Path, index: &Index) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_header(&mut file, MAGIC)?;
+    bincode::serialize_into(&mut file, index)?;
+    file.flush()?;
+    Ok(())
+}
+
+pub(crate) fn write_header(mut writer: impl Write, magic: &[u8; 8]) -> io::Result<()> {
+    writer.write_all(magic)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+/// Check the magic bytes and the format version at the start of a binary.
+pub(crate) fn read_header(mut reader: impl Read, magic: &[u8; 8]) -> io::Result<()> {
+    let mut header = [0; 12];
+    let read = reader.read_exact(&mut header);
+    if read.is_err() || header[..8] != magic[..] {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "rtree binary has no format version, it was written by an older version: \
+             rebuild it with build-rtree",
+        ));
+    }
+    let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "rtree binary has format version {}, expected {}: rebuild it with build-rtree",
+                version, FORMAT_VERSION
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> Index {
+        Index {
+            metadata: Metadata::default(),
+            tree: RTree::new(),
+            osm: None,
+        }
+    }
+
+    #[test]
+    fn checks_format_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        write_index(&path, &index()).unwrap();
+        assert_eq!(load_index(&path).unwrap().tree.size(), 0);
+
+        bincode::serialize_into(File::create(&path).unwrap(), &index()).unwrap();
+        let error = load_index(&path).unwrap_err();
+        assert!(error.to_string().contains("no format version"));
+
+        let mut file = File::create(&path).unwrap();
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&(FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        let error = load_index(&path).unwrap_err();
+        assert!(error.to_string().contains("format version 5, expected 4"));
+    }
+
+    #[test]
+    fn stores_pole_in_index() {
+        use geo_types::polygon;
+
+        let l_shape = polygon![
+            (x: 0., y: 0.),
+            (x: 10., y: 0.),
+            (x: 10., y: 2.),
+            (x: 2., y: 2.),
+            (x: 2., y: 10.),
+            (x: 0., y: 10.),
+        ];
+        let boundary = Boundary::new(MultiPolygon(vec![l_shape]), "l", 4);
+        assert_ne!(boundary.pole, boundary.centroid);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        let mut index = index();
+        index.tree = RTree::bulk_load(vec![boundary.clone()]);
+        write_index(&path, &index).unwrap();
+        let loaded = load_index(&path).unwrap();
+        let stored = loaded.tree.iter().next().unwrap();
+        assert_eq!(stored.pole, boundary.pole);
+    }
 }
@@ -1,16 +1,18 @@
-use crate::geojson::write_geojson;
-use boundary::Boundary;
-use location::Location;
-use rayon::prelude::*;
-use rstar::RTree;
+use geo_types::Point;
+use osm_admin_lookup::boundary::Boundary;
+use osm_admin_lookup::geojson::write_geojson;
+use osm_admin_lookup::location::{AxisOrder, Location};
+use osm_admin_lookup::service::BoundaryDetails;
+use osm_admin_lookup::{load_tree, Tree};
+use serde::Serialize;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
-
-pub mod boundary;
-pub mod geojson;
-pub mod location;
+use wkt::ToWkt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "locate", about = "locate in rtree")]
@@ -19,36 +21,368 @@ struct Opt {
     #[structopt(short = "b", long = "bin")]
     bin_path: PathBuf,
 
-    /// location (lng,lat)
-    #[structopt(short = "l", long = "loc")]
-    loc: Location,
+    /// location, e.g. `13.405,52.52`, `52°31'12"N 13°24'18"E`, a geohash,
+    /// a geo: uri or a wkt point, repeat for several
+    #[structopt(
+        short = "l",
+        long = "loc",
+        number_of_values = 1,
+        allow_hyphen_values = true
+    )]
+    loc: Vec<String>,
+
+    /// file with a location per line, empty lines and lines starting with
+    /// `#` are skipped
+    #[structopt(long = "file")]
+    file: Option<PathBuf>,
+
+    /// read decimal pairs and degrees without hemispheres as lat,lng
+    #[structopt(long = "lat-lng")]
+    lat_lng: bool,
 
-    /// output geojson path
+    /// answer locations typed on stdin until `quit` or end of input, the
+    /// tree is loaded once
+    #[structopt(short = "i", long = "interactive")]
+    interactive: bool,
+
+    /// output geojson path, `-` for stdout
     #[structopt(short = "g", long = "geojson")]
     geojson_path: Option<PathBuf>,
+
+    /// output format: text, json, geojson or wkt
+    #[structopt(long = "format", default_value = "text")]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Geojson,
+    Wkt,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "geojson" => Ok(Format::Geojson),
+            "wkt" => Ok(Format::Wkt),
+            _ => Err(format!(
+                "unknown format {}, expected text, json, geojson or wkt",
+                s
+            )),
+        }
+    }
+}
+
+fn write_text(mut out: impl Write, boundaries: &[&Boundary]) -> io::Result<()> {
+    for boundary in boundaries {
+        writeln!(
+            out,
+            "boundary: {}, level: {}, area: {:.2} km², centroid: {:.5},{:.5}",
+            boundary.name,
+            boundary.admin_level,
+            boundary.area,
+            boundary.centroid.x(),
+            boundary.centroid.y(),
+        )?;
+        let members = [
+            ("admin centre", &boundary.admin_centre),
+            ("label", &boundary.label),
+        ];
+        for (role, member) in members {
+            if let Some(member) = member {
+                writeln!(
+                    out,
+                    "  {}: {}, at {:.5},{:.5}",
+                    role,
+                    member.name.as_deref().unwrap_or("-"),
+                    member.point.x(),
+                    member.point.y(),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Header of wkt output, written once per run.
+const WKT_HEADER: &str = "loc\tid\tlevel\tname\twkt";
+
+/// Tab separated location, id, level, name and geometry of each boundary.
+fn write_wkt(mut out: impl Write, loc: &Location, boundaries: &[&Boundary]) -> io::Result<()> {
+    for boundary in boundaries {
+        let id = boundary.osm_id.map(|id| id.to_string());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            loc,
+            id.unwrap_or_default(),
+            boundary.admin_level,
+            boundary.name,
+            boundary.mp.wkt_string(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Stdout, or a file created at `path` unless it is `-`.
+fn output(path: Option<&Path>) -> io::Result<Box<dyn Write>> {
+    match path {
+        Some(path) if path != Path::new("-") => Ok(Box::new(BufWriter::new(File::create(path)?))),
+        _ => Ok(Box::new(BufWriter::new(io::stdout()))),
+    }
+}
+
+/// Boundaries of a location in json output.
+#[derive(Serialize)]
+struct LocationResult {
+    loc: [f64; 2],
+    boundaries: Vec<BoundaryDetails>,
+}
+
+/// Read locations of a file, one per line.
+fn read_locations(path: &Path, order: AxisOrder) -> Result<Vec<Location>, Box<dyn Error>> {
+    let mut locations = vec![];
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let loc = Location::parse(line, order)
+            .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        locations.push(loc);
+    }
+    Ok(locations)
+}
+
+/// Write the boundaries of `locations`. Geojson is written as a single
+/// feature collection, with each boundary once and a point per location.
+fn write_locations(
+    tree: &Tree,
+    locations: &[Location],
+    format: Format,
+    out: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Geojson => {
+            // boundaries are cloned, shards holding them might be dropped
+            let mut ids = HashSet::new();
+            let mut found = vec![];
+            let mut points = vec![];
+            for loc in locations {
+                let point: [f64; 2] = loc.clone().into();
+                tree.locate(&point, |boundaries| {
+                    let unseen = boundaries
+                        .into_iter()
+                        .filter(|b| b.osm_id.is_none_or(|id| ids.insert(id)));
+                    found.extend(unseen.cloned());
+                })?;
+                points.push(Point::from(point));
+            }
+            write_geojson(&mut *out, found.iter().collect(), &points)?;
+            writeln!(out)?;
+        }
+        Format::Wkt => {
+            for loc in locations {
+                let point: [f64; 2] = loc.clone().into();
+                tree.locate(&point, |boundaries| write_wkt(&mut *out, loc, &boundaries))??;
+            }
+        }
+        Format::Json => {
+            for loc in locations {
+                let point: [f64; 2] = loc.clone().into();
+                let boundaries = tree.locate(&point, |boundaries| {
+                    boundaries.into_iter().map(BoundaryDetails::from).collect()
+                })?;
+                let result = LocationResult {
+                    loc: point,
+                    boundaries,
+                };
+                writeln!(out, "{}", serde_json::to_string(&result)?)?;
+            }
+        }
+        Format::Text => {
+            for loc in locations {
+                if locations.len() > 1 {
+                    writeln!(out, "location: {}", loc)?;
+                }
+                let point: [f64; 2] = loc.clone().into();
+                tree.locate(&point, |boundaries| write_text(&mut *out, &boundaries))??;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answer locations read from `input` line by line, e.g. typed on stdin,
+/// the prompt goes to stderr.
+fn interactive(
+    tree: &Tree,
+    format: Format,
+    order: AxisOrder,
+    input: impl BufRead,
+    out: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    eprint!("> ");
+    for line in input.lines() {
+        let line = line?;
+        match line.trim() {
+            "" => {}
+            "quit" | "exit" => break,
+            line => match Location::parse(line, order) {
+                Ok(loc) => {
+                    write_locations(tree, &[loc], format, out)?;
+                    out.flush()?;
+                }
+                Err(e) => eprintln!("{}", e),
+            },
+        }
+        eprint!("> ");
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
-    let file = File::open(opt.bin_path)?;
-    let tree: RTree<Boundary> = bincode::deserialize_from(file)?;
-    let point: &[f64; 2] = &opt.loc.into();
-    let candidates: Vec<&Boundary> = tree.locate_all_at_point(point).collect();
-    let boundaries = candidates
-        .into_par_iter()
-        .filter(|boundary| boundary.contains(point))
-        .collect();
-
-    match opt.geojson_path {
-        Some(path) => write_geojson(File::open(path)?, boundaries)?,
-        None => {
-            for boundary in &boundaries {
-                println!(
-                    "boundary: {}, level: {}",
-                    boundary.name, boundary.admin_level
-                );
-            }
+    let format = match (&opt.geojson_path, opt.format) {
+        (None, format) => format,
+        (Some(_), _) if opt.interactive => {
+            return Err("-g can't be combined with --interactive".into())
         }
+        (Some(_), Format::Text | Format::Geojson) => Format::Geojson,
+        (Some(_), format) => {
+            return Err(format!("-g writes geojson, it can't be combined with {:?}", format).into())
+        }
+    };
+    let order = match opt.lat_lng {
+        true => AxisOrder::LatLng,
+        false => AxisOrder::LngLat,
+    };
+    let mut locations = opt
+        .loc
+        .iter()
+        .map(|loc| Location::parse(loc, order))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(path) = &opt.file {
+        locations.extend(read_locations(path, order)?);
+    }
+    if locations.is_empty() && !opt.interactive {
+        return Err("pass locations with -l or --file, or use --interactive".into());
+    }
+    let tree = load_tree(&opt.bin_path)?;
+    let mut out = output(opt.geojson_path.as_deref())?;
+    if format == Format::Wkt {
+        writeln!(out, "{}", WKT_HEADER)?;
+    }
+    if !locations.is_empty() {
+        write_locations(&tree, &locations, format, &mut out)?;
+    }
+    out.flush()?;
+    if opt.interactive {
+        interactive(&tree, format, order, io::stdin().lock(), &mut out)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use osm_admin_lookup::RTree;
+
+    fn square(x: f64, name: &str, id: i64) -> Boundary {
+        let polygon = polygon![
+            (x: x, y: 0.),
+            (x: x + 1., y: 0.),
+            (x: x + 1., y: 1.),
+            (x: x, y: 1.),
+        ];
+        let mut boundary = Boundary::new(polygon.into(), name, 8);
+        boundary.osm_id = Some(id);
+        boundary
+    }
+
+    fn tree() -> Tree {
+        Tree::from(RTree::bulk_load(vec![
+            square(0., "west", 1),
+            square(2., "east", 2),
+        ]))
+    }
+
+    fn locate(locations: &[&str], format: Format) -> String {
+        let tree = tree();
+        let locations: Vec<Location> = locations.iter().map(|loc| loc.parse().unwrap()).collect();
+        let mut out = vec![];
+        write_locations(&tree, &locations, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_several_locations() {
+        let text = locate(&["0.5,0.5", "2.5,0.5", "5,5"], Format::Text);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "location: 0.5,0.5");
+        assert!(lines[1].starts_with("boundary: west"));
+        assert_eq!(lines[4], "location: 5,5");
+
+        let json = locate(&["0.5,0.5", "5,5"], Format::Json);
+        assert_eq!(json.lines().count(), 2);
+
+        let geojson = locate(&["0.5,0.5", "0.6,0.6", "2.5,0.5"], Format::Geojson);
+        let collection: geojson::FeatureCollection = geojson.trim().parse().unwrap();
+        let names: Vec<_> = collection
+            .features
+            .iter()
+            .filter_map(|f| f.property("name"))
+            .collect();
+        assert_eq!(names, ["west", "east"]);
+        assert_eq!(collection.features.len(), 5);
+    }
+
+    #[test]
+    fn answers_interactive_queries() {
+        let input = "0.5,0.5\nnowhere\n\n2.5,0.5\nquit\n0.6,0.6\n";
+        let mut out = vec![];
+        interactive(
+            &tree(),
+            Format::Wkt,
+            AxisOrder::LngLat,
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let names: Vec<&str> = out.lines().map(|l| l.split('\t').nth(3).unwrap()).collect();
+        assert_eq!(names, ["west", "east"]);
+        assert!(!out.contains(WKT_HEADER));
+    }
+
+    #[test]
+    fn reads_location_files() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "# stores\n53.1,8.8\n\n 52°31'N 13°24'E \ngeo:52.5,13.4"
+        )
+        .unwrap();
+        let locations = read_locations(file.path(), AxisOrder::LatLng).unwrap();
+        let locations: Vec<String> = locations.iter().map(Location::to_string).collect();
+        assert_eq!(
+            locations,
+            ["8.8,53.1", "13.4,52.516666666666666", "13.4,52.5"]
+        );
+
+        writeln!(file, "13.4").unwrap();
+        let error = read_locations(file.path(), AxisOrder::LatLng)
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with(":6: invalid location 13.4"), "{}", error);
+    }
+}
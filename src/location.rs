@@ -2,9 +2,7 @@ use serde::de::Error as SerdeError;
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::{TryFrom, TryInto};
-// use std::error::Error;
 use std::fmt;
-use std::num::ParseFloatError;
 use std::str::FromStr;
 
 #[derive(Clone, Debug)]
@@ -25,22 +23,325 @@ impl Location {
 
         Ok(Location { lng, lat })
     }
+
+    /// Parse a location given as
+    ///
+    /// - two decimals separated by a comma or whitespace, in `order`
+    /// - degrees, minutes and seconds, e.g. `52°31'12"N 13°24'18"E`, in
+    ///   `order` unless hemispheres are given
+    /// - a geohash, e.g. `u33dc0`, yielding the center of its cell
+    /// - a `geo:` uri, e.g. `geo:52.52,13.405`, always lat,lng
+    /// - a wkt point, e.g. `POINT(13.405 52.52)`, always lng,lat
+    pub fn parse(s: &str, order: AxisOrder) -> Result<Location, String> {
+        let s = s.trim();
+        let [lng, lat] = if let Some(uri) = strip_prefix_ignore_case(s, "geo:") {
+            parse_geo_uri(uri)?
+        } else if let Some(point) = strip_prefix_ignore_case(s, "point") {
+            parse_wkt_point(point)?
+        } else if let Some(pair) = parse_pair(s) {
+            order.lng_lat(pair)
+        } else if is_geohash(s) {
+            decode_geohash(s)
+        } else {
+            parse_dms(s, order)?
+        };
+        Location::new(lng, lat).map_err(String::from)
+    }
+}
+
+/// Order of the numbers of a coordinate pair without explicit axes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AxisOrder {
+    #[default]
+    LngLat,
+    LatLng,
+}
+
+impl AxisOrder {
+    fn lng_lat(self, [a, b]: [f64; 2]) -> [f64; 2] {
+        match self {
+            AxisOrder::LngLat => [a, b],
+            AxisOrder::LatLng => [b, a],
+        }
+    }
+}
+
+impl FromStr for AxisOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lnglat" | "lng,lat" => Ok(AxisOrder::LngLat),
+            "latlng" | "lat,lng" => Ok(AxisOrder::LatLng),
+            _ => Err(format!("unknown order {}, expected lnglat or latlng", s)),
+        }
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+/// Two decimals separated by a comma and/or whitespace.
+fn parse_pair(s: &str) -> Option<[f64; 2]> {
+    let parts: Vec<&str> = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect();
+    match parts[..] {
+        [a, b] if s.matches(',').count() <= 1 => Some([a.parse().ok()?, b.parse().ok()?]),
+        _ => None,
+    }
+}
+
+/// `geo:lat,lng[,alt][;params]` as of RFC 5870.
+fn parse_geo_uri(uri: &str) -> Result<[f64; 2], String> {
+    let invalid = || format!("invalid geo uri geo:{}", uri);
+    let mut parts = uri.split(';');
+    let coords: Vec<f64> = parts
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let crs = parts.find_map(|param| strip_prefix_ignore_case(param.trim(), "crs="));
+    if crs.is_some_and(|crs| !crs.eq_ignore_ascii_case("wgs84")) {
+        return Err(format!("unsupported crs in geo uri geo:{}", uri));
+    }
+    match coords[..] {
+        [lat, lng] | [lat, lng, _] => Ok([lng, lat]),
+        _ => Err(invalid()),
+    }
+}
+
+/// The rest of `POINT(lng lat)` or `POINT Z (lng lat alt)`.
+fn parse_wkt_point(point: &str) -> Result<[f64; 2], String> {
+    let invalid = || format!("invalid wkt POINT{}", point);
+    let point = point.trim();
+    let point = strip_prefix_ignore_case(point, "zm")
+        .or_else(|| strip_prefix_ignore_case(point, "z"))
+        .or_else(|| strip_prefix_ignore_case(point, "m"))
+        .unwrap_or(point)
+        .trim();
+    let coords = point
+        .strip_prefix('(')
+        .and_then(|point| point.strip_suffix(')'))
+        .ok_or_else(invalid)?;
+    let coords: Vec<f64> = coords
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    match coords[..] {
+        [lng, lat, ..] if coords.len() <= 4 => Ok([lng, lat]),
+        _ => Err(invalid()),
+    }
+}
+
+const GEOHASH_ALPHABET: &str = "0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Digits are valid geohash characters, but a bare number is more likely a
+/// pair missing its separator, so a geohash needs a letter. So are the
+/// hemispheres, numbers each followed by one are read as degrees instead.
+fn is_geohash(s: &str) -> bool {
+    (1..=12).contains(&s.len())
+        && s.chars().any(|c| c.is_ascii_alphabetic())
+        && !is_compact_dms(s)
+        && s.chars()
+            .all(|c| GEOHASH_ALPHABET.contains(c.to_ascii_lowercase()))
+}
+
+/// Numbers each followed by a hemisphere, e.g. `52N13E`.
+fn is_compact_dms(s: &str) -> bool {
+    let mut number = false;
+    for c in s.chars() {
+        match c.to_ascii_uppercase() {
+            '0'..='9' | '.' => number = true,
+            'N' | 'S' | 'E' | 'W' if number => number = false,
+            _ => return false,
+        }
+    }
+    !number
+}
+
+/// Center of the cell of a geohash, whose characters have been checked by
+/// `is_geohash`.
+fn decode_geohash(hash: &str) -> [f64; 2] {
+    let mut lng = (-180., 180.);
+    let mut lat = (-90., 90.);
+    let mut even = true;
+    for c in hash.chars() {
+        let index = GEOHASH_ALPHABET
+            .find(c.to_ascii_lowercase())
+            .unwrap_or_default();
+        for bit in (0..5).rev() {
+            let range = if even { &mut lng } else { &mut lat };
+            let middle = (range.0 + range.1) / 2.;
+            match (index >> bit) & 1 {
+                1 => range.0 = middle,
+                _ => range.1 = middle,
+            }
+            even = !even;
+        }
+    }
+    [(lng.0 + lng.1) / 2., (lat.0 + lat.1) / 2.]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(f64),
+    Degrees,
+    Minutes,
+    Seconds,
+    Hemisphere(char),
+    Separator,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '0'..='9' | '.' | '-' | '+' => {
+                let mut number = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                let value = number
+                    .parse()
+                    .map_err(|_| format!("invalid number {}", number))?;
+                Token::Number(value)
+            }
+            '°' | 'º' => Token::Degrees,
+            '\'' | '′' | '’' => match chars.next_if_eq(&'\'') {
+                Some(_) => Token::Seconds,
+                None => Token::Minutes,
+            },
+            '"' | '″' | '”' => Token::Seconds,
+            'N' | 'S' | 'E' | 'W' | 'n' | 's' | 'e' | 'w' => {
+                Token::Hemisphere(c.to_ascii_uppercase())
+            }
+            ',' | ';' => Token::Separator,
+            c if c.is_whitespace() => continue,
+            c => return Err(format!("unexpected character {:?} in location", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Degrees, minutes and seconds of one axis.
+#[derive(Debug, Default)]
+struct Angle {
+    parts: Vec<(f64, Option<Token>)>,
+    hemisphere: Option<char>,
+}
+
+impl Angle {
+    fn degrees(&self) -> Result<f64, String> {
+        let mut values = [0.; 3];
+        for (i, (value, unit)) in self.parts.iter().enumerate() {
+            let position = match unit {
+                Some(Token::Degrees) => 0,
+                Some(Token::Minutes) => 1,
+                Some(Token::Seconds) => 2,
+                _ => i,
+            };
+            values[position] = *value;
+        }
+        let [degrees, minutes, seconds] = values;
+        if minutes < 0. || seconds < 0. || minutes >= 60. || seconds >= 60. {
+            return Err("minutes and seconds have to be values between 0 & 60".to_string());
+        }
+        let magnitude = degrees.abs() + minutes / 60. + seconds / 3600.;
+        let negative = degrees.is_sign_negative() || matches!(self.hemisphere, Some('S' | 'W'));
+        Ok(if negative { -magnitude } else { magnitude })
+    }
+}
+
+/// Split tokens into the angles of both axes.
+fn angles(tokens: Vec<Token>) -> Result<Vec<Angle>, String> {
+    let mut angles = vec![];
+    let mut current = Angle::default();
+    for token in tokens {
+        match token {
+            Token::Number(value) => {
+                let after_seconds = current
+                    .parts
+                    .last()
+                    .is_some_and(|(_, unit)| *unit == Some(Token::Seconds));
+                if current.parts.len() == 3 || after_seconds {
+                    angles.push(std::mem::take(&mut current));
+                }
+                current.parts.push((value, None));
+            }
+            Token::Degrees | Token::Minutes | Token::Seconds => {
+                // a number after minutes or seconds followed by degrees
+                // starts the next angle
+                if token == Token::Degrees && current.parts.len() > 1 {
+                    let part = current.parts.pop();
+                    angles.push(std::mem::take(&mut current));
+                    current.parts.extend(part);
+                }
+                match current.parts.last_mut() {
+                    Some((_, unit @ None)) => *unit = Some(token),
+                    _ => return Err("unit without number in location".to_string()),
+                }
+            }
+            Token::Hemisphere(hemisphere) => {
+                if current.hemisphere.is_some() {
+                    angles.push(std::mem::take(&mut current));
+                }
+                current.hemisphere = Some(hemisphere);
+                if !current.parts.is_empty() {
+                    angles.push(std::mem::take(&mut current));
+                }
+            }
+            Token::Separator => {
+                if !current.parts.is_empty() || current.hemisphere.is_some() {
+                    angles.push(std::mem::take(&mut current));
+                }
+            }
+        }
+    }
+    if !current.parts.is_empty() || current.hemisphere.is_some() {
+        angles.push(current);
+    }
+    Ok(angles)
+}
+
+/// Degrees, minutes and seconds with optional hemispheres, axes without
+/// hemisphere are taken in `order`.
+fn parse_dms(s: &str, order: AxisOrder) -> Result<[f64; 2], String> {
+    let invalid = || format!("invalid location {}", s);
+    let angles = angles(tokenize(s)?)?;
+    let [a, b] = match &angles[..] {
+        [a, b] if !a.parts.is_empty() && !b.parts.is_empty() => [a, b],
+        _ => return Err(invalid()),
+    };
+    let is_lat = |angle: &Angle| match angle.hemisphere {
+        Some('N' | 'S') => Some(true),
+        Some(_) => Some(false),
+        None => None,
+    };
+    let a_is_lat = match (is_lat(a), is_lat(b)) {
+        (Some(a), Some(b)) if a == b => return Err(invalid()),
+        (Some(a), _) => a,
+        (None, Some(b)) => !b,
+        (None, None) => order == AxisOrder::LatLng,
+    };
+    let (a, b) = (a.degrees()?, b.degrees()?);
+    Ok(if a_is_lat { [b, a] } else { [a, b] })
 }
 
 impl TryFrom<&str> for Location {
-    type Error = &'static str;
+    type Error = String;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let error_msg = "location must be specified as 2 comma seperated floats";
-        let ll: Vec<&str> = value.split(',').collect();
-        if ll.len() != 2 {
-            return Err(error_msg);
-        }
-        let lng_str = ll.first().ok_or(error_msg)?;
-        let lat_str = ll.get(1).ok_or(error_msg)?;
-        let lng = f64::from_str(lng_str).or(Err(error_msg))?;
-        let lat = f64::from_str(lat_str).or(Err(error_msg))?;
-        Location::new(lng, lat).or(Err(error_msg))
+        Location::parse(value, AxisOrder::LngLat)
     }
 }
 
@@ -79,13 +380,96 @@ impl From<Location> for [f64; 2] {
 }
 
 impl FromStr for Location {
-    type Err = ParseFloatError;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let coords: Vec<&str> = s.split(',').collect();
-        let lng = coords[0].parse::<f64>()?;
-        let lat = coords[1].parse::<f64>()?;
-        let loc = Self { lng, lat };
-        Ok(loc)
+        Location::parse(s, AxisOrder::LngLat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str, order: AxisOrder) -> [f64; 2] {
+        let [lng, lat]: [f64; 2] = Location::parse(s, order).unwrap().into();
+        let round = |v: f64| (v * 1e4).round() / 1e4;
+        [round(lng), round(lat)]
+    }
+
+    #[test]
+    fn parses_decimal_pairs() {
+        let lng_lat = AxisOrder::LngLat;
+        assert_eq!(parse("13.405,52.52", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse(" 13.405, 52.52 ", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse("13.405 52.52", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse("52.52,13.405", AxisOrder::LatLng), [13.405, 52.52]);
+        assert_eq!(parse("-70.5\t-33.4", lng_lat), [-70.5, -33.4]);
+    }
+
+    #[test]
+    fn parses_degrees_minutes_seconds() {
+        let berlin = [13.405, 52.52];
+        for s in [
+            "52°31'12\"N 13°24'18\"E",
+            "13°24'18\"E, 52°31'12\"N",
+            "N 52°31.2' E 13°24.3'",
+            "52°31′12″N 13°24′18″E",
+            "52 31 12 N 13 24 18 E",
+            "52.52N 13.405E",
+            "52.52N13.405E",
+        ] {
+            assert_eq!(parse(s, AxisOrder::LngLat), berlin, "{}", s);
+        }
+        assert_eq!(parse("52°31'12\" 13°24'18\"", AxisOrder::LatLng), berlin);
+        assert_eq!(
+            parse("33°26'S 70°39'W", AxisOrder::LngLat),
+            [-70.65, -33.4333]
+        );
+        assert_eq!(
+            parse("-33°26', -70°39'", AxisOrder::LatLng),
+            [-70.65, -33.4333]
+        );
+    }
+
+    #[test]
+    fn parses_uris_wkt_and_geohashes() {
+        let lng_lat = AxisOrder::LngLat;
+        assert_eq!(parse("geo:52.52,13.405", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse("geo:52.52,13.405,34;u=10", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse("POINT(13.405 52.52)", lng_lat), [13.405, 52.52]);
+        assert_eq!(parse("point z (13.405 52.52 34)", lng_lat), [13.405, 52.52]);
+        // wikipedia's example, the cell spans about 0.05 degrees
+        let [lng, lat] = parse("ezs42", AxisOrder::LatLng);
+        assert!((lng + 5.6).abs() < 0.03 && (lat - 42.6).abs() < 0.03);
+        let [lng, lat] = parse("u33dc0cpke7v", lng_lat);
+        assert!((lng - 13.405).abs() < 1e-3 && (lat - 52.52).abs() < 1e-3);
+        // hemisphere letters in a geohash, but not after every number
+        let [lng, lat] = parse("6egx", lng_lat);
+        assert!((-90.0..-45.).contains(&lng) && (-45.0..0.).contains(&lat));
+        assert_eq!(parse("52N13E", lng_lat), [13., 52.]);
+    }
+
+    #[test]
+    fn rejects_invalid_locations() {
+        for s in [
+            "",
+            "13.405",
+            "52",
+            "5213405",
+            "13.405,52.52,1",
+            "1,2,3,4",
+            "200,52.52",
+            "13.405,95",
+            "52°31'N 13°24'N",
+            "52°61'N 13°24'E",
+            "geo:52.52",
+            "geo:52.52,13.405;crs=epsg:25832",
+            "POINT(13.405)",
+            "abc,def",
+            "ai",
+        ] {
+            assert!(s.parse::<Location>().is_err(), "{}", s);
+        }
     }
 }
@@ -0,0 +1,237 @@
+use memmap2::{Mmap, MmapMut};
+use osmpbfreader::{Node, NodeId, Tags};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Offset applied to stored latitudes, so a stored location is never zero
+/// and zeroed (sparse) regions of a dense file read as missing.
+const LAT_OFFSET: i64 = 1_000_000_000;
+const DENSE_ENTRY: usize = 8;
+const SPARSE_ENTRY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeStoreKind {
+    /// file indexed by node id, 8 bytes per id up to the largest one
+    Dense,
+    /// file of (id, location) entries sorted by id, 16 bytes per node
+    Sparse,
+}
+
+impl FromStr for NodeStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dense" => Ok(NodeStoreKind::Dense),
+            "sparse" => Ok(NodeStoreKind::Sparse),
+            _ => Err(format!(
+                "unknown node store {}, expected dense or sparse",
+                s
+            )),
+        }
+    }
+}
+
+fn encode(node: &Node) -> [u8; DENSE_ENTRY] {
+    let lat = (node.decimicro_lat as i64 + LAT_OFFSET) as u32;
+    let lon = node.decimicro_lon as u32;
+    let mut buf = [0; DENSE_ENTRY];
+    buf[..4].copy_from_slice(&lat.to_le_bytes());
+    buf[4..].copy_from_slice(&lon.to_le_bytes());
+    buf
+}
+
+fn decode(id: NodeId, buf: &[u8]) -> Option<Node> {
+    let lat = u32::from_le_bytes(buf[..4].try_into().ok()?);
+    if lat == 0 {
+        return None;
+    }
+    let lon = u32::from_le_bytes(buf[4..DENSE_ENTRY].try_into().ok()?);
+    Some(Node {
+        id,
+        tags: Tags::new(),
+        decimicro_lat: (lat as i64 - LAT_OFFSET) as i32,
+        decimicro_lon: lon as i32,
+    })
+}
+
+fn sparse_id(entry: &[u8]) -> i64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&entry[..8]);
+    i64::from_le_bytes(buf)
+}
+
+enum Store {
+    Dense(MmapMut),
+    Sparse {
+        writer: Option<BufWriter<File>>,
+        file: File,
+        map: Option<Mmap>,
+        last_id: i64,
+        sorted: bool,
+    },
+}
+
+/// Node locations kept in a memory mapped file, so memory use doesn't grow
+/// with the number of nodes. Locations are inserted first, `finish` has to
+/// be called before they can be read.
+pub struct NodeStore {
+    store: Store,
+}
+
+impl NodeStore {
+    /// Create a store for node ids up to `max_id` at `path`. Dense files
+    /// are created sparse on file systems supporting it, so only pages
+    /// holding locations take up disk space.
+    pub fn create(path: &Path, kind: NodeStoreKind, max_id: i64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let store = match kind {
+            NodeStoreKind::Dense => {
+                let len = (max_id.max(0) as u64 + 1) * DENSE_ENTRY as u64;
+                file.set_len(len)?;
+                Store::Dense(unsafe { MmapMut::map_mut(&file)? })
+            }
+            NodeStoreKind::Sparse => Store::Sparse {
+                writer: Some(BufWriter::new(file.try_clone()?)),
+                file,
+                map: None,
+                last_id: i64::MIN,
+                sorted: true,
+            },
+        };
+        Ok(NodeStore { store })
+    }
+
+    pub fn insert(&mut self, node: &Node) -> io::Result<()> {
+        match &mut self.store {
+            Store::Dense(map) => {
+                let pos = usize::try_from(node.id.0)
+                    .ok()
+                    .and_then(|id| id.checked_mul(DENSE_ENTRY))
+                    .filter(|pos| pos + DENSE_ENTRY <= map.len())
+                    .ok_or_else(|| {
+                        let msg = format!("node {} exceeds the dense store", node.id.0);
+                        io::Error::new(io::ErrorKind::InvalidInput, msg)
+                    })?;
+                map[pos..pos + DENSE_ENTRY].copy_from_slice(&encode(node));
+            }
+            Store::Sparse {
+                writer: Some(writer),
+                last_id,
+                sorted,
+                ..
+            } => {
+                *sorted &= node.id.0 > *last_id;
+                *last_id = node.id.0;
+                writer.write_all(&node.id.0.to_le_bytes())?;
+                writer.write_all(&encode(node))?;
+            }
+            Store::Sparse { writer: None, .. } => {
+                let msg = "node store is finished";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.store {
+            Store::Dense(map) => map.flush(),
+            Store::Sparse {
+                writer,
+                file,
+                map,
+                sorted,
+                ..
+            } => {
+                if let Some(mut writer) = writer.take() {
+                    writer.flush()?;
+                }
+                let mut mapped = unsafe { MmapMut::map_mut(&*file)? };
+                // osm files are sorted by id, others are sorted here
+                if !*sorted {
+                    let mut entries: Vec<[u8; SPARSE_ENTRY]> = mapped
+                        .chunks_exact(SPARSE_ENTRY)
+                        .filter_map(|entry| entry.try_into().ok())
+                        .collect();
+                    entries.sort_by_key(|entry| sparse_id(entry));
+                    for (i, entry) in entries.iter().enumerate() {
+                        mapped[i * SPARSE_ENTRY..(i + 1) * SPARSE_ENTRY].copy_from_slice(entry);
+                    }
+                    *sorted = true;
+                }
+                *map = Some(mapped.make_read_only()?);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<Node> {
+        match &self.store {
+            Store::Dense(map) => {
+                let pos = usize::try_from(id.0).ok()?.checked_mul(DENSE_ENTRY)?;
+                decode(id, map.get(pos..pos + DENSE_ENTRY)?)
+            }
+            Store::Sparse { map, .. } => {
+                let map = map.as_ref()?;
+                let count = map.len() / SPARSE_ENTRY;
+                let entry = |i: usize| &map[i * SPARSE_ENTRY..(i + 1) * SPARSE_ENTRY];
+                let (mut lower, mut upper) = (0, count);
+                while lower < upper {
+                    let middle = (lower + upper) / 2;
+                    match sparse_id(entry(middle)).cmp(&id.0) {
+                        std::cmp::Ordering::Less => lower = middle + 1,
+                        std::cmp::Ordering::Greater => upper = middle,
+                        std::cmp::Ordering::Equal => return decode(id, &entry(middle)[8..]),
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, lat: i32, lon: i32) -> Node {
+        Node {
+            id: NodeId(id),
+            tags: Tags::new(),
+            decimicro_lat: lat,
+            decimicro_lon: lon,
+        }
+    }
+
+    #[test]
+    fn stores_locations() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = [
+            node(7, 530_890_000, 88_220_000),
+            node(3, -900_000_000, -1_800_000_000),
+            node(12, 0, 0),
+        ];
+        for kind in &[NodeStoreKind::Dense, NodeStoreKind::Sparse] {
+            let path = dir.path().join(format!("{:?}.nodes", kind));
+            let mut store = NodeStore::create(&path, *kind, 12).unwrap();
+            for node in &nodes {
+                store.insert(node).unwrap();
+            }
+            store.finish().unwrap();
+            for node in &nodes {
+                assert_eq!(store.get(node.id).as_ref(), Some(node));
+            }
+            assert_eq!(store.get(NodeId(5)), None);
+            assert_eq!(store.get(NodeId(13)), None);
+        }
+    }
+}
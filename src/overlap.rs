@@ -0,0 +1,80 @@
+use geo_types::Geometry;
+use geojson::GeoJson;
+use osm_admin_lookup::boundary::geodesic_area_km2;
+use osm_admin_lookup::load_tree;
+use osm_admin_lookup::query::to_multi_polygon;
+use osm_admin_lookup::query::{OverlapEntry, OverlapResponse};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "overlap",
+    about = "share of a polygon falling into each boundary"
+)]
+struct Opt {
+    /// rtree bin path
+    #[structopt(short = "b", long = "bin")]
+    bin_path: PathBuf,
+
+    /// geojson file with a polygon or multipolygon geometry, feature or
+    /// feature collection
+    #[structopt(short = "g", long = "geojson")]
+    geojson_path: PathBuf,
+
+    /// limit results to admin levels, repeat for several
+    #[structopt(short = "a", long = "admin-level")]
+    admin_level: Option<Vec<u8>>,
+
+    /// print json instead of text
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let geojson: GeoJson = read_to_string(&opt.geojson_path)?.parse()?;
+    let mp = to_multi_polygon(Geometry::try_from(geojson)?)
+        .ok_or("geojson does not contain a polygon or multipolygon")?;
+    let tree = load_tree(&opt.bin_path)?;
+
+    let levels = opt.admin_level;
+    let mut overlaps: Vec<OverlapEntry> = tree.overlaps(&mp, |overlaps| {
+        overlaps
+            .into_iter()
+            .filter(|overlap| {
+                let level = overlap.boundary.admin_level;
+                levels.as_ref().is_none_or(|levels| levels.contains(&level))
+            })
+            .map(OverlapEntry::from)
+            .collect()
+    })?;
+    overlaps.sort_by(|a, b| {
+        a.level
+            .cmp(&b.level)
+            .then(b.fraction.total_cmp(&a.fraction))
+    });
+    let response = OverlapResponse {
+        area_km2: geodesic_area_km2(&mp),
+        overlaps,
+    };
+
+    if opt.json {
+        println!("{}", serde_json::to_string(&response)?);
+        return Ok(());
+    }
+    println!("area: {:.3} km²", response.area_km2);
+    for overlap in &response.overlaps {
+        println!(
+            "boundary: {}, level: {}, area: {:.3} km², fraction: {:.1}%",
+            overlap.name,
+            overlap.level,
+            overlap.area_km2,
+            overlap.fraction * 100.,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use crate::boundary::{geodesic_area_km2, Boundary};
+use crate::RTree;
+use geo::algorithm::bool_ops::BooleanOps;
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::intersects::Intersects;
+use geo::algorithm::relate::Relate;
+use geo_types::{Geometry, MultiPolygon, Rect};
+use rstar::AABB;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How boundaries relate to a query geometry to be part of the result.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    /// boundaries sharing at least one point with the geometry
+    #[default]
+    Intersects,
+    /// boundaries containing the geometry fully, its border included
+    Contains,
+    /// boundaries lying completely within the geometry
+    Within,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "intersects" => Ok(Mode::Intersects),
+            "contains" => Ok(Mode::Contains),
+            "within" => Ok(Mode::Within),
+            _ => Err(format!(
+                "unknown mode {}, expected intersects, contains or within",
+                s
+            )),
+        }
+    }
+}
+
+/// Envelope of a geometry, `None` for empty geometries.
+pub fn geometry_envelope(geometry: &Geometry<f64>) -> Option<AABB<[f64; 2]>> {
+    let rect = geometry.bounding_rect()?;
+    let lower = [rect.min().x, rect.min().y];
+    let upper = [rect.max().x, rect.max().y];
+    Some(AABB::from_corners(lower, upper))
+}
+
+/// Parse a bounding box given as `min_lng,min_lat,max_lng,max_lat`, with
+/// lng in -180..180 and lat in -90..90.
+pub fn parse_bbox(s: &str) -> Result<Rect<f64>, String> {
+    let coords: Vec<f64> = s
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid bbox {}: {}", s, e))?;
+    let lng = |x: f64| (-180.0..=180.0).contains(&x);
+    let lat = |y: f64| (-90.0..=90.0).contains(&y);
+    match coords[..] {
+        [min_x, min_y, max_x, max_y]
+            if lng(min_x)
+                && lng(max_x)
+                && lat(min_y)
+                && lat(max_y)
+                && min_x <= max_x
+                && min_y <= max_y =>
+        {
+            Ok(Rect::new((min_x, min_y), (max_x, max_y)))
+        }
+        _ => Err(format!(
+            "invalid bbox {}, expected min_lng,min_lat,max_lng,max_lat",
+            s
+        )),
+    }
+}
+
+fn matches(boundary: &Boundary, geometry: &Geometry<f64>, mode: Mode) -> bool {
+    match mode {
+        Mode::Intersects => boundary.mp.intersects(geometry),
+        Mode::Contains => boundary.mp.relate(geometry).is_covers(),
+        Mode::Within => boundary.mp.relate(geometry).is_coveredby(),
+    }
+}
+
+/// Boundaries of `tree` relating to `geometry` as given by `mode`.
+pub fn boundaries_intersecting<'b>(
+    tree: &'b RTree,
+    geometry: &Geometry<f64>,
+    mode: Mode,
+) -> Vec<&'b Boundary> {
+    let envelope = match geometry_envelope(geometry) {
+        Some(envelope) => envelope,
+        None => return vec![],
+    };
+    let candidates: Box<dyn Iterator<Item = &Boundary>> = match mode {
+        Mode::Within => Box::new(tree.locate_in_envelope(&envelope)),
+        _ => Box::new(tree.locate_in_envelope_intersecting(&envelope)),
+    };
+    candidates
+        .filter(|boundary| matches(boundary, geometry, mode))
+        .collect()
+}
+
+/// Boundaries of `tree` relating to `bbox` as given by `mode`.
+pub fn boundaries_in_bbox(tree: &RTree, bbox: Rect<f64>, mode: Mode) -> Vec<&Boundary> {
+    boundaries_intersecting(tree, &bbox.to_polygon().into(), mode)
+}
+
+/// The part of a query polygon falling into a boundary.
+#[derive(Debug)]
+pub struct Overlap<'b> {
+    pub boundary: &'b Boundary,
+    /// geodesic area of the intersection in km²
+    pub area: f64,
+    /// share of the query polygon's area
+    pub fraction: f64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OverlapResponse {
+    /// geodesic area of the query in km²
+    pub area_km2: f64,
+    pub overlaps: Vec<OverlapEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OverlapEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub level: u8,
+    pub name: String,
+    /// geodesic area of the intersection in km²
+    pub area_km2: f64,
+    /// share of the query's area
+    pub fraction: f64,
+}
+
+impl From<Overlap<'_>> for OverlapEntry {
+    fn from(overlap: Overlap) -> Self {
+        OverlapEntry {
+            id: overlap.boundary.osm_id,
+            level: overlap.boundary.admin_level,
+            name: overlap.boundary.name.clone(),
+            area_km2: overlap.area,
+            fraction: overlap.fraction,
+        }
+    }
+}
+
+/// Areal parts of a geometry, `None` if it has none. Points and lines of
+/// geometry collections are ignored.
+pub fn to_multi_polygon(geometry: Geometry<f64>) -> Option<MultiPolygon<f64>> {
+    let polygons = match geometry {
+        Geometry::Polygon(polygon) => vec![polygon],
+        Geometry::MultiPolygon(mp) => mp.0,
+        Geometry::Rect(rect) => vec![rect.to_polygon()],
+        Geometry::Triangle(triangle) => vec![triangle.to_polygon()],
+        Geometry::GeometryCollection(collection) => collection
+            .into_iter()
+            .filter_map(to_multi_polygon)
+            .flatten()
+            .collect(),
+        _ => vec![],
+    };
+    match polygons.is_empty() {
+        true => None,
+        false => Some(MultiPolygon(polygons)),
+    }
+}
+
+/// Boundaries of `tree` intersecting `mp`, with the area of the
+/// intersection and its share of the area of `mp`.
+pub fn overlaps<'b>(tree: &'b RTree, mp: &MultiPolygon<f64>) -> Vec<Overlap<'b>> {
+    let total = geodesic_area_km2(mp);
+    let geometry = Geometry::MultiPolygon(mp.clone());
+    boundaries_intersecting(tree, &geometry, Mode::Intersects)
+        .into_iter()
+        .filter_map(|boundary| {
+            let area = geodesic_area_km2(&boundary.mp.intersection(mp));
+            let fraction = match total > 0. {
+                true => (area / total).min(1.),
+                false => 0.,
+            };
+            // touching borders only
+            (area > 0.).then_some(Overlap {
+                boundary,
+                area,
+                fraction,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+
+    fn square(min: f64, max: f64, name: &str) -> Boundary {
+        let polygon = polygon![
+            (x: min, y: min),
+            (x: max, y: min),
+            (x: max, y: max),
+            (x: min, y: max),
+        ];
+        Boundary::new(polygon.into(), name, 8)
+    }
+
+    fn names(boundaries: Vec<&Boundary>) -> Vec<&str> {
+        let mut names: Vec<&str> = boundaries.iter().map(|b| b.name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn queries_by_mode() {
+        let tree = RTree::bulk_load(vec![
+            square(0., 4., "large"),
+            square(1., 2., "small"),
+            square(5., 6., "far"),
+        ]);
+        let bbox = parse_bbox("0.5,0.5,2.5,2.5").unwrap();
+        let intersecting = boundaries_in_bbox(&tree, bbox, Mode::Intersects);
+        assert_eq!(names(intersecting), ["large", "small"]);
+        let containing = boundaries_in_bbox(&tree, bbox, Mode::Contains);
+        assert_eq!(names(containing), ["large"]);
+        let within = boundaries_in_bbox(&tree, bbox, Mode::Within);
+        assert_eq!(names(within), ["small"]);
+
+        let point = Geometry::Point((5.5, 5.5).into());
+        let found = boundaries_intersecting(&tree, &point, Mode::Contains);
+        assert_eq!(names(found), ["far"]);
+    }
+
+    fn fractions(tree: &RTree, bbox: &str) -> Vec<(String, f64)> {
+        let query: MultiPolygon<f64> = parse_bbox(bbox).unwrap().to_polygon().into();
+        let mut fractions: Vec<(String, f64)> = overlaps(tree, &query)
+            .iter()
+            .map(|o| (o.boundary.name.clone(), (o.fraction * 100.).round() / 100.))
+            .collect();
+        fractions.sort_by(|a, b| a.0.cmp(&b.0));
+        fractions
+    }
+
+    #[test]
+    fn computes_overlap_fractions() {
+        let tree = RTree::bulk_load(vec![
+            square(0., 1., "left"),
+            square(1., 2., "right"),
+            square(0., 2., "both"),
+        ]);
+        let query: MultiPolygon<f64> = parse_bbox("0.5,0,1,1").unwrap().to_polygon().into();
+        let overlap = overlaps(&tree, &query);
+        assert!((overlap[0].area - geodesic_area_km2(&query)).abs() < 1e-6);
+
+        // "right" only touches the query in a corner
+        let expected = [("both".to_string(), 1.), ("left".to_string(), 1.)];
+        assert_eq!(fractions(&tree, "0.5,0,1,1"), expected);
+        let expected = [
+            ("both".to_string(), 1.),
+            ("left".to_string(), 0.25),
+            ("right".to_string(), 0.25),
+        ];
+        assert_eq!(fractions(&tree, "0.5,0.5,1.5,1.5"), expected);
+    }
+
+    #[test]
+    fn rejects_invalid_bbox() {
+        assert!(parse_bbox("1,2,3").is_err());
+        assert!(parse_bbox("3,0,1,1").is_err());
+        assert!(parse_bbox("a,0,1,1").is_err());
+        assert!(parse_bbox("0,3,1,1").is_err());
+        assert!(parse_bbox("-181,0,1,1").is_err());
+        assert!(parse_bbox("0,0,180.5,1").is_err());
+        assert!(parse_bbox("0,-91,1,1").is_err());
+        assert!(parse_bbox("0,0,1,NaN").is_err());
+        assert!(parse_bbox("-180,-90,180,90").is_ok());
+    }
+}
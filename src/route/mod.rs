@@ -0,0 +1,267 @@
+use crate::boundary::Boundary;
+use geo::algorithm::line_intersection::{line_intersection, LineIntersection};
+use geo::{Distance, Haversine};
+use geo_types::{Coord, Line, LineString, MultiPolygon, Point};
+use rstar::primitives::Line as Segment;
+use rstar::{RTree, AABB};
+use std::collections::BTreeMap;
+
+/// Precision of encoded polylines, unless given otherwise.
+pub const DEFAULT_PRECISION: u32 = 5;
+
+/// A stretch of a route inside a boundary.
+#[derive(Debug)]
+pub struct Visit<'b> {
+    pub boundary: &'b Boundary,
+    /// where the route crosses into the boundary, `None` if it starts inside
+    pub entry: Option<Point<f64>>,
+    /// where the route leaves the boundary, `None` if it ends inside
+    pub exit: Option<Point<f64>>,
+    /// distance along the route in metres at which the visit starts
+    pub start: f64,
+    /// distance along the route in metres at which the visit ends
+    pub end: f64,
+}
+
+impl Visit<'_> {
+    /// Distance travelled inside the boundary in metres.
+    pub fn distance(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// Boundaries of one admin level a route passes through, in order.
+#[derive(Debug)]
+pub struct LevelCrossings<'b> {
+    pub level: u8,
+    pub visits: Vec<Visit<'b>>,
+}
+
+/// Decode an encoded polyline, e.g. of a routing engine.
+pub fn decode_polyline(encoded: &str, precision: u32) -> Result<LineString<f64>, String> {
+    polyline::decode_polyline(encoded, precision).map_err(|e| format!("invalid polyline: {}", e))
+}
+
+/// Length of a route in metres.
+pub fn length(line: &LineString<f64>) -> f64 {
+    line.lines()
+        .map(|segment| Haversine::distance(segment.start_point(), segment.end_point()))
+        .sum()
+}
+
+/// Ring segments of a boundary, indexed so route segments are only
+/// intersected with the ones near them.
+fn ring_segments(mp: &MultiPolygon<f64>) -> RTree<Segment<[f64; 2]>> {
+    let segments =
+        mp.0.iter()
+            .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+            .flat_map(LineString::lines)
+            .map(|line| Segment::new(line.start.into(), line.end.into()))
+            .collect();
+    RTree::bulk_load(segments)
+}
+
+/// Position of `p`, a point on `segment`, as a fraction of its length.
+fn param(segment: &Line<f64>, p: Coord<f64>) -> f64 {
+    let d = segment.delta();
+    let t = match d.x.abs() > d.y.abs() {
+        true => (p.x - segment.start.x) / d.x,
+        false => (p.y - segment.start.y) / d.y,
+    };
+    t.clamp(0., 1.)
+}
+
+/// Fractions of `segment` at which it crosses or touches the ring
+/// segments of a boundary, including its start and end.
+fn split_params(segment: &Line<f64>, rings: &RTree<Segment<[f64; 2]>>) -> Vec<f64> {
+    let mut params = vec![0., 1.];
+    let envelope = AABB::from_corners(segment.start.into(), segment.end.into());
+    for ring_segment in rings.locate_in_envelope_intersecting(&envelope) {
+        let line = Line::new(ring_segment.from, ring_segment.to);
+        match line_intersection(*segment, line) {
+            Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                params.push(param(segment, intersection));
+            }
+            Some(LineIntersection::Collinear { intersection }) => {
+                params.push(param(segment, intersection.start));
+                params.push(param(segment, intersection.end));
+            }
+            None => {}
+        }
+    }
+    params.sort_by(f64::total_cmp);
+    params.dedup();
+    params
+}
+
+/// Stretches of `line` inside `boundary`, in route order.
+fn visits<'b>(boundary: &'b Boundary, line: &LineString<f64>) -> Vec<Visit<'b>> {
+    let mut visits = vec![];
+    let mut current: Option<Visit> = None;
+    let mut offset = 0.;
+    let rings = ring_segments(&boundary.mp);
+    for segment in line.lines() {
+        // repeated fixes have no direction to split or sample along
+        if segment.start == segment.end {
+            continue;
+        }
+        let at = |t: f64| Point::from(segment.start + segment.delta() * t);
+        let along = |t: f64| offset + Haversine::distance(segment.start_point(), at(t));
+        for piece in split_params(&segment, &rings).windows(2) {
+            let (t0, t1) = (piece[0], piece[1]);
+            if t1 - t0 < 1e-12 {
+                continue;
+            }
+            let middle = at((t0 + t1) / 2.);
+            if boundary.contains(&[middle.x(), middle.y()]) {
+                match &mut current {
+                    Some(visit) => visit.end = along(t1),
+                    None => {
+                        let start = along(t0);
+                        current = Some(Visit {
+                            boundary,
+                            entry: (start > 0.).then(|| at(t0)),
+                            exit: None,
+                            start,
+                            end: along(t1),
+                        });
+                    }
+                }
+            } else if let Some(mut visit) = current.take() {
+                visit.exit = Some(at(t0));
+                visits.push(visit);
+            }
+        }
+        offset += Haversine::distance(segment.start_point(), segment.end_point());
+    }
+    visits.extend(current);
+    visits
+}
+
+/// Stretches of `line` inside each of `boundaries`, grouped by admin level
+/// and ordered along the route.
+pub fn crossings<'b>(
+    boundaries: impl IntoIterator<Item = &'b Boundary>,
+    line: &LineString<f64>,
+) -> Vec<LevelCrossings<'b>> {
+    let mut levels: BTreeMap<u8, Vec<Visit>> = BTreeMap::new();
+    for boundary in boundaries {
+        let visits = visits(boundary, line);
+        if !visits.is_empty() {
+            levels
+                .entry(boundary.admin_level)
+                .or_default()
+                .extend(visits);
+        }
+    }
+    levels
+        .into_iter()
+        .map(|(level, mut visits)| {
+            visits.sort_by(|a, b| a.start.total_cmp(&b.start));
+            LevelCrossings { level, visits }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::{line_string, polygon};
+    use geo_types::Polygon;
+
+    fn rect(min_x: f64, max_x: f64, name: &str, level: u8) -> Boundary {
+        let polygon = polygon![
+            (x: min_x, y: 0.),
+            (x: max_x, y: 0.),
+            (x: max_x, y: 1.),
+            (x: min_x, y: 1.),
+        ];
+        Boundary::new(polygon.into(), name, level)
+    }
+
+    #[test]
+    fn orders_crossings_along_route() {
+        let boundaries = [
+            rect(0., 1., "west", 8),
+            rect(1., 2., "east", 8),
+            rect(0., 2., "state", 4),
+        ];
+        let line = line_string![(x: 0.5, y: 0.5), (x: 1.5, y: 0.5), (x: 2.5, y: 0.5)];
+        let levels = crossings(&boundaries, &line);
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].level, 4);
+        assert_eq!(levels[0].visits[0].boundary.name, "state");
+
+        let visits = &levels[1].visits;
+        let names: Vec<&str> = visits.iter().map(|v| v.boundary.name.as_str()).collect();
+        assert_eq!(names, ["west", "east"]);
+        assert_eq!(visits[0].entry, None);
+        assert_eq!(visits[0].exit, Some(Point::new(1., 0.5)));
+        assert_eq!(visits[1].entry, Some(Point::new(1., 0.5)));
+        assert_eq!(visits[1].exit, Some(Point::new(2., 0.5)));
+        // half a degree and a degree of longitude, close to the equator
+        assert!((visits[0].distance() - 55_597.).abs() < 100.);
+        assert!((visits[1].distance() - 111_195.).abs() < 100.);
+        assert!((visits[1].end - length(&line) + 55_597.).abs() < 100.);
+    }
+
+    #[test]
+    fn splits_reentering_visits() {
+        let boundaries = [rect(0., 1., "area", 8)];
+        let line = line_string![(x: 0.5, y: 0.5), (x: 0.5, y: 1.5), (x: 0.6, y: 0.5)];
+        let levels = crossings(&boundaries, &line);
+        let visits = &levels[0].visits;
+        assert_eq!(visits.len(), 2);
+        assert_eq!(visits[1].exit, None);
+        assert!(visits[0].end < visits[1].start);
+    }
+
+    #[test]
+    fn ignores_repeated_vertices() {
+        let polygon = polygon![
+            (x: 0., y: 0.),
+            (x: 1., y: 0.),
+            (x: 1., y: 0.),
+            (x: 1., y: 1.),
+            (x: 0., y: 1.),
+        ];
+        let boundaries = [Boundary::new(polygon.into(), "area", 8)];
+        let line = line_string![
+            (x: 0.5, y: 0.5),
+            (x: 0.5, y: 1.),
+            (x: 0.5, y: 1.),
+            (x: 0.5, y: 0.8),
+            (x: 1.5, y: 0.8),
+        ];
+        let levels = crossings(&boundaries, &line);
+        let visits = &levels[0].visits;
+        assert_eq!(visits.len(), 1);
+        assert_eq!(visits[0].entry, None);
+        assert_eq!(visits[0].exit, Some(Point::new(1., 0.8)));
+    }
+
+    #[test]
+    fn crosses_rings_with_many_vertices() {
+        let ring: LineString<f64> = (0..=10_000)
+            .map(|i| {
+                let angle = i as f64 / 10_000. * std::f64::consts::TAU;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        let circle = Polygon::new(ring, vec![]);
+        let boundaries = [Boundary::new(circle.into(), "circle", 8)];
+        let line = line_string![(x: -2., y: 0.001), (x: 2., y: 0.001)];
+        let levels = crossings(&boundaries, &line);
+        let visits = &levels[0].visits;
+        assert_eq!(visits.len(), 1);
+        let (entry, exit) = (visits[0].entry.unwrap(), visits[0].exit.unwrap());
+        assert!((entry.x() + 1.).abs() < 1e-3 && (exit.x() - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn decodes_polylines() {
+        let line = decode_polyline("_p~iF~ps|U_ulLnnqC_mqNvxq`@", DEFAULT_PRECISION).unwrap();
+        assert_eq!(line.0[0], Coord { x: -120.2, y: 38.5 });
+        assert!(decode_polyline("_p~iF~ps|U_", DEFAULT_PRECISION).is_err());
+    }
+}
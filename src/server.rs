@@ -1,4 +1,4 @@
-use osm_admin_lookup::load_tree;
+use osm_admin_lookup::open_tree;
 use osm_admin_lookup::service::start;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -10,6 +10,13 @@ pub struct Opt {
     /// rtree bin path
     #[structopt(short = "b", long = "bin", env = "RTREE_BIN")]
     pub bin_path: PathBuf,
+    /// shards of a sharded bin kept in memory
+    #[structopt(long = "max-shards", env = "MAX_SHARDS", default_value = "32")]
+    pub max_shards: usize,
+    /// size limit of json request bodies in bytes, routes and polygons
+    /// easily exceed actix's default of 32 KB
+    #[structopt(long = "json-limit", env = "JSON_LIMIT", default_value = "8388608")]
+    pub json_limit: usize,
     /// http port
     #[structopt(short, long, env = "PORT", default_value = "8080")]
     pub port: u16,
@@ -18,8 +25,8 @@ pub struct Opt {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    let rtree = load_tree(&opt.bin_path)?;
-    info!("rtree {:?} loaded", opt.bin_path);
-    start(rtree, opt.port).await?;
+    let tree = open_tree(&opt.bin_path, opt.max_shards)?;
+    info!("rtree {:?} loaded, {:?}", opt.bin_path, tree.metadata());
+    start(tree, opt.port, opt.json_limit).await?;
     Ok(())
 }
@@ -1,15 +1,19 @@
-use super::boundaries;
-use super::boundary::Boundary;
-use super::location::Location;
-use super::RTree;
+use super::boundary::geodesic_area_km2;
+use super::boundary::{Boundary, MemberNode};
+use super::location::{AxisOrder, Location};
+use super::query::{parse_bbox, to_multi_polygon, Mode, OverlapEntry, OverlapResponse};
+use super::route::{decode_polyline, length, LevelCrossings, Visit, DEFAULT_PRECISION};
+use super::Tree;
 use actix_web::dev::Service as _;
-use actix_web::{error, get, web, App, HttpServer, Responder, Result};
+use actix_web::{error, get, post, web, App, HttpServer, Responder, Result};
 use futures_util::future::FutureExt;
+use geo_types::{Geometry, LineString};
+use geojson::GeoJson;
 use lazy_static::lazy_static;
 use prometheus::{register_histogram_vec, register_int_counter_vec};
 use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::sync::Arc;
 use time::OffsetDateTime;
@@ -33,15 +37,18 @@ lazy_static! {
     .unwrap();
 }
 
-type AppState = Arc<RTree>;
+type AppState = Arc<Tree>;
 
 impl From<Vec<&Boundary>> for LocateResponse {
     fn from(boundaries: Vec<&Boundary>) -> Self {
         let boundaries = boundaries
             .into_iter()
             .map(|boundary| BoundaryResponse {
+                id: boundary.osm_id,
                 level: boundary.admin_level,
                 name: boundary.name.clone(),
+                admin_centre: boundary.admin_centre.as_ref().map(Into::into),
+                label: boundary.label.as_ref().map(Into::into),
             })
             .collect();
         LocateResponse { boundaries }
@@ -55,13 +62,78 @@ pub struct LocateResponse {
 
 #[derive(Deserialize, Serialize)]
 pub struct BoundaryResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub level: u8,
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_centre: Option<MemberResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<MemberResponse>,
 }
 
+/// An `admin_centre` or `label` node of a boundary.
+#[derive(Deserialize, Serialize)]
+pub struct MemberResponse {
+    pub id: i64,
+    pub name: Option<String>,
+    pub coordinates: [f64; 2],
+}
+
+impl From<&MemberNode> for MemberResponse {
+    fn from(member: &MemberNode) -> Self {
+        MemberResponse {
+            id: member.osm_id,
+            name: member.name.clone(),
+            coordinates: member.point.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BoundaryDetails {
+    pub id: i64,
+    pub level: u8,
+    pub name: String,
+    pub area_km2: f64,
+    pub centroid: [f64; 2],
+    pub pole: [f64; 2],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_centre: Option<MemberResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<MemberResponse>,
+}
+
+impl From<&Boundary> for BoundaryDetails {
+    fn from(boundary: &Boundary) -> Self {
+        BoundaryDetails {
+            id: boundary.osm_id.unwrap_or_default(),
+            level: boundary.admin_level,
+            name: boundary.name.clone(),
+            area_km2: boundary.area,
+            centroid: boundary.centroid.into(),
+            pole: boundary.pole.into(),
+            admin_centre: boundary.admin_centre.as_ref().map(Into::into),
+            label: boundary.label.as_ref().map(Into::into),
+        }
+    }
+}
+
+/// The `loc` is parsed by `Location::parse`, `order` is `lnglat` (default)
+/// or `latlng` for decimal pairs.
 #[derive(Deserialize)]
 pub struct LocateQuery {
     loc: String,
+    order: Option<String>,
+}
+
+impl LocateQuery {
+    fn order(&self) -> Result<AxisOrder> {
+        match &self.order {
+            Some(order) => order.parse().map_err(error::ErrorBadRequest),
+            None => Ok(AxisOrder::default()),
+        }
+    }
 }
 
 #[get("/locate")]
@@ -69,18 +141,243 @@ pub async fn locate(
     info: web::Query<LocateQuery>,
     state: web::Data<AppState>,
 ) -> Result<impl Responder> {
-    let location: Location = info
-        .loc
-        .as_str()
-        .try_into()
-        .map_err(error::ErrorBadRequest)?;
+    let location = Location::parse(&info.loc, info.order()?).map_err(error::ErrorBadRequest)?;
+    let point: [f64; 2] = location.into();
     let response: LocateResponse =
-        task::spawn_blocking(move || boundaries(&location, &state.clone()).into())
+        task::spawn_blocking(move || state.locate(&point, |boundaries| boundaries.into()))
             .await
-            .unwrap();
+            .unwrap()
+            .map_err(error::ErrorInternalServerError)?;
     Ok(web::Json(response))
 }
 
+/// Parameters shared by region queries: the `mode` defaults to intersects,
+/// `levels` is a comma separated list of admin levels to return.
+#[derive(Deserialize)]
+pub struct RegionQuery {
+    bbox: Option<String>,
+    mode: Option<String>,
+    levels: Option<String>,
+}
+
+impl RegionQuery {
+    fn mode(&self) -> Result<Mode> {
+        match &self.mode {
+            Some(mode) => mode.parse().map_err(error::ErrorBadRequest),
+            None => Ok(Mode::default()),
+        }
+    }
+
+    fn levels(&self) -> Result<Option<Vec<u8>>> {
+        let levels = match &self.levels {
+            Some(levels) => levels,
+            None => return Ok(None),
+        };
+        let levels = levels
+            .split(',')
+            .map(|level| level.trim().parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| error::ErrorBadRequest(format!("invalid levels {}: {}", levels, e)))?;
+        Ok(Some(levels))
+    }
+}
+
+/// Boundaries relating to `geometry`, on the requested levels.
+async fn region(
+    geometry: Geometry<f64>,
+    query: &RegionQuery,
+    state: web::Data<AppState>,
+) -> Result<LocateResponse> {
+    let mode = query.mode()?;
+    let levels = query.levels()?;
+    task::spawn_blocking(move || {
+        state.boundaries_intersecting(&geometry, mode, |mut boundaries| {
+            if let Some(levels) = levels {
+                boundaries.retain(|boundary| levels.contains(&boundary.admin_level));
+            }
+            boundaries.into()
+        })
+    })
+    .await
+    .unwrap()
+    .map_err(error::ErrorInternalServerError)
+}
+
+#[get("/boundaries")]
+pub async fn boundaries_in_bbox(
+    query: web::Query<RegionQuery>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder> {
+    let bbox = query
+        .bbox
+        .as_deref()
+        .ok_or_else(|| error::ErrorBadRequest("missing bbox"))?;
+    let bbox = parse_bbox(bbox).map_err(error::ErrorBadRequest)?;
+    let response = region(bbox.to_polygon().into(), &query, state).await?;
+    Ok(web::Json(response))
+}
+
+/// Boundaries relating to a GeoJSON geometry, feature or feature collection.
+#[post("/intersect")]
+pub async fn intersect(
+    query: web::Query<RegionQuery>,
+    body: web::Json<GeoJson>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder> {
+    let geometry = Geometry::try_from(body.into_inner()).map_err(error::ErrorBadRequest)?;
+    let response = region(geometry, &query, state).await?;
+    Ok(web::Json(response))
+}
+
+/// Share of a GeoJSON polygon falling into each intersecting boundary.
+#[post("/overlap")]
+pub async fn overlap_fractions(
+    query: web::Query<RegionQuery>,
+    body: web::Json<GeoJson>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder> {
+    let geometry = Geometry::try_from(body.into_inner()).map_err(error::ErrorBadRequest)?;
+    let mp = to_multi_polygon(geometry)
+        .ok_or_else(|| error::ErrorBadRequest("expected a polygon or multipolygon"))?;
+    let levels = query.levels()?;
+    let area_km2 = geodesic_area_km2(&mp);
+    let overlaps = task::spawn_blocking(move || {
+        state.overlaps(&mp, |overlaps| {
+            let overlaps = overlaps.into_iter().filter(|overlap| {
+                let level = overlap.boundary.admin_level;
+                levels.as_ref().is_none_or(|levels| levels.contains(&level))
+            });
+            overlaps.map(OverlapEntry::from).collect()
+        })
+    })
+    .await
+    .unwrap()
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(web::Json(OverlapResponse { area_km2, overlaps }))
+}
+
+/// A route as GeoJSON LineString, or as encoded polyline.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RouteBody {
+    Polyline {
+        polyline: String,
+        precision: Option<u32>,
+    },
+    GeoJson(GeoJson),
+}
+
+impl TryFrom<RouteBody> for LineString<f64> {
+    type Error = String;
+
+    fn try_from(body: RouteBody) -> std::result::Result<Self, Self::Error> {
+        match body {
+            RouteBody::Polyline {
+                polyline,
+                precision,
+            } => decode_polyline(&polyline, precision.unwrap_or(DEFAULT_PRECISION)),
+            RouteBody::GeoJson(geojson) => match Geometry::try_from(geojson) {
+                Ok(Geometry::LineString(line)) => Ok(line),
+                Ok(_) => Err("expected a linestring".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RouteResponse {
+    /// length of the route in metres
+    pub distance_m: f64,
+    pub levels: Vec<LevelResponse>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct LevelResponse {
+    pub level: u8,
+    pub visits: Vec<VisitResponse>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VisitResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub name: String,
+    /// border crossing into the boundary, absent if the route starts inside
+    pub entry: Option<[f64; 2]>,
+    /// border crossing out of the boundary, absent if the route ends inside
+    pub exit: Option<[f64; 2]>,
+    /// distance along the route where the visit starts, in metres
+    pub start_m: f64,
+    /// distance travelled inside the boundary in metres
+    pub distance_m: f64,
+}
+
+impl From<&Visit<'_>> for VisitResponse {
+    fn from(visit: &Visit) -> Self {
+        VisitResponse {
+            id: visit.boundary.osm_id,
+            name: visit.boundary.name.clone(),
+            entry: visit.entry.map(Into::into),
+            exit: visit.exit.map(Into::into),
+            start_m: visit.start,
+            distance_m: visit.distance(),
+        }
+    }
+}
+
+impl From<LevelCrossings<'_>> for LevelResponse {
+    fn from(crossings: LevelCrossings) -> Self {
+        LevelResponse {
+            level: crossings.level,
+            visits: crossings.visits.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Boundaries a route passes through per admin level, with border
+/// crossings and distances.
+#[post("/route")]
+pub async fn route_crossings(
+    query: web::Query<RegionQuery>,
+    body: web::Json<RouteBody>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder> {
+    let line = LineString::try_from(body.into_inner()).map_err(error::ErrorBadRequest)?;
+    let levels = query.levels()?;
+    let distance_m = length(&line);
+    let levels = task::spawn_blocking(move || {
+        state.crossings(&line, |crossings| {
+            let crossings = crossings.into_iter().filter(|crossings| {
+                let level = crossings.level;
+                levels.as_ref().is_none_or(|levels| levels.contains(&level))
+            });
+            crossings.map(LevelResponse::from).collect()
+        })
+    })
+    .await
+    .unwrap()
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(web::Json(RouteResponse { distance_m, levels }))
+}
+
+#[get("/boundaries/{id}")]
+pub async fn boundary_by_id(
+    id: web::Path<i64>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder> {
+    let id = id.into_inner();
+    let details: Option<BoundaryDetails> =
+        task::spawn_blocking(move || state.find(id, |boundary| boundary.map(Into::into)))
+            .await
+            .unwrap()
+            .map_err(error::ErrorInternalServerError)?;
+    match details {
+        Some(details) => Ok(web::Json(details)),
+        None => Err(error::ErrorNotFound(format!("no boundary {}", id))),
+    }
+}
+
 #[get("/health")]
 async fn health() -> &'static str {
     "Ok"
@@ -96,9 +393,11 @@ async fn metrics() -> String {
 fn track_metrics(code: u16, method: &str, route: &str, time: f64) {
     // fn track_metrics(code: u16, method: &str, route: &str) {
     // dos protection
-    if route != "/locate" && route != "/health" {
-        return;
-    }
+    let route = match route {
+        "/locate" | "/health" | "/boundaries" | "/intersect" | "/overlap" | "/route" => route,
+        _ if route.starts_with("/boundaries/") => "/boundaries/{id}",
+        _ => return,
+    };
 
     let normalized_code = match code {
         200..=299 => "2XX",
@@ -128,13 +427,18 @@ fn init_logging() {
         .init();
 }
 
-pub async fn start(rtree: RTree, port: u16) -> std::result::Result<(), Box<dyn Error>> {
+pub async fn start(
+    tree: Tree,
+    port: u16,
+    json_limit: usize,
+) -> std::result::Result<(), Box<dyn Error>> {
     init_logging();
-    let state = Arc::new(rtree);
+    let state = Arc::new(tree);
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(state.clone()))
+            .app_data(web::JsonConfig::default().limit(json_limit))
             .wrap(TracingLogger::default())
             .wrap_fn(|req, srv| {
                 let path = String::from(req.path());
@@ -151,6 +455,11 @@ pub async fn start(rtree: RTree, port: u16) -> std::result::Result<(), Box<dyn E
             })
             .service(health)
             .service(locate)
+            .service(boundaries_in_bbox)
+            .service(intersect)
+            .service(overlap_fractions)
+            .service(route_crossings)
+            .service(boundary_by_id)
             .service(metrics)
     })
     .bind(("127.0.0.1", port))?
@@ -0,0 +1,343 @@
+use crate::boundary::Boundary;
+use crate::{read_header, write_header, IdTree, Metadata, RTree};
+use lru::LruCache;
+use memmap2::Mmap;
+use rstar::{Envelope, RTreeObject, AABB};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Leading bytes of sharded rtree binaries, followed by the format version
+/// and the length of the manifest.
+pub const MAGIC: &[u8; 8] = b"OALSHARD";
+
+/// Bytes per entry of the id table, an osm id and the index of its shard.
+const ID_ENTRY_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardEntry {
+    /// envelope of all boundaries in the shard
+    pub envelope: AABB<[f64; 2]>,
+    /// position of the serialized tree, relative to the end of the id table
+    pub offset: u64,
+    pub len: u64,
+    pub boundaries: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub metadata: Metadata,
+    pub shards: Vec<ShardEntry>,
+    /// entries of the id table following the manifest, sorted by osm id
+    pub ids: u64,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("could not deserialize sharded rtree binary: {}", e),
+    )
+}
+
+/// Write boundaries as a sharded binary: the manifest, a table of the osm
+/// ids and their shards, and one tree per grid tile of `tile_size` degrees.
+/// Boundaries are assigned to the tile holding the centre of their
+/// envelope.
+pub fn write_sharded(
+    path: &Path,
+    metadata: Metadata,
+    boundaries: Vec<Boundary>,
+    tile_size: f64,
+) -> Result<Manifest, Box<dyn Error>> {
+    let mut tiles: BTreeMap<(i32, i32), Vec<Boundary>> = BTreeMap::new();
+    for boundary in boundaries {
+        let [x, y] = boundary.envelope().center();
+        let tile = (
+            (x / tile_size).floor() as i32,
+            (y / tile_size).floor() as i32,
+        );
+        tiles.entry(tile).or_default().push(boundary);
+    }
+
+    let mut shards = vec![];
+    let mut ids: Vec<(i64, u32)> = vec![];
+    let mut blobs = vec![];
+    let mut offset = 0;
+    for boundaries in tiles.into_values() {
+        let shard = u32::try_from(shards.len())?;
+        ids.extend(boundaries.iter().filter_map(|b| Some((b.osm_id?, shard))));
+        let count = boundaries.len();
+        let tree = RTree::bulk_load(boundaries);
+        let blob = bincode::serialize(&tree)?;
+        shards.push(ShardEntry {
+            envelope: tree.root().envelope(),
+            offset,
+            len: blob.len() as u64,
+            boundaries: count,
+        });
+        offset += blob.len() as u64;
+        blobs.push(blob);
+    }
+    ids.sort_unstable();
+
+    let manifest = Manifest {
+        metadata,
+        shards,
+        ids: ids.len() as u64,
+    };
+    let encoded = bincode::serialize(&manifest)?;
+    let mut file = BufWriter::new(File::create(path)?);
+    write_header(&mut file, MAGIC)?;
+    file.write_all(&(encoded.len() as u64).to_le_bytes())?;
+    file.write_all(&encoded)?;
+    for (id, shard) in ids {
+        file.write_all(&id.to_le_bytes())?;
+        file.write_all(&shard.to_le_bytes())?;
+    }
+    for blob in blobs {
+        file.write_all(&blob)?;
+    }
+    file.flush()?;
+    Ok(manifest)
+}
+
+/// Check whether the file at `path` is a sharded binary.
+pub fn is_sharded(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 8];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A sharded binary of which only the manifest is read upfront. Shards are
+/// loaded when a query first touches them, at most `max_shards` are kept in
+/// memory, the least recently used are dropped first.
+pub struct ShardedTree {
+    pub manifest: Manifest,
+    map: Mmap,
+    /// position of the id table, which is searched in the mapped file
+    ids: usize,
+    start: usize,
+    cache: Mutex<LruCache<usize, Arc<IdTree>>>,
+    /// held while a shard is read, the cache is only locked to look up
+    /// and insert shards
+    loading: Vec<Mutex<()>>,
+}
+
+impl ShardedTree {
+    pub fn open(path: &Path, max_shards: usize) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        read_header(&map[..], MAGIC)?;
+        let len = map
+            .get(12..20)
+            .and_then(|bytes| <[u8; 8]>::try_from(bytes).ok())
+            .map(u64::from_le_bytes)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(|| invalid_data("truncated header"))?;
+        let ids = 20 + len;
+        let encoded = map
+            .get(20..ids)
+            .ok_or_else(|| invalid_data("truncated manifest"))?;
+        let manifest: Manifest = bincode::deserialize(encoded).map_err(invalid_data)?;
+        let start = usize::try_from(manifest.ids)
+            .ok()
+            .and_then(|entries| entries.checked_mul(ID_ENTRY_LEN))
+            .and_then(|len| len.checked_add(ids))
+            .filter(|&start| start <= map.len())
+            .ok_or_else(|| invalid_data("truncated id table"))?;
+        let max_shards = NonZeroUsize::new(max_shards).unwrap_or(NonZeroUsize::MIN);
+        let loading = manifest.shards.iter().map(|_| Mutex::new(())).collect();
+        Ok(ShardedTree {
+            manifest,
+            map,
+            ids,
+            start,
+            cache: Mutex::new(LruCache::new(max_shards)),
+            loading,
+        })
+    }
+
+    fn read(&self, i: usize) -> io::Result<RTree> {
+        let shard = &self.manifest.shards[i];
+        let range = usize::try_from(shard.offset)
+            .ok()
+            .zip(usize::try_from(shard.len).ok())
+            .map(|(offset, len)| self.start + offset..self.start + offset + len);
+        let encoded = range
+            .and_then(|range| self.map.get(range))
+            .ok_or_else(|| invalid_data("truncated shard"))?;
+        bincode::deserialize(encoded).map_err(invalid_data)
+    }
+
+    fn cached(&self, i: usize) -> Option<Arc<IdTree>> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.get(&i).cloned()
+    }
+
+    /// Shard `i` from the cache, or read while lookups of other shards go
+    /// on. Concurrent loads of the same shard wait for the first one.
+    fn load(&self, i: usize) -> io::Result<Arc<IdTree>> {
+        if let Some(tree) = self.cached(i) {
+            return Ok(tree);
+        }
+        let _loading = self.loading[i].lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tree) = self.cached(i) {
+            return Ok(tree);
+        }
+        let tree = Arc::new(IdTree::from(self.read(i)?));
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.put(i, tree.clone());
+        Ok(tree)
+    }
+
+    /// Read all shards into a single tree, bypassing the cache.
+    pub fn load_all(&self) -> io::Result<RTree> {
+        let mut boundaries = vec![];
+        for i in 0..self.manifest.shards.len() {
+            boundaries.extend(self.read(i)?);
+        }
+        Ok(RTree::bulk_load(boundaries))
+    }
+
+    /// Shards with boundaries intersecting `envelope`, loaded if necessary.
+    pub fn trees(&self, envelope: &AABB<[f64; 2]>) -> io::Result<Vec<Arc<IdTree>>> {
+        let shards = self.manifest.shards.iter().enumerate();
+        shards
+            .filter(|(_, shard)| shard.envelope.intersects(envelope))
+            .map(|(i, _)| self.load(i))
+            .collect()
+    }
+
+    /// Entry `i` of the id table, an osm id and the index of its shard.
+    fn id_entry(&self, i: usize) -> (i64, usize) {
+        let pos = self.ids + i * ID_ENTRY_LEN;
+        let mut id = [0; 8];
+        let mut shard = [0; 4];
+        id.copy_from_slice(&self.map[pos..pos + 8]);
+        shard.copy_from_slice(&self.map[pos + 8..pos + ID_ENTRY_LEN]);
+        (i64::from_le_bytes(id), u32::from_le_bytes(shard) as usize)
+    }
+
+    /// The shard holding the boundary of osm relation `id`, if any, found by
+    /// a binary search of the id table.
+    pub fn find(&self, id: i64) -> io::Result<Option<Arc<IdTree>>> {
+        let (mut low, mut high) = (0, (self.start - self.ids) / ID_ENTRY_LEN);
+        while low < high {
+            let middle = (low + high) / 2;
+            let (entry, shard) = self.id_entry(middle);
+            match entry.cmp(&id) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal if shard < self.manifest.shards.len() => {
+                    return self.load(shard).map(Some)
+                }
+                Ordering::Equal => return Err(invalid_data("id table refers to a missing shard")),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Number of shards currently in memory.
+    pub fn loaded(&self) -> usize {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+
+    fn square(x: f64, y: f64, name: &str, id: i64) -> Boundary {
+        let polygon = polygon![
+            (x: x, y: y),
+            (x: x + 1., y: y),
+            (x: x + 1., y: y + 1.),
+            (x: x, y: y + 1.),
+        ];
+        let mut boundary = Boundary::new(polygon.into(), name, 8);
+        boundary.osm_id = Some(id);
+        boundary
+    }
+
+    #[test]
+    fn loads_shards_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sharded.bin");
+        let boundaries = vec![
+            square(0., 0., "a", 1),
+            square(0.5, 0.5, "b", 2),
+            square(20., 0., "c", 3),
+            square(40., 0., "d", 4),
+        ];
+        let manifest = write_sharded(&path, Metadata::default(), boundaries, 10.).unwrap();
+        assert_eq!(manifest.shards.len(), 3);
+        assert!(is_sharded(&path).unwrap());
+
+        let tree = ShardedTree::open(&path, 1).unwrap();
+        assert_eq!(tree.loaded(), 0);
+        let trees = tree.trees(&AABB::from_point([0.7, 0.7])).unwrap();
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].size(), 2);
+        assert_eq!(tree.loaded(), 1);
+
+        let trees = tree.trees(&AABB::from_point([20.5, 0.5])).unwrap();
+        let names: Vec<&str> = trees[0].iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["c"]);
+        assert_eq!(tree.loaded(), 1);
+
+        assert!(tree
+            .trees(&AABB::from_point([30., 0.5]))
+            .unwrap()
+            .is_empty());
+        assert_eq!(tree.load_all().unwrap().size(), 4);
+
+        assert_eq!(manifest.ids, 4);
+        for (id, name) in [(1, "a"), (3, "c"), (4, "d")] {
+            let found = tree.find(id).unwrap().unwrap();
+            assert_eq!(found.find(id).unwrap().name, name);
+        }
+        assert!(tree.find(0).unwrap().is_none());
+        assert!(tree.find(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_id_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sharded.bin");
+        let boundaries = (0..4).map(|i| square(i as f64 * 20., 0., "a", i)).collect();
+        write_sharded(&path, Metadata::default(), boundaries, 10.).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let len = u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[12..20]).unwrap()) as usize;
+        std::fs::write(&path, &bytes[..20 + len + ID_ENTRY_LEN]).unwrap();
+        let error = ShardedTree::open(&path, 1).err().unwrap();
+        assert!(error.to_string().contains("truncated id table"));
+    }
+
+    #[test]
+    fn loads_shards_once_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sharded.bin");
+        let boundaries = (0..4).map(|i| square(i as f64 * 20., 0., "a", i)).collect();
+        write_sharded(&path, Metadata::default(), boundaries, 10.).unwrap();
+        let tree = ShardedTree::open(&path, 4).unwrap();
+        let loaded: Vec<Arc<IdTree>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| tree.find(2).unwrap().unwrap()))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(loaded.iter().all(|shard| Arc::ptr_eq(shard, &loaded[0])));
+        assert_eq!(tree.loaded(), 1);
+    }
+}
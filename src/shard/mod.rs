@@ -0,0 +1,305 @@
+use crate::boundary::{assemble, get_admin, Boundary, BuildReport, OsmMap, MEMBER_ROLES};
+use crate::nodes::{NodeStore, NodeStoreKind};
+use indicatif::ProgressBar;
+use osmpbfreader::{Node, NodeId, OsmObj, OsmPbfReader, Relation, WayId};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::{remove_file, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+pub mod index;
+
+/// Size in degrees of the grid cells relations are sharded by.
+const REGION_SIZE: f64 = 30.;
+
+type Region = Option<(i32, i32)>;
+
+/// Relations on requested levels, and those without a valid level, which
+/// are reported as skipped.
+fn is_wanted(rel: &Relation, admin_levels: &[u8]) -> bool {
+    let admin_level = rel.tags.get("admin_level").and_then(|l| l.parse().ok());
+    admin_level.is_none_or(|level| admin_levels.contains(&level))
+}
+
+fn read_relations(
+    pbf: &mut OsmPbfReader<File>,
+    admin_levels: &[u8],
+) -> Result<Vec<Relation>, Box<dyn Error>> {
+    let mut relations = vec![];
+    for obj in pbf.par_iter() {
+        let obj = obj?;
+        if let Some(rel) = get_admin(&obj).filter(|rel| is_wanted(rel, admin_levels)) {
+            relations.push(rel.clone());
+        }
+    }
+    Ok(relations)
+}
+
+fn read_ways(
+    pbf: &mut OsmPbfReader<File>,
+    relations: &[Relation],
+) -> Result<HashMap<WayId, Vec<NodeId>>, Box<dyn Error>> {
+    let ids: HashSet<WayId> = relations
+        .iter()
+        .flat_map(|rel| rel.refs.iter().filter_map(|r| r.member.way()))
+        .collect();
+    let mut ways = HashMap::new();
+    pbf.rewind()?;
+    for obj in pbf.par_iter() {
+        if let OsmObj::Way(way) = obj? {
+            if ids.contains(&way.id) {
+                ways.insert(way.id, way.nodes);
+            }
+        }
+    }
+    Ok(ways)
+}
+
+/// Node members with roles like `admin_centre`, which are kept in memory
+/// with their tags.
+fn member_nodes(relations: &[Relation]) -> HashSet<NodeId> {
+    relations
+        .iter()
+        .flat_map(|rel| &rel.refs)
+        .filter(|r| MEMBER_ROLES.contains(&r.role.as_str()))
+        .filter_map(|r| r.member.node())
+        .collect()
+}
+
+fn read_nodes(
+    pbf: &mut OsmPbfReader<File>,
+    ways: &HashMap<WayId, Vec<NodeId>>,
+    members: &HashSet<NodeId>,
+    path: &Path,
+    kind: NodeStoreKind,
+) -> Result<(NodeStore, HashMap<NodeId, Node>), Box<dyn Error>> {
+    let mut ids: Vec<i64> = ways.values().flatten().map(|id| id.0).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let max_id = ids.last().copied().unwrap_or(0);
+    let mut store = NodeStore::create(path, kind, max_id)?;
+    let mut member_nodes = HashMap::new();
+    pbf.rewind()?;
+    for obj in pbf.par_iter() {
+        if let OsmObj::Node(node) = obj? {
+            if ids.binary_search(&node.id.0).is_ok() {
+                store.insert(&node)?;
+            }
+            if members.contains(&node.id) {
+                member_nodes.insert(node.id, node);
+            }
+        }
+    }
+    store.finish()?;
+    Ok((store, member_nodes))
+}
+
+/// Objects a single relation is assembled from.
+fn relation_objects(
+    rel: &Relation,
+    ways: &HashMap<WayId, Vec<NodeId>>,
+    nodes: &NodeStore,
+    member_nodes: &HashMap<NodeId, Node>,
+) -> OsmMap {
+    let mut objects = OsmMap::new();
+    objects.insert(rel.id.into(), rel.clone().into());
+    for node in rel
+        .refs
+        .iter()
+        .filter_map(|r| member_nodes.get(&r.member.node()?))
+    {
+        objects.insert(node.id.into(), node.clone().into());
+    }
+    for id in rel.refs.iter().filter_map(|r| r.member.way()) {
+        let way_nodes = match ways.get(&id) {
+            Some(way_nodes) => way_nodes,
+            None => continue,
+        };
+        for node in way_nodes.iter().filter_map(|id| nodes.get(*id)) {
+            objects.insert(node.id.into(), node.into());
+        }
+        let way = osmpbfreader::Way {
+            id,
+            tags: Default::default(),
+            nodes: way_nodes.clone(),
+        };
+        objects.insert(id.into(), way.into());
+    }
+    objects
+}
+
+/// Grid cell of the first located node of a relation.
+fn region(rel: &Relation, ways: &HashMap<WayId, Vec<NodeId>>, nodes: &NodeStore) -> Region {
+    let node = rel
+        .refs
+        .iter()
+        .filter_map(|r| ways.get(&r.member.way()?))
+        .flatten()
+        .find_map(|id| nodes.get(*id))?;
+    let cell = |degrees: f64| (degrees / REGION_SIZE).floor() as i32;
+    Some((cell(node.lon()), cell(node.lat())))
+}
+
+fn shard_path(dir: &Path, region: Region) -> PathBuf {
+    match region {
+        Some((x, y)) => dir.join(format!("shard_{}_{}.bin", x, y)),
+        None => dir.join("shard_unlocated.bin"),
+    }
+}
+
+/// Build boundaries from a PBF file with bounded memory and write them to
+/// per-region shard files in `dir`. The file is read in passes: admin
+/// relations, their member ways and finally the ways' node locations, which
+/// go to an on-disk node store. Relations are then assembled region by
+/// region, each region is written to its own shard.
+///
+/// Memory holds the admin relations, the node lists of their member ways,
+/// a sorted copy of those node ids while the nodes are read, and the
+/// boundaries of one region while it is assembled. Other ways and nodes are
+/// skipped, node locations are only held by the node store. All of it is
+/// dropped before the shards are merged by `merge_shards`, which holds the
+/// boundaries of every region, as the final rtree does.
+pub fn build_shards(
+    path: &Path,
+    dir: &Path,
+    kind: NodeStoreKind,
+    admin_levels: &[u8],
+    validate: bool,
+    report: &mut BuildReport,
+    progress: &ProgressBar,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut pbf = OsmPbfReader::new(File::open(path)?);
+    let relations = read_relations(&mut pbf, admin_levels)?;
+    let ways = read_ways(&mut pbf, &relations)?;
+    let nodes_path = dir.join("nodes.bin");
+    let members = member_nodes(&relations);
+    let (nodes, member_nodes) = read_nodes(&mut pbf, &ways, &members, &nodes_path, kind)?;
+
+    let mut regions: BTreeMap<Region, Vec<&Relation>> = BTreeMap::new();
+    for rel in &relations {
+        let region = region(rel, &ways, &nodes);
+        regions.entry(region).or_default().push(rel);
+    }
+
+    progress.set_length(relations.len() as u64);
+    let mut paths = vec![];
+    for (region, relations) in regions {
+        let results: Vec<_> = relations
+            .into_par_iter()
+            .filter_map(|rel| {
+                let objects = relation_objects(rel, &ways, &nodes, &member_nodes);
+                let result = assemble(rel, &objects, admin_levels, validate);
+                progress.inc(1);
+                Some((rel.id.0, result?))
+            })
+            .collect();
+        let boundaries: Vec<Boundary> = results
+            .into_iter()
+            .filter_map(|(id, result)| report.add(id, result))
+            .collect();
+
+        let path = shard_path(dir, region);
+        bincode::serialize_into(BufWriter::new(File::create(&path)?), &boundaries)?;
+        paths.push(path);
+    }
+    progress.finish_and_clear();
+
+    drop(nodes);
+    remove_file(nodes_path)?;
+    Ok(paths)
+}
+
+/// Read the boundaries of shard files written by `build_shards`, all of
+/// them are held in memory.
+pub fn merge_shards(paths: &[PathBuf]) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let mut boundaries = vec![];
+    for path in paths {
+        let file = BufReader::new(File::open(path)?);
+        let shard: Vec<Boundary> = bincode::deserialize_from(file)?;
+        boundaries.extend(shard);
+    }
+    Ok(boundaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boundary::get_osm_boundaries;
+
+    #[test]
+    fn builds_same_boundaries_as_in_memory() {
+        let path = Path::new("./tests/data/schwachhausen.pbf");
+        let expected =
+            get_osm_boundaries(path.into(), &[10], false, &mut BuildReport::default()).unwrap();
+        for kind in &[NodeStoreKind::Dense, NodeStoreKind::Sparse] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut report = BuildReport::default();
+            let progress = ProgressBar::hidden();
+            let paths = build_shards(
+                path,
+                dir.path(),
+                *kind,
+                &[10],
+                false,
+                &mut report,
+                &progress,
+            )
+            .unwrap();
+            assert_eq!(paths.len(), 1);
+            assert!(!dir.path().join("nodes.bin").exists());
+
+            let boundaries = merge_shards(&paths).unwrap();
+            assert_eq!(boundaries.len(), expected.len());
+            assert_eq!(boundaries[0].name, expected[0].name);
+            assert_eq!(boundaries[0].mp, expected[0].mp);
+            assert_eq!(boundaries[0].admin_centre, expected[0].admin_centre);
+            assert_eq!(boundaries[0].label, expected[0].label);
+            assert_eq!(report.levels[&10].built, 1);
+        }
+    }
+
+    #[test]
+    fn keeps_only_member_ways_and_their_nodes() {
+        let path = Path::new("./tests/data/schwachhausen.pbf");
+        let mut pbf = OsmPbfReader::new(File::open(path).unwrap());
+        let relations = read_relations(&mut pbf, &[10]).unwrap();
+        let ways = read_ways(&mut pbf, &relations).unwrap();
+        let members: HashSet<WayId> = relations
+            .iter()
+            .flat_map(|rel| rel.refs.iter().filter_map(|r| r.member.way()))
+            .collect();
+        assert!(ways.keys().all(|id| members.contains(id)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let nodes_path = dir.path().join("nodes.bin");
+        let (nodes, member_nodes) = read_nodes(
+            &mut pbf,
+            &ways,
+            &HashSet::new(),
+            &nodes_path,
+            NodeStoreKind::Sparse,
+        )
+        .unwrap();
+        assert!(member_nodes.is_empty());
+        let way_nodes: HashSet<NodeId> = ways.values().flatten().copied().collect();
+        let (mut stored, mut skipped, mut other_ways) = (0, 0, 0);
+        pbf.rewind().unwrap();
+        for obj in pbf.iter() {
+            match obj.unwrap() {
+                OsmObj::Node(node) if way_nodes.contains(&node.id) => {
+                    assert!(nodes.get(node.id).is_some());
+                    stored += 1;
+                }
+                OsmObj::Node(node) => {
+                    assert!(nodes.get(node.id).is_none());
+                    skipped += 1;
+                }
+                OsmObj::Way(way) if !ways.contains_key(&way.id) => other_ways += 1,
+                _ => {}
+            }
+        }
+        assert!(stored > 0 && skipped > 0 && other_ways > 0);
+    }
+}
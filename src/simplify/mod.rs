@@ -0,0 +1,516 @@
+use crate::boundary::Boundary;
+use geo::algorithm::coords_iter::CoordsIter;
+use geo::algorithm::line_intersection::{line_intersection, LineIntersection};
+use geo::algorithm::simplify::SimplifyIdx;
+use geo_types::{Coord, Line, LineString, MultiPolygon, Polygon};
+use rstar::primitives::{GeomWithData, Line as Segment};
+use rstar::{RTree, RTreeObject};
+use std::collections::{HashMap, HashSet};
+
+const METRES_PER_DEGREE: f64 = 111_320.;
+
+/// Arcs crossing others are simplified again with half the tolerance, at
+/// most this many times before their original points are kept.
+const MAX_ROUNDS: u32 = 4;
+
+type Key = (u64, u64);
+
+fn key(coord: &Coord<f64>) -> Key {
+    (coord.x.to_bits(), coord.y.to_bits())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SimplifyStats {
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+    /// arcs simplified with a lower tolerance, or kept, to avoid crossings
+    /// and collapsed rings
+    pub reduced_arcs: usize,
+}
+
+/// Simplify boundary geometries with a tolerance in metres. Rings are split
+/// into arcs at junctions, points where rings of different boundaries meet
+/// or part, and every arc is simplified once. Borders shared between
+/// boundaries therefore stay identical and neither gaps nor overlaps appear.
+/// Arcs crossing other arcs or themselves, and the arcs of rings collapsing
+/// below the tolerance, are simplified with a lower tolerance on all sides
+/// until the result is valid, eventually keeping their original points.
+pub fn simplify_boundaries(boundaries: &mut [Boundary], tolerance: f64) -> SimplifyStats {
+    simplify_in_context(boundaries, &[], tolerance)
+}
+
+/// Simplify `boundaries` like `simplify_boundaries`, next to boundaries
+/// which have been simplified before. `context` holds their original and
+/// their simplified geometry: the original rings contribute junctions and
+/// arcs shared with them keep the points of the simplified geometry.
+pub fn simplify_in_context(
+    boundaries: &mut [Boundary],
+    context: &[(Boundary, &Boundary)],
+    tolerance: f64,
+) -> SimplifyStats {
+    let mut stats = SimplifyStats::default();
+    let originals = context.iter().map(|(original, _)| original);
+    let junctions = find_junctions(boundaries.iter().chain(originals));
+    let mut arcs = Arcs::default();
+
+    // rings of every polygon of every boundary, exterior first, as arcs
+    let rings: Vec<Vec<Vec<Option<Ring>>>> = boundaries
+        .iter()
+        .map(|boundary| {
+            stats.vertices_before += boundary.mp.coords_count();
+            boundary
+                .mp
+                .0
+                .iter()
+                .map(|polygon| {
+                    polygon_rings(polygon)
+                        .map(|ring| arcs.split(ring, &junctions))
+                        .collect()
+                })
+                .collect()
+        })
+        .collect();
+    for (original, simplified) in context {
+        let kept: HashSet<Key> = simplified.mp.coords_iter().map(|c| key(&c)).collect();
+        for polygon in &original.mp.0 {
+            for ring in polygon_rings(polygon) {
+                arcs.pin(ring, &junctions, &kept);
+            }
+        }
+    }
+
+    let all_rings: Vec<&Ring> = rings.iter().flatten().flatten().flatten().collect();
+    let mut round = 0;
+    loop {
+        arcs.simplify(tolerance);
+        let offending = arcs.offending(&all_rings);
+        if offending.is_empty() || round == MAX_ROUNDS {
+            break;
+        }
+        arcs.reduce(offending);
+        round += 1;
+    }
+    stats.reduced_arcs = arcs.reduced();
+
+    for (boundary, polygons) in boundaries.iter_mut().zip(&rings) {
+        let polygons = boundary
+            .mp
+            .0
+            .iter()
+            .zip(polygons)
+            .map(|(polygon, rings)| {
+                let mut rings =
+                    polygon_rings(polygon)
+                        .zip(rings)
+                        .map(|(ring, arcs_of)| match arcs_of {
+                            Some(arcs_of) => arcs.ring(arcs_of).unwrap_or_else(|| ring.clone()),
+                            None => ring.clone(),
+                        });
+                let exterior = rings.next().unwrap_or_else(|| polygon.exterior().clone());
+                Polygon::new(exterior, rings.collect())
+            })
+            .collect();
+        boundary.set_geometry(MultiPolygon(polygons));
+        stats.vertices_after += boundary.mp.coords_count();
+    }
+    stats
+}
+
+fn polygon_rings(polygon: &Polygon<f64>) -> impl Iterator<Item = &LineString<f64>> {
+    std::iter::once(polygon.exterior()).chain(polygon.interiors())
+}
+
+/// Points of a closed ring without the closing point.
+fn ring_points(ring: &LineString<f64>) -> &[Coord<f64>] {
+    match ring.0.split_last() {
+        Some((_, points)) if ring.is_closed() && points.len() >= 3 => points,
+        _ => &[],
+    }
+}
+
+/// A point is a junction if it has different neighbours in different rings,
+/// or occurs more than once in a ring with different neighbours.
+fn find_junctions<'a>(boundaries: impl Iterator<Item = &'a Boundary>) -> HashSet<Key> {
+    let mut neighbours: HashMap<Key, (Key, Key)> = HashMap::new();
+    let mut junctions = HashSet::new();
+    let rings = boundaries
+        .flat_map(|boundary| boundary.mp.0.iter())
+        .flat_map(polygon_rings);
+    for ring in rings {
+        let points = ring_points(ring);
+        let len = points.len();
+        for (i, point) in points.iter().enumerate() {
+            let prev = key(&points[(i + len - 1) % len]);
+            let next = key(&points[(i + 1) % len]);
+            let pair = (prev.min(next), prev.max(next));
+            let seen = neighbours.entry(key(point)).or_insert(pair);
+            if *seen != pair {
+                junctions.insert(key(point));
+            }
+        }
+    }
+    junctions
+}
+
+fn farthest_from(points: &[Coord<f64>], from: usize) -> usize {
+    let origin = points[from];
+    let distance = |coord: &Coord<f64>| (coord.x - origin.x).powi(2) + (coord.y - origin.y).powi(2);
+    (0..points.len())
+        .max_by(|a, b| distance(&points[*a]).total_cmp(&distance(&points[*b])))
+        .unwrap_or(from)
+}
+
+/// Split a ring into arcs at junctions, in ring direction. Rings without
+/// junctions are split at a position independent of the ring's start and
+/// direction, so equal rings yield equal arcs.
+fn split_ring(ring: &LineString<f64>, junctions: &HashSet<Key>) -> Vec<Vec<Coord<f64>>> {
+    let points = ring_points(ring);
+    if points.is_empty() {
+        return vec![];
+    }
+    let mut splits: Vec<usize> = (0..points.len())
+        .filter(|i| junctions.contains(&key(&points[*i])))
+        .collect();
+    if splits.is_empty() {
+        let start = (0..points.len())
+            .min_by_key(|i| key(&points[*i]))
+            .unwrap_or(0);
+        splits.push(start);
+    }
+    if splits.len() == 1 {
+        splits.push(farthest_from(points, splits[0]));
+    }
+    splits.sort_unstable();
+    splits.dedup();
+
+    splits
+        .iter()
+        .enumerate()
+        .map(|(i, from)| {
+            let to = splits[(i + 1) % splits.len()];
+            if to > *from {
+                points[*from..=to].to_vec()
+            } else {
+                points[*from..]
+                    .iter()
+                    .chain(&points[..=to])
+                    .copied()
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// A segment of a simplified arc, with the arc and its position in it.
+type ArcSegment = GeomWithData<Segment<[f64; 2]>, (usize, usize)>;
+
+/// A ring as arcs, each reversed or not.
+type Ring = Vec<(usize, bool)>;
+
+struct TopoArc {
+    points: Vec<Coord<f64>>,
+    /// halvings of the tolerance, the original points are kept after
+    /// `MAX_ROUNDS`
+    reductions: u32,
+    simplified: Option<Vec<Coord<f64>>>,
+    pinned: bool,
+}
+
+/// Arcs of all rings, stored once in canonical direction, so an arc shared
+/// by two rings in opposite directions is simplified identically.
+#[derive(Default)]
+struct Arcs {
+    arcs: Vec<TopoArc>,
+    index: HashMap<Vec<Key>, usize>,
+}
+
+impl Arcs {
+    fn insert(&mut self, mut points: Vec<Coord<f64>>) -> (usize, bool) {
+        let keys: Vec<Key> = points.iter().map(key).collect();
+        let reversed_keys: Vec<Key> = keys.iter().rev().copied().collect();
+        let reversed = reversed_keys < keys;
+        let keys = if reversed {
+            points.reverse();
+            reversed_keys
+        } else {
+            keys
+        };
+        let arcs = &mut self.arcs;
+        let i = *self.index.entry(keys).or_insert_with(|| {
+            arcs.push(TopoArc {
+                points,
+                reductions: 0,
+                simplified: None,
+                pinned: false,
+            });
+            arcs.len() - 1
+        });
+        (i, reversed)
+    }
+
+    /// The arcs of a ring, `None` for rings which are not simplified.
+    fn split(&mut self, ring: &LineString<f64>, junctions: &HashSet<Key>) -> Option<Ring> {
+        let arcs = split_ring(ring, junctions);
+        if arcs.is_empty() {
+            return None;
+        }
+        Some(arcs.into_iter().map(|arc| self.insert(arc)).collect())
+    }
+
+    /// Keep the `kept` points of arcs shared with a ring simplified before.
+    fn pin(&mut self, ring: &LineString<f64>, junctions: &HashSet<Key>, kept: &HashSet<Key>) {
+        for arc in split_ring(ring, junctions) {
+            let keys: Vec<Key> = arc.iter().map(key).collect();
+            let reversed: Vec<Key> = keys.iter().rev().copied().collect();
+            let i = match self.index.get(&keys.min(reversed)) {
+                Some(i) => *i,
+                None => continue,
+            };
+            let arc = &mut self.arcs[i];
+            let last = arc.points.len() - 1;
+            let points = arc
+                .points
+                .iter()
+                .enumerate()
+                .filter(|(i, point)| *i == 0 || *i == last || kept.contains(&key(point)))
+                .map(|(_, point)| *point)
+                .collect();
+            arc.simplified = Some(points);
+            arc.pinned = true;
+        }
+    }
+
+    /// Simplify arcs which are not simplified yet, with the tolerance of
+    /// their reductions.
+    fn simplify(&mut self, tolerance: f64) {
+        for arc in self.arcs.iter_mut().filter(|arc| arc.simplified.is_none()) {
+            let tolerance = tolerance / 2f64.powi(arc.reductions as i32);
+            arc.simplified = Some(match arc.reductions < MAX_ROUNDS {
+                true => simplify_arc(&arc.points, tolerance),
+                false => arc.points.clone(),
+            });
+        }
+    }
+
+    fn simplified(&self, i: usize) -> &[Coord<f64>] {
+        self.arcs[i].simplified.as_deref().unwrap_or_default()
+    }
+
+    /// Points of a simplified ring, `None` if it collapsed.
+    fn ring(&self, ring: &Ring) -> Option<LineString<f64>> {
+        let mut coords = vec![];
+        for (i, reversed) in ring {
+            let arc = self.simplified(*i);
+            match reversed {
+                true => coords.extend(arc.iter().rev().skip(1)),
+                false => coords.extend(&arc[1..]),
+            }
+        }
+        coords.insert(0, *coords.last()?);
+        (coords.len() >= 4).then_some(LineString(coords))
+    }
+
+    /// Arcs of collapsed rings and arcs crossing other arcs or themselves,
+    /// which can still be reduced.
+    fn offending(&self, rings: &[&Ring]) -> HashSet<usize> {
+        let mut offending = HashSet::new();
+        for ring in rings {
+            if self.ring(ring).is_none() {
+                offending.extend(ring.iter().map(|(i, _)| *i));
+            }
+        }
+
+        let segments: Vec<ArcSegment> = (0..self.arcs.len())
+            .flat_map(|i| {
+                self.simplified(i)
+                    .windows(2)
+                    .enumerate()
+                    .map(move |(j, pair)| {
+                        let line = Segment::new(pair[0].into(), pair[1].into());
+                        GeomWithData::new(line, (i, j))
+                    })
+            })
+            .collect();
+        let tree = RTree::bulk_load(segments);
+        for segment in tree.iter() {
+            let candidates = tree.locate_in_envelope_intersecting(&segment.envelope());
+            for other in candidates.filter(|other| other.data > segment.data) {
+                if crosses(segment.geom(), other.geom()) {
+                    offending.insert(segment.data.0);
+                    offending.insert(other.data.0);
+                }
+            }
+        }
+        offending.retain(|i| {
+            let arc = &self.arcs[*i];
+            !arc.pinned && arc.reductions < MAX_ROUNDS
+        });
+        offending
+    }
+
+    fn reduce(&mut self, offending: HashSet<usize>) {
+        for i in offending {
+            let arc = &mut self.arcs[i];
+            arc.reductions += 1;
+            arc.simplified = None;
+        }
+    }
+
+    fn reduced(&self) -> usize {
+        self.arcs.iter().filter(|arc| arc.reductions > 0).count()
+    }
+}
+
+/// Whether two segments intersect other than in a common end point.
+fn crosses(a: &Segment<[f64; 2]>, b: &Segment<[f64; 2]>) -> bool {
+    let line = |segment: &Segment<[f64; 2]>| Line::new(segment.from, segment.to);
+    let (a, b) = (line(a), line(b));
+    let is_end = |line: &Line<f64>, point: Coord<f64>| line.start == point || line.end == point;
+    match line_intersection(a, b) {
+        None => false,
+        Some(LineIntersection::SinglePoint {
+            intersection,
+            is_proper,
+        }) => is_proper || !(is_end(&a, intersection) && is_end(&b, intersection)),
+        Some(LineIntersection::Collinear { intersection }) => {
+            intersection.start != intersection.end
+        }
+    }
+}
+
+/// Simplify an arc given in canonical direction.
+fn simplify_arc(arc: &[Coord<f64>], tolerance: f64) -> Vec<Coord<f64>> {
+    // scale longitudes, so the tolerance applies in metres on both axes
+    let scale = arc[0].y.to_radians().cos();
+    let scaled: LineString<f64> = arc
+        .iter()
+        .map(|coord| Coord {
+            x: coord.x * scale,
+            y: coord.y,
+        })
+        .collect();
+    let epsilon = tolerance / METRES_PER_DEGREE;
+    scaled
+        .simplify_idx(&epsilon)
+        .into_iter()
+        .map(|i| arc[i])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Intersects;
+
+    fn boundary(coords: Vec<(f64, f64)>, name: &str) -> Boundary {
+        let polygon = Polygon::new(LineString::from(coords), vec![]);
+        Boundary::new(polygon.into(), name, 8)
+    }
+
+    fn border(boundary: &Boundary) -> Vec<Key> {
+        let mut border: Vec<Key> = boundary
+            .mp
+            .coords_iter()
+            .filter(|coord| (coord.x - 0.01).abs() < 1e-3)
+            .map(|coord| key(&coord))
+            .collect();
+        border.sort_unstable();
+        border.dedup();
+        border
+    }
+
+    #[test]
+    fn keeps_shared_borders_consistent() {
+        // the border between both boundaries wiggles by ~10m
+        let wiggle = vec![
+            (0.01, 0.),
+            (0.0101, 0.002),
+            (0.0099, 0.005),
+            (0.01, 0.008),
+            (0.01, 0.01),
+        ];
+        let mut left = vec![(0., 0.)];
+        left.extend(wiggle.iter().copied());
+        left.extend(vec![(0., 0.01), (0., 0.)]);
+        let mut right = wiggle.clone();
+        right.reverse();
+        right.extend(vec![(0.02, 0.), (0.02, 0.01), (0.01, 0.01)]);
+
+        let mut boundaries = vec![boundary(left, "left"), boundary(right, "right")];
+        let stats = simplify_boundaries(&mut boundaries, 50.);
+        assert_eq!(stats.vertices_before, 16);
+        assert!(stats.vertices_after < stats.vertices_before);
+        assert_eq!(border(&boundaries[0]), border(&boundaries[1]));
+        assert_eq!(border(&boundaries[0]).len(), 2);
+    }
+
+    #[test]
+    fn keeps_small_rings() {
+        let mut boundaries = vec![boundary(
+            vec![(0., 0.), (0.0001, 0.), (0.0001, 0.0001), (0., 0.)],
+            "tiny",
+        )];
+        simplify_boundaries(&mut boundaries, 1000.);
+        assert_eq!(boundaries[0].mp.0[0].exterior().0.len(), 4);
+    }
+
+    #[test]
+    fn keeps_arcs_of_collapsed_rings_on_all_sides() {
+        // tiny sits on the north edge of big, their shared base bends by ~1m
+        let big = vec![
+            (0., 0.),
+            (0.02, 0.),
+            (0.02, 0.02),
+            (0.0101, 0.02),
+            (0.01005, 0.02001),
+            (0.01, 0.02),
+            (0., 0.02),
+            (0., 0.),
+        ];
+        let tiny = vec![
+            (0.01, 0.02),
+            (0.01005, 0.02001),
+            (0.0101, 0.02),
+            (0.01005, 0.02005),
+            (0.01, 0.02),
+        ];
+        let mut boundaries = vec![boundary(big, "big"), boundary(tiny, "tiny")];
+        let stats = simplify_boundaries(&mut boundaries, 50.);
+        assert!(stats.reduced_arcs > 0);
+        assert_eq!(boundaries[1].mp.0[0].exterior().0.len(), 5);
+        let bend = Coord {
+            x: 0.01005,
+            y: 0.02001,
+        };
+        assert!(boundaries[0].mp.coords_iter().any(|coord| coord == bend));
+    }
+
+    #[test]
+    fn avoids_crossing_arcs() {
+        // big has a ~20m dent in its north edge, island sits in the dent
+        let big = vec![
+            (0., 0.),
+            (0.01, 0.00001),
+            (0.02, 0.),
+            (0.02, 0.02),
+            (0.0102, 0.02),
+            (0.0102, 0.0198),
+            (0.0098, 0.0198),
+            (0.0098, 0.02),
+            (0., 0.02),
+            (0., 0.),
+        ];
+        let island = vec![
+            (0.0099, 0.0199),
+            (0.0101, 0.0199),
+            (0.0101, 0.02005),
+            (0.0099, 0.02005),
+            (0.0099, 0.0199),
+        ];
+        let mut boundaries = vec![boundary(big, "big"), boundary(island, "island")];
+        let stats = simplify_boundaries(&mut boundaries, 50.);
+        assert!(stats.reduced_arcs > 0);
+        assert!(stats.vertices_after < stats.vertices_before);
+        assert!(!boundaries[0].mp.intersects(&boundaries[1].mp));
+    }
+}
@@ -0,0 +1,58 @@
+use osm_admin_lookup::boundary::BuildReport;
+use osm_admin_lookup::update::{read_changes, update_index};
+use osm_admin_lookup::{load_index, write_index};
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "update-rtree",
+    about = "apply osm change files to rtree binary"
+)]
+struct Opt {
+    /// input bin path, built with --updatable
+    #[structopt(short = "b", long = "bin")]
+    bin_path: PathBuf,
+
+    /// osm change file (.osc or .osc.gz), repeat to apply several in order
+    #[structopt(short = "c", long = "osc", required = true)]
+    osc_paths: Vec<PathBuf>,
+
+    /// output bin path
+    #[structopt(short = "o", long = "out")]
+    out_path: PathBuf,
+
+    /// output path for a json report on re-assembled relations
+    #[structopt(short = "r", long = "report")]
+    report_path: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let mut index = load_index(&opt.bin_path)?;
+
+    let mut changes = vec![];
+    for path in &opt.osc_paths {
+        changes.extend(read_changes(path)?);
+    }
+    let mut report = BuildReport::default();
+    let stats = update_index(&mut index, changes, &mut report)?;
+    eprintln!(
+        "{} objects changed, {} relations re-assembled, {} boundaries removed, {} kept",
+        stats.changed, stats.reassembled, stats.removed, stats.kept
+    );
+    for (level, summary) in &report.levels {
+        eprintln!(
+            "level {}: {} built, {} skipped, {} repaired",
+            level, summary.built, summary.skipped, summary.repaired
+        );
+    }
+    if let Some(path) = opt.report_path {
+        serde_json::to_writer_pretty(File::create(path)?, &report)?;
+    }
+
+    write_index(&opt.out_path, &index)?;
+    Ok(())
+}
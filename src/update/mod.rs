@@ -0,0 +1,518 @@
+use crate::boundary::{assemble, get_admin, Boundary, BuildReport, OsmMap, MEMBER_ROLES};
+use crate::simplify::simplify_in_context;
+use crate::{Index, RTree};
+use osmpbfreader::{OsmId, OsmObj, Relation, RelationId, Tags};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+pub mod osc;
+pub use osc::{read_changes, Change};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UpdateStats {
+    /// changed objects the index is built from
+    pub changed: usize,
+    /// relations which have been re-assembled
+    pub reassembled: usize,
+    /// boundaries removed from the tree, including replaced ones
+    pub removed: usize,
+    /// relations which failed to re-assemble and kept their boundary
+    pub kept: usize,
+}
+
+fn admin_level(obj: &OsmObj) -> Option<u8> {
+    get_admin(obj)?.tags.get("admin_level")?.parse().ok()
+}
+
+fn is_source_relation(obj: &OsmObj, admin_levels: &[u8]) -> bool {
+    admin_level(obj).is_some_and(|level| admin_levels.contains(&level))
+}
+
+/// Ways and nodes only contribute geometry, tags are dropped to save space.
+/// Node names are kept for `admin_centre` and `label` members.
+fn strip_tags(mut obj: OsmObj) -> OsmObj {
+    match &mut obj {
+        OsmObj::Node(node) => {
+            let mut tags = Tags::new();
+            if let Some(name) = node.tags.get("name") {
+                tags.insert("name".into(), name.clone());
+            }
+            node.tags = tags;
+        }
+        OsmObj::Way(way) => way.tags = Tags::new(),
+        OsmObj::Relation(_) => {}
+    }
+    obj
+}
+
+/// The objects boundaries on `admin_levels` are assembled from: the
+/// administrative relations, their member ways, the ways' nodes and the
+/// relations' `admin_centre` and `label` nodes.
+pub fn source_objects(objects: &OsmMap, admin_levels: &[u8]) -> OsmMap {
+    let mut source = OsmMap::new();
+    let relations = objects
+        .values()
+        .filter(|obj| is_source_relation(obj, admin_levels))
+        .filter_map(OsmObj::relation);
+    for rel in relations {
+        source.insert(rel.id.into(), rel.clone().into());
+        for id in member_node_ids(rel) {
+            if let Some(node) = objects.get(&id) {
+                source.insert(id, strip_tags(node.clone()));
+            }
+        }
+        let ways = rel
+            .refs
+            .iter()
+            .filter_map(|r| objects.get(&r.member)?.way());
+        for way in ways {
+            source.insert(way.id.into(), strip_tags(way.clone().into()));
+            for id in &way.nodes {
+                if let Some(node) = objects.get(&(*id).into()) {
+                    source.insert((*id).into(), strip_tags(node.clone()));
+                }
+            }
+        }
+    }
+    source
+}
+
+fn member_node_ids(rel: &Relation) -> impl Iterator<Item = OsmId> + '_ {
+    rel.refs
+        .iter()
+        .filter(|r| r.member.is_node() && MEMBER_ROLES.contains(&r.role.as_str()))
+        .map(|r| r.member)
+}
+
+/// Apply changes to the source objects. Ways and nodes which are not part
+/// of the source yet are kept aside, they might be members of new or
+/// modified relations. Returns the ids of all changed source objects.
+fn apply_changes(source: &mut OsmMap, changes: Vec<Change>, admin_levels: &[u8]) -> Vec<OsmId> {
+    let mut changed = vec![];
+    let mut aside = OsmMap::new();
+    for change in changes {
+        match change {
+            Change::Upsert(obj) => {
+                let id = obj.id();
+                if let OsmObj::Relation(_) = obj {
+                    // a relation which is no boundary anymore is removed
+                    if is_source_relation(&obj, admin_levels) {
+                        source.insert(id, obj);
+                        changed.push(id);
+                    } else if source.remove(&id).is_some() {
+                        changed.push(id);
+                    }
+                } else if let Some(current) = source.get_mut(&id) {
+                    *current = strip_tags(obj);
+                    changed.push(id);
+                } else {
+                    aside.insert(id, strip_tags(obj));
+                }
+            }
+            Change::Delete(id) => {
+                aside.remove(&id);
+                if source.remove(&id).is_some() {
+                    changed.push(id);
+                }
+            }
+        }
+    }
+    if aside.is_empty() {
+        return changed;
+    }
+
+    // complete relations and ways with members from the change files
+    let missing_ways: Vec<OsmId> = source
+        .values()
+        .filter_map(OsmObj::relation)
+        .flat_map(|rel| rel.refs.iter().map(|r| r.member))
+        .filter(|id| id.is_way() && !source.contains_key(id))
+        .collect();
+    for id in missing_ways {
+        if let Some(way) = aside.remove(&id) {
+            source.insert(id, way);
+            changed.push(id);
+        }
+    }
+    let missing_nodes: Vec<OsmId> = source
+        .values()
+        .filter_map(OsmObj::way)
+        .flat_map(|way| way.nodes.iter().map(|id| (*id).into()))
+        .chain(
+            source
+                .values()
+                .filter_map(OsmObj::relation)
+                .flat_map(member_node_ids),
+        )
+        .filter(|id| !source.contains_key(id))
+        .collect();
+    for id in missing_nodes {
+        if let Some(node) = aside.remove(&id) {
+            source.insert(id, node);
+        }
+    }
+    changed
+}
+
+/// Relations affected by changes to the given objects: changed relations,
+/// relations with changed members and relations with ways whose nodes have
+/// changed.
+fn affected_relations(source: &OsmMap, changed: &[OsmId]) -> BTreeSet<RelationId> {
+    let mut member_relations: BTreeMap<OsmId, Vec<RelationId>> = BTreeMap::new();
+    let mut node_ways: BTreeMap<OsmId, Vec<OsmId>> = BTreeMap::new();
+    for obj in source.values() {
+        match obj {
+            OsmObj::Relation(rel) => {
+                for r in &rel.refs {
+                    member_relations.entry(r.member).or_default().push(rel.id);
+                }
+            }
+            OsmObj::Way(way) => {
+                for node in &way.nodes {
+                    node_ways
+                        .entry((*node).into())
+                        .or_default()
+                        .push(way.id.into());
+                }
+            }
+            OsmObj::Node(_) => {}
+        }
+    }
+
+    let mut affected = BTreeSet::new();
+    let relations_of = |member: &OsmId| member_relations.get(member).into_iter().flatten();
+    for id in changed {
+        match id {
+            OsmId::Relation(id) => {
+                affected.insert(*id);
+            }
+            OsmId::Way(_) => affected.extend(relations_of(id)),
+            OsmId::Node(_) => {
+                affected.extend(relations_of(id));
+                for way in node_ways.get(id).into_iter().flatten() {
+                    affected.extend(relations_of(way));
+                }
+            }
+        }
+    }
+    affected
+}
+
+/// Relations by the nodes of their member ways. Relations sharing a node
+/// meet at a point of their rings.
+fn node_relations(source: &OsmMap) -> BTreeMap<OsmId, BTreeSet<RelationId>> {
+    let mut relations: BTreeMap<OsmId, BTreeSet<RelationId>> = BTreeMap::new();
+    for rel in source.values().filter_map(OsmObj::relation) {
+        let ways = rel.refs.iter().filter_map(|r| source.get(&r.member)?.way());
+        for way in ways {
+            for node in &way.nodes {
+                relations.entry((*node).into()).or_default().insert(rel.id);
+            }
+        }
+    }
+    relations
+}
+
+/// Other relations sharing a node with any of `relations` in any of the
+/// given states.
+fn touching(
+    relations: &BTreeSet<RelationId>,
+    states: &[&BTreeMap<OsmId, BTreeSet<RelationId>>],
+) -> BTreeSet<RelationId> {
+    states
+        .iter()
+        .flat_map(|state| state.values())
+        .filter(|node_relations| !node_relations.is_disjoint(relations))
+        .flatten()
+        .filter(|id| !relations.contains(id))
+        .copied()
+        .collect()
+}
+
+/// Apply osm changes to an index built with osm source objects, only
+/// relations affected by the changes are re-assembled. Relations which
+/// fail to re-assemble, e.g. because a modified relation references ways
+/// missing from the source, keep their previous boundary.
+///
+/// Simplified arcs are split where rings meet, in simplified indexes the
+/// boundaries touching an affected relation before or after the changes
+/// are re-simplified as well. Arcs shared with their own neighbours keep
+/// the points those were simplified to.
+pub fn update_index(
+    index: &mut Index,
+    changes: Vec<Change>,
+    report: &mut BuildReport,
+) -> Result<UpdateStats, Box<dyn Error>> {
+    let metadata = &index.metadata;
+    let source = index
+        .osm
+        .as_mut()
+        .ok_or("rtree binary does not contain osm source objects, build it with --updatable")?;
+    let before = metadata.simplify_tolerance.map(|_| node_relations(source));
+    let changed = apply_changes(source, changes, &metadata.admin_levels);
+    *source = source_objects(source, &metadata.admin_levels);
+
+    let affected = affected_relations(source, &changed);
+    let (reassembled, context) = match &before {
+        Some(before) if !affected.is_empty() => {
+            let after = node_relations(source);
+            let mut reassembled = touching(&affected, &[before, &after]);
+            reassembled.extend(&affected);
+            let context = touching(&reassembled, &[&after]);
+            (reassembled, context)
+        }
+        _ => (affected, BTreeSet::new()),
+    };
+
+    let mut boundaries = vec![];
+    let mut previous = BTreeMap::new();
+    for boundary in std::mem::replace(&mut index.tree, RTree::new()) {
+        match boundary.osm_id.map(RelationId) {
+            Some(id) if reassembled.contains(&id) => {
+                previous.insert(id, boundary);
+            }
+            _ => boundaries.push(boundary),
+        }
+    }
+    let replaced = previous.len();
+
+    let mut fresh = vec![];
+    for id in &reassembled {
+        let rel = match source.get(&(*id).into()).and_then(OsmObj::relation) {
+            Some(rel) => rel,
+            None => continue,
+        };
+        let result = assemble(rel, source, &metadata.admin_levels, metadata.validate);
+        match result.and_then(|result| report.add(id.0, result)) {
+            Some(boundary) => fresh.push(boundary),
+            None => boundaries.extend(previous.remove(id)),
+        }
+    }
+    let kept = replaced - previous.len();
+    if let Some(tolerance) = metadata.simplify_tolerance {
+        let stored: HashMap<i64, &Boundary> = boundaries
+            .iter()
+            .filter_map(|boundary| Some((boundary.osm_id?, boundary)))
+            .filter(|(id, _)| context.contains(&RelationId(*id)))
+            .collect();
+        let context: Vec<(Boundary, &Boundary)> = context
+            .iter()
+            .filter_map(|id| {
+                let simplified = stored.get(&id.0)?;
+                let rel = source.get(&(*id).into())?.relation()?;
+                let result = assemble(rel, source, &metadata.admin_levels, metadata.validate);
+                Some((result?.ok()?.0, *simplified))
+            })
+            .collect();
+        simplify_in_context(&mut fresh, &context, tolerance);
+    }
+    boundaries.extend(fresh);
+
+    index.tree = RTree::bulk_load(boundaries);
+    Ok(UpdateStats {
+        changed: changed.len(),
+        reassembled: reassembled.len(),
+        removed: replaced - kept,
+        kept,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boundary::get_boundaries;
+    use crate::simplify::simplify_boundaries;
+    use crate::Metadata;
+    use geo::CoordsIter;
+    use geo_types::Point;
+    use osm_boundaries_utils::osm_builder::{named_node, OsmBuilder};
+    use osmpbfreader::{Node, NodeId, Way, WayId};
+
+    fn updatable_index() -> Index {
+        let mut builder = OsmBuilder::new();
+        let id = builder
+            .relation()
+            .outer(vec![
+                named_node(0.0, 0.0, "a"),
+                named_node(0.0, 1.0, "b"),
+                named_node(1.0, 1.0, "c"),
+                named_node(1.0, 0.0, "d"),
+                named_node(0.0, 0.0, "a"),
+            ])
+            .relation_id;
+        if let Some(OsmObj::Relation(rel)) = builder.objects.get_mut(&id.into()) {
+            rel.tags.insert("boundary".into(), "administrative".into());
+            rel.tags.insert("admin_level".into(), "8".into());
+            rel.tags.insert("name".into(), "square".into());
+        }
+        let levels = [8];
+        let mut report = BuildReport::default();
+        let boundaries = get_boundaries(&builder.objects, &levels, false, &mut report);
+        Index {
+            metadata: Metadata {
+                admin_levels: levels.to_vec(),
+                ..Metadata::default()
+            },
+            tree: RTree::bulk_load(boundaries),
+            osm: Some(source_objects(&builder.objects, &levels)),
+        }
+    }
+
+    fn names_at(index: &Index, point: [f64; 2]) -> Vec<String> {
+        let boundaries = index.tree.locate_all_at_point(&point);
+        boundaries
+            .filter(|boundary| boundary.contains(&point))
+            .map(|boundary| boundary.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn moves_nodes() {
+        let mut index = updatable_index();
+        assert!(names_at(&index, [1.4, 0.6]).is_empty());
+
+        // node "c" (id 2) moves east
+        let node = Node {
+            id: NodeId(2),
+            tags: Tags::new(),
+            decimicro_lat: 10_000_000,
+            decimicro_lon: 20_000_000,
+        };
+        let changes = vec![Change::Upsert(node.into())];
+        let stats = update_index(&mut index, changes, &mut BuildReport::default()).unwrap();
+        assert_eq!(stats.changed, 1);
+        assert_eq!(stats.reassembled, 1);
+        assert_eq!(names_at(&index, [1.4, 0.6]), ["square"]);
+    }
+
+    #[test]
+    fn keeps_boundaries_failing_to_reassemble() {
+        let mut index = updatable_index();
+        // way 0 is no ring without a node which is not part of the changes
+        let way = Way {
+            id: WayId(0),
+            tags: Tags::new(),
+            nodes: vec![0, 1, 2, 3, 99].into_iter().map(NodeId).collect(),
+        };
+        let changes = vec![Change::Upsert(way.into())];
+        let mut report = BuildReport::default();
+        let stats = update_index(&mut index, changes, &mut report).unwrap();
+        assert_eq!(stats.kept, 1);
+        assert_eq!(stats.removed, 0);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(names_at(&index, [0.5, 0.5]), ["square"]);
+    }
+
+    fn square(builder: &mut OsmBuilder, nodes: Vec<(Point<f64>, Option<String>)>, name: &str) {
+        let id = builder.relation().outer(nodes).relation_id;
+        if let Some(OsmObj::Relation(rel)) = builder.objects.get_mut(&id.into()) {
+            rel.tags.insert("boundary".into(), "administrative".into());
+            rel.tags.insert("admin_level".into(), "8".into());
+            rel.tags.insert("name".into(), name.into());
+        }
+    }
+
+    fn border(index: &Index, name: &str) -> Vec<(i64, i64)> {
+        let boundary = index.tree.iter().find(|b| b.name == name).unwrap();
+        let mut border: Vec<(i64, i64)> = boundary
+            .mp
+            .exterior_coords_iter()
+            .filter(|coord| (coord.x - 0.01).abs() < 1e-3)
+            .map(|coord| ((coord.x * 1e7) as i64, (coord.y * 1e7) as i64))
+            .collect();
+        border.sort_unstable();
+        border.dedup();
+        border
+    }
+
+    #[test]
+    fn resimplifies_touching_boundaries() {
+        // left and right share a border wiggling by ~10m, far is apart
+        let mut builder = OsmBuilder::new();
+        let wiggle = || {
+            vec![
+                named_node(0.01, 0., "s"),
+                named_node(0.0101, 0.002, "w1"),
+                named_node(0.0099, 0.005, "w2"),
+                named_node(0.01, 0.008, "w3"),
+                named_node(0.01, 0.01, "n"),
+            ]
+        };
+        let mut left = vec![named_node(0., 0., "sw")];
+        left.extend(wiggle());
+        left.extend(vec![named_node(0., 0.01, "nw"), named_node(0., 0., "sw")]);
+        square(&mut builder, left, "left");
+        let mut right = wiggle();
+        right.reverse();
+        right.extend(vec![
+            named_node(0.02, 0., "se"),
+            named_node(0.02, 0.01, "ne"),
+            named_node(0.01, 0.01, "n"),
+        ]);
+        square(&mut builder, right, "right");
+        let far = vec![
+            named_node(1., 1., "f1"),
+            named_node(1., 1.01, "f2"),
+            named_node(1.01, 1.01, "f3"),
+            named_node(1.01, 1., "f4"),
+            named_node(1., 1., "f1"),
+        ];
+        square(&mut builder, far, "far");
+
+        let levels = [8];
+        let mut boundaries = get_boundaries(
+            &builder.objects,
+            &levels,
+            false,
+            &mut BuildReport::default(),
+        );
+        simplify_boundaries(&mut boundaries, 50.);
+        let mut index = Index {
+            metadata: Metadata {
+                admin_levels: levels.to_vec(),
+                simplify_tolerance: Some(50.),
+                ..Metadata::default()
+            },
+            tree: RTree::bulk_load(boundaries),
+            osm: Some(source_objects(&builder.objects, &levels)),
+        };
+        let simplified = border(&index, "left");
+        assert_eq!(simplified.len(), 2);
+        assert_eq!(border(&index, "right"), simplified);
+
+        // node "nw" (id 6) of left moves west
+        let node = Node {
+            id: NodeId(6),
+            tags: Tags::new(),
+            decimicro_lat: 100_000,
+            decimicro_lon: -10_000,
+        };
+        let changes = vec![Change::Upsert(node.into())];
+        let stats = update_index(&mut index, changes, &mut BuildReport::default()).unwrap();
+        assert_eq!(stats.reassembled, 2);
+        assert_eq!(border(&index, "left"), simplified);
+        assert_eq!(border(&index, "right"), simplified);
+        assert_eq!(names_at(&index, [-0.0005, 0.0099]), ["left"]);
+    }
+
+    #[test]
+    fn removes_deleted_relations() {
+        let mut index = updatable_index();
+        let changes = vec![
+            Change::Delete(RelationId(0).into()),
+            Change::Delete(WayId(0).into()),
+        ];
+        let stats = update_index(&mut index, changes, &mut BuildReport::default()).unwrap();
+        assert_eq!(stats.removed, 1);
+        assert_eq!(index.tree.size(), 0);
+        assert!(index.osm.unwrap().is_empty());
+    }
+
+    #[test]
+    fn requires_source_objects() {
+        let mut index = updatable_index();
+        index.osm = None;
+        let result = update_index(&mut index, vec![], &mut BuildReport::default());
+        assert!(result.is_err());
+    }
+}
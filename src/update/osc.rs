@@ -0,0 +1,221 @@
+use flate2::read::GzDecoder;
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// an object was created or modified
+    Upsert(OsmObj),
+    Delete(OsmId),
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn attributes(element: &BytesStart) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut attributes = HashMap::new();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+        attributes.insert(key, attribute.unescape_value()?.into_owned());
+    }
+    Ok(attributes)
+}
+
+fn attribute<T: std::str::FromStr>(
+    attributes: &HashMap<String, String>,
+    key: &str,
+) -> Result<T, io::Error> {
+    attributes
+        .get(key)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid_data(format!("missing or invalid attribute {}", key)))
+}
+
+fn decimicro(attributes: &HashMap<String, String>, key: &str) -> Result<i32, io::Error> {
+    // deleted nodes come without coordinates
+    match attributes.get(key) {
+        Some(_) => Ok((attribute::<f64>(attributes, key)? * 1e7).round() as i32),
+        None => Ok(0),
+    }
+}
+
+fn start_object(name: &[u8], element: &BytesStart) -> Result<Option<OsmObj>, Box<dyn Error>> {
+    let attributes = attributes(element)?;
+    let obj = match name {
+        b"node" => OsmObj::Node(Node {
+            id: NodeId(attribute(&attributes, "id")?),
+            tags: Tags::new(),
+            decimicro_lat: decimicro(&attributes, "lat")?,
+            decimicro_lon: decimicro(&attributes, "lon")?,
+        }),
+        b"way" => OsmObj::Way(Way {
+            id: WayId(attribute(&attributes, "id")?),
+            tags: Tags::new(),
+            nodes: vec![],
+        }),
+        b"relation" => OsmObj::Relation(Relation {
+            id: RelationId(attribute(&attributes, "id")?),
+            tags: Tags::new(),
+            refs: vec![],
+        }),
+        _ => return Ok(None),
+    };
+    Ok(Some(obj))
+}
+
+fn add_child(obj: &mut OsmObj, name: &[u8], element: &BytesStart) -> Result<(), Box<dyn Error>> {
+    let attributes = attributes(element)?;
+    match (name, obj) {
+        (b"tag", obj) => {
+            let key: String = attribute(&attributes, "k")?;
+            let value: String = attribute(&attributes, "v")?;
+            let tags = match obj {
+                OsmObj::Node(node) => &mut node.tags,
+                OsmObj::Way(way) => &mut way.tags,
+                OsmObj::Relation(rel) => &mut rel.tags,
+            };
+            tags.insert(key.into(), value.into());
+        }
+        (b"nd", OsmObj::Way(way)) => way.nodes.push(NodeId(attribute(&attributes, "ref")?)),
+        (b"member", OsmObj::Relation(rel)) => {
+            let id = attribute(&attributes, "ref")?;
+            let member = match attributes.get("type").map(String::as_str) {
+                Some("node") => NodeId(id).into(),
+                Some("way") => WayId(id).into(),
+                Some("relation") => RelationId(id).into(),
+                other => {
+                    return Err(invalid_data(format!("invalid member type {:?}", other)).into())
+                }
+            };
+            let role: String = attributes.get("role").cloned().unwrap_or_default();
+            rel.refs.push(Ref {
+                member,
+                role: role.into(),
+            });
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Parse an osmChange document into changes in document order.
+pub fn parse<R: BufRead>(reader: R) -> Result<Vec<Change>, Box<dyn Error>> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = vec![];
+    let mut changes = vec![];
+    let mut delete = false;
+    let mut current: Option<OsmObj> = None;
+
+    let mut finish = |obj: OsmObj, delete: bool| {
+        changes.push(match delete {
+            true => Change::Delete(obj.id()),
+            false => Change::Upsert(obj),
+        })
+    };
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(element) => {
+                let name = element.name();
+                match name.as_ref() {
+                    b"create" | b"modify" => delete = false,
+                    b"delete" => delete = true,
+                    name => match current.as_mut() {
+                        Some(obj) => add_child(obj, name, &element)?,
+                        None => current = start_object(name, &element)?,
+                    },
+                }
+            }
+            Event::Empty(element) => {
+                let name = element.name();
+                match current.as_mut() {
+                    Some(obj) => add_child(obj, name.as_ref(), &element)?,
+                    None => {
+                        if let Some(obj) = start_object(name.as_ref(), &element)? {
+                            finish(obj, delete);
+                        }
+                    }
+                }
+            }
+            Event::End(element) => {
+                if let b"node" | b"way" | b"relation" = element.name().as_ref() {
+                    if let Some(obj) = current.take() {
+                        finish(obj, delete);
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(changes)
+}
+
+/// Read an `.osc` or gzip compressed `.osc.gz` change file.
+pub fn read_changes(path: &Path) -> Result<Vec<Change>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let gzip = path.extension().is_some_and(|ext| ext == "gz");
+    match gzip {
+        true => parse(BufReader::new(GzDecoder::new(file))),
+        false => parse(BufReader::new(file)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_change_file() {
+        let osc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+  <modify>
+    <node id="1" version="2" lat="53.1" lon="8.8"/>
+    <way id="2" version="3">
+      <nd ref="1"/>
+      <nd ref="3"/>
+    </way>
+  </modify>
+  <create>
+    <relation id="4" version="1">
+      <member type="way" ref="2" role="outer"/>
+      <tag k="boundary" v="administrative"/>
+      <tag k="name" v="Bürgerpark &amp; Umgebung"/>
+    </relation>
+  </create>
+  <delete>
+    <node id="5" version="4"/>
+  </delete>
+</osmChange>"#;
+        let changes = parse(osc.as_bytes()).unwrap();
+        assert_eq!(changes.len(), 4);
+        match &changes[0] {
+            Change::Upsert(OsmObj::Node(node)) => {
+                assert_eq!(node.decimicro_lat, 531_000_000);
+                assert_eq!(node.decimicro_lon, 88_000_000);
+            }
+            other => panic!("unexpected change {:?}", other),
+        }
+        match &changes[1] {
+            Change::Upsert(OsmObj::Way(way)) => assert_eq!(way.nodes, [NodeId(1), NodeId(3)]),
+            other => panic!("unexpected change {:?}", other),
+        }
+        match &changes[2] {
+            Change::Upsert(OsmObj::Relation(rel)) => {
+                assert_eq!(rel.refs[0].member, WayId(2).into());
+                assert_eq!(rel.refs[0].role, "outer");
+                assert_eq!(rel.tags.get("name").unwrap(), "Bürgerpark & Umgebung");
+            }
+            other => panic!("unexpected change {:?}", other),
+        }
+        assert_eq!(changes[3], Change::Delete(NodeId(5).into()));
+    }
+}
@@ -0,0 +1,297 @@
+use geo::algorithm::area::Area;
+use geo::algorithm::bool_ops::BooleanOps;
+use geo::algorithm::intersects::Intersects;
+use geo::algorithm::kernels::{Kernel, Orientation, RobustKernel};
+use geo::algorithm::orient::{Direction, Orient};
+use geo::algorithm::remove_repeated_points::RemoveRepeatedPoints;
+use geo::algorithm::winding_order::Winding;
+use geo_types::{Coord, LineString, MultiPolygon, Point, Polygon};
+use osmpbfreader::{NodeId, OsmId, OsmObj, Relation};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Relative difference between the area of the input and the overlay result
+/// above which the geometry is considered invalid. The overlay snaps to an
+/// integer grid, so results are never exactly equal.
+const AREA_TOLERANCE: f64 = 1e-6;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Repair {
+    ClosedRing,
+    RemovedRepeatedPoints,
+    RemovedDegenerateRing,
+    FixedOrientation,
+    ResolvedSelfIntersection,
+}
+
+/// Repair a multipolygon: repeated points and degenerate rings are removed,
+/// exteriors are oriented counter-clockwise and interiors clockwise.
+/// Self-intersections and overlapping parts are resolved by overlaying the
+/// geometry with itself, the equivalent of buffering by zero.
+pub fn repair(mp: MultiPolygon<f64>) -> (MultiPolygon<f64>, Vec<Repair>) {
+    let mut repairs = vec![];
+    let polygons =
+        mp.0.into_iter()
+            .filter_map(|polygon| clean_polygon(polygon, &mut repairs))
+            .collect();
+    let mut mp = MultiPolygon(polygons);
+
+    let expected: f64 = mp.0.iter().map(polygon_area).sum();
+    let resolved = overlay(&mp);
+    let actual = resolved.unsigned_area();
+    if (actual - expected).abs() > AREA_TOLERANCE * expected.max(actual) {
+        repairs.push(Repair::ResolvedSelfIntersection);
+        mp = resolved.orient(Direction::Default);
+    }
+
+    repairs.sort();
+    repairs.dedup();
+    (mp, repairs)
+}
+
+fn clean_polygon(polygon: Polygon<f64>, repairs: &mut Vec<Repair>) -> Option<Polygon<f64>> {
+    let (exterior, interiors) = polygon.into_inner();
+    let exterior = orient_ring(clean_ring(exterior, repairs)?, false, repairs);
+    let interiors = interiors
+        .into_iter()
+        .filter_map(|ring| Some(orient_ring(clean_ring(ring, repairs)?, true, repairs)))
+        .collect();
+    Some(Polygon::new(exterior, interiors))
+}
+
+fn clean_ring(mut ring: LineString<f64>, repairs: &mut Vec<Repair>) -> Option<LineString<f64>> {
+    if !ring.is_closed() {
+        ring.close();
+        repairs.push(Repair::ClosedRing);
+    }
+    let cleaned = ring.remove_repeated_points();
+    if cleaned.0.len() != ring.0.len() {
+        repairs.push(Repair::RemovedRepeatedPoints);
+    }
+    if is_degenerate(&cleaned) {
+        repairs.push(Repair::RemovedDegenerateRing);
+        return None;
+    }
+    Some(cleaned)
+}
+
+/// A ring is degenerate if it has less than 3 distinct points or if all of
+/// them are on a line.
+fn is_degenerate(ring: &LineString<f64>) -> bool {
+    if ring.0.len() < 4 {
+        return true;
+    }
+    let first = ring.0[0];
+    let second = ring.0[1];
+    ring.0
+        .iter()
+        .all(|coord| RobustKernel::orient2d(first, second, *coord) == Orientation::Collinear)
+}
+
+fn orient_ring(
+    mut ring: LineString<f64>,
+    clockwise: bool,
+    repairs: &mut Vec<Repair>,
+) -> LineString<f64> {
+    if ring.is_cw() != clockwise {
+        ring.0.reverse();
+        repairs.push(Repair::FixedOrientation);
+    }
+    ring
+}
+
+fn ring_area(ring: &LineString<f64>) -> f64 {
+    let twice_area: f64 = ring.lines().map(|line| line.determinant()).sum();
+    (twice_area / 2.).abs()
+}
+
+fn polygon_area(polygon: &Polygon<f64>) -> f64 {
+    let holes: f64 = polygon.interiors().iter().map(ring_area).sum();
+    ring_area(polygon.exterior()) - holes
+}
+
+/// Overlay each polygon with itself and union the results, so neither
+/// self-intersections nor overlaps between parts remain.
+fn overlay(mp: &MultiPolygon<f64>) -> MultiPolygon<f64> {
+    let empty = MultiPolygon::<f64>(vec![]);
+    mp.0.iter()
+        .map(|polygon| polygon.union(&empty))
+        .reduce(|acc, mp| acc.union(&mp))
+        .unwrap_or(empty)
+}
+
+/// Fallback assembly for relations `build_boundary` gives up on: chains of
+/// member ways which don't end where they start are closed by connecting
+/// their ends.
+pub fn assemble_closing_rings(
+    rel: &Relation,
+    objects: &BTreeMap<OsmId, OsmObj>,
+) -> Option<(MultiPolygon<f64>, Vec<Repair>)> {
+    let mut repairs = vec![];
+    let mut rings = |roles: &[&str]| -> Vec<LineString<f64>> {
+        let ways = rel
+            .refs
+            .iter()
+            .filter(|r| roles.contains(&r.role.as_str()))
+            .filter_map(|r| objects.get(&r.member)?.way())
+            .filter(|way| !way.nodes.is_empty())
+            .map(|way| way.nodes.clone())
+            .collect();
+        join_chains(ways)
+            .into_iter()
+            .filter_map(|mut chain| {
+                if chain.first() != chain.last() {
+                    chain.push(chain[0]);
+                    repairs.push(Repair::ClosedRing);
+                }
+                let coords: Vec<Coord<f64>> = chain
+                    .iter()
+                    .filter_map(|id| objects.get(&OsmId::Node(*id))?.node())
+                    .map(|node| Coord {
+                        x: node.lon(),
+                        y: node.lat(),
+                    })
+                    .collect();
+                if coords.len() < 4 {
+                    return None;
+                }
+                Some(LineString(coords))
+            })
+            .collect()
+    };
+    let outers = rings(&["outer", "enclave", ""]);
+    let inners = rings(&["inner"]);
+
+    let mut polygons: Vec<Polygon<f64>> = outers
+        .into_iter()
+        .map(|ring| Polygon::new(ring, vec![]))
+        .collect();
+    for inner in inners {
+        let point = Point::from(inner.0[0]);
+        if let Some(polygon) = polygons.iter_mut().find(|p| p.intersects(&point)) {
+            polygon.interiors_push(inner);
+        }
+    }
+    if polygons.is_empty() {
+        return None;
+    }
+    repairs.dedup();
+    Some((MultiPolygon(polygons), repairs))
+}
+
+/// Join node sequences sharing end points into chains, reversing them as
+/// needed.
+fn join_chains(mut ways: Vec<Vec<NodeId>>) -> Vec<Vec<NodeId>> {
+    let mut chains = vec![];
+    while let Some(mut chain) = ways.pop() {
+        while chain.len() < 2 || chain.first() != chain.last() {
+            let first = chain[0];
+            let last = chain[chain.len() - 1];
+            let touches = |way: &Vec<NodeId>| {
+                let (start, end) = (way[0], way[way.len() - 1]);
+                start == last || end == last || start == first || end == first
+            };
+            let mut way = match ways.iter().position(touches) {
+                Some(i) => ways.swap_remove(i),
+                None => break,
+            };
+            if way[0] == last || way[way.len() - 1] == last {
+                if way[0] != last {
+                    way.reverse();
+                }
+                chain.extend(way.drain(1..));
+            } else {
+                if way[0] == first {
+                    way.reverse();
+                }
+                way.extend(chain.drain(1..));
+                chain = way;
+            }
+        }
+        chains.push(chain);
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use osm_boundaries_utils::osm_builder::{named_node, OsmBuilder};
+
+    #[test]
+    fn resolves_bowtie() {
+        let bowtie: MultiPolygon<f64> = polygon![
+            (x: 0., y: 0.),
+            (x: 2., y: 2.),
+            (x: 2., y: 0.),
+            (x: 0., y: 2.),
+            (x: 0., y: 0.),
+        ]
+        .into();
+        let (mp, repairs) = repair(bowtie);
+        assert!(repairs.contains(&Repair::ResolvedSelfIntersection));
+        assert_eq!(mp.0.len(), 2);
+        assert!((mp.unsigned_area() - 2.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn merges_overlapping_parts() {
+        let mp = MultiPolygon(vec![
+            polygon![(x: 0., y: 0.), (x: 2., y: 0.), (x: 2., y: 2.), (x: 0., y: 2.)],
+            polygon![(x: 1., y: 1.), (x: 3., y: 1.), (x: 3., y: 3.), (x: 1., y: 3.)],
+        ]);
+        let (mp, repairs) = repair(mp);
+        assert_eq!(repairs, [Repair::ResolvedSelfIntersection]);
+        assert_eq!(mp.0.len(), 1);
+        assert!((mp.unsigned_area() - 7.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cleans_rings() {
+        let polygon = Polygon::new(
+            LineString::from(vec![(0., 0.), (0., 2.), (0., 2.), (2., 2.), (2., 0.)]),
+            vec![LineString::from(vec![(1., 1.), (1.5, 1.), (1.8, 1.)])],
+        );
+        let (mp, repairs) = repair(polygon.into());
+        assert_eq!(
+            repairs,
+            [
+                Repair::RemovedRepeatedPoints,
+                Repair::RemovedDegenerateRing,
+                Repair::FixedOrientation
+            ]
+        );
+        assert_eq!(mp.0[0].exterior().0.len(), 5);
+        assert!(mp.0[0].interiors().is_empty());
+        assert!(mp.0[0].exterior().is_ccw());
+    }
+
+    #[test]
+    fn keeps_valid_geometries() {
+        let square: MultiPolygon<f64> =
+            polygon![(x: 0., y: 0.), (x: 1., y: 0.), (x: 1., y: 1.), (x: 0., y: 1.)].into();
+        let (mp, repairs) = repair(square.clone());
+        assert!(repairs.is_empty());
+        assert_eq!(mp, square);
+    }
+
+    #[test]
+    fn closes_open_chains() {
+        let mut builder = OsmBuilder::new();
+        let id = builder
+            .relation()
+            .outer(vec![named_node(0., 0., "a"), named_node(0., 1., "b")])
+            .outer(vec![named_node(1., 1., "c"), named_node(0., 1., "b")])
+            .relation_id;
+        let rel = match &builder.objects[&id.into()] {
+            OsmObj::Relation(rel) => rel.clone(),
+            _ => unreachable!(),
+        };
+        let (mp, repairs) = assemble_closing_rings(&rel, &builder.objects).unwrap();
+        assert_eq!(repairs, [Repair::ClosedRing]);
+        assert_eq!(mp.0.len(), 1);
+        assert_eq!(mp.0[0].exterior().0.len(), 4);
+    }
+}
@@ -1,13 +1,18 @@
 use actix_web::{test, web, App};
-use osm_admin_lookup::build_rtree;
-use osm_admin_lookup::service::{locate, LocateResponse};
+use osm_admin_lookup::geojson::write_geojson;
+use osm_admin_lookup::query::OverlapResponse;
+use osm_admin_lookup::service::{
+    boundaries_in_bbox, boundary_by_id, intersect, locate, overlap_fractions, route_crossings,
+    BoundaryDetails, LocateResponse, RouteResponse,
+};
+use osm_admin_lookup::{build_rtree, Tree};
 use std::sync::Arc;
 
 #[tokio::test]
 async fn locate_400() {
     let path = "./tests/data/schwachhausen.pbf";
     let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
-    let state = Arc::new(rtree);
+    let state = Arc::new(Tree::from(rtree));
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(state.clone()))
@@ -24,7 +29,7 @@ async fn locate_400() {
 async fn locate_hit() {
     let path = "./tests/data/schwachhausen.pbf";
     let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
-    let state = Arc::new(rtree);
+    let state = Arc::new(Tree::from(rtree));
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(state.clone()))
@@ -40,11 +45,47 @@ async fn locate_hit() {
     assert_eq!(res.boundaries[0].level, 10);
 }
 
+#[tokio::test]
+async fn locate_notations() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(locate),
+    )
+    .await;
+    let queries = [
+        "loc=53.089,8.822&order=latlng",
+        "loc=8.822%2053.089",
+        "loc=geo:53.089,8.822",
+        "loc=POINT(8.822%2053.089)",
+        "loc=53%C2%B005%2720%22N%208%C2%B049%2719%22E",
+    ];
+    for query in queries {
+        let req = test::TestRequest::get()
+            .uri(&format!("/locate?{}", query))
+            .to_request();
+        let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.boundaries.len(), 1, "{}", query);
+        assert_eq!(res.boundaries[0].name, "Schwachhausen");
+    }
+
+    for query in ["loc=8.822,53.089&order=xy", "loc=5213405"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/locate?{}", query))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400, "{}", query);
+    }
+}
+
 #[tokio::test]
 async fn locate_miss() {
     let path = "./tests/data/schwachhausen.pbf";
     let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
-    let state = Arc::new(rtree);
+    let state = Arc::new(Tree::from(rtree));
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(state.clone()))
@@ -55,3 +96,221 @@ async fn locate_miss() {
     let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
     assert_eq!(res.boundaries.len(), 0);
 }
+
+#[tokio::test]
+async fn boundary_details() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(locate)
+            .service(boundary_by_id),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/locate?loc=8.822,53.089")
+        .to_request();
+    let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+    let id = res.boundaries[0].id.expect("missing osm id");
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/boundaries/{}", id))
+        .to_request();
+    let res: BoundaryDetails = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.name, "Schwachhausen");
+    assert!(
+        res.area_km2 > 1. && res.area_km2 < 10.,
+        "area {}",
+        res.area_km2
+    );
+    let [lng, lat] = res.pole;
+    assert!((8.8..8.9).contains(&lng) && (53.0..53.2).contains(&lat));
+    let label = res.label.expect("missing label member");
+    assert_eq!(label.name.as_deref(), Some("Schwachhausen"));
+
+    let req = test::TestRequest::get().uri("/boundaries/1").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn region_queries() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(boundaries_in_bbox)
+            .service(intersect),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/boundaries?bbox=8.82,53.08,8.83,53.09")
+        .to_request();
+    let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.boundaries.len(), 1);
+    assert_eq!(res.boundaries[0].name, "Schwachhausen");
+
+    let req = test::TestRequest::get()
+        .uri("/boundaries?bbox=8.82,53.08,8.83,53.09&mode=within")
+        .to_request();
+    let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(res.boundaries.is_empty());
+
+    for bbox in [
+        "8.82,53.08,8.83",
+        "8.82,53.08,8.83,95",
+        "8.83,53.08,8.82,53.09",
+    ] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/boundaries?bbox={}", bbox))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400, "{}", bbox);
+    }
+
+    let body = r#"{"type": "Point", "coordinates": [8.822, 53.089]}"#;
+    let req = test::TestRequest::post()
+        .uri("/intersect?mode=contains&levels=10")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.boundaries.len(), 1);
+
+    let req = test::TestRequest::post()
+        .uri("/intersect?levels=8")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let res: LocateResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(res.boundaries.is_empty());
+}
+
+#[tokio::test]
+async fn overlap_fractions_of_polygon() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(overlap_fractions),
+    )
+    .await;
+    let body = r#"{"type": "Polygon", "coordinates": [[
+        [8.80, 53.08], [8.83, 53.08], [8.83, 53.10], [8.80, 53.10], [8.80, 53.08]
+    ]]}"#;
+    let req = test::TestRequest::post()
+        .uri("/overlap")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let res: OverlapResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.overlaps.len(), 1);
+    let overlap = &res.overlaps[0];
+    assert_eq!(overlap.name, "Schwachhausen");
+    assert!(overlap.fraction > 0.3 && overlap.fraction < 0.4);
+    assert!((overlap.area_km2 / res.area_km2 - overlap.fraction).abs() < 1e-9);
+
+    let body = r#"{"type": "Point", "coordinates": [8.822, 53.089]}"#;
+    let req = test::TestRequest::post()
+        .uri("/overlap")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn route_through_boundary() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(route_crossings),
+    )
+    .await;
+    let line: geo_types::LineString<f64> = vec![(8.80, 53.089), (8.90, 53.089)].into();
+    let encoded = polyline::encode_coordinates(line.coords().copied(), 5).unwrap();
+    let bodies = [
+        r#"{"type": "LineString", "coordinates": [[8.80, 53.089], [8.90, 53.089]]}"#.to_string(),
+        format!(r#"{{"polyline": "{}"}}"#, encoded.replace('\\', "\\\\")),
+    ];
+    for body in bodies {
+        let req = test::TestRequest::post()
+            .uri("/route")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        let res: RouteResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.levels.len(), 1);
+        let visits = &res.levels[0].visits;
+        assert_eq!(visits.len(), 1);
+        assert_eq!(visits[0].name, "Schwachhausen");
+        let (entry, exit) = (visits[0].entry.unwrap(), visits[0].exit.unwrap());
+        assert!(entry[0] < exit[0]);
+        assert!(visits[0].distance_m > 1_000. && visits[0].distance_m < res.distance_m);
+    }
+}
+
+#[tokio::test]
+async fn route_larger_than_default_json_limit() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let state = Arc::new(Tree::from(rtree));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .app_data(web::JsonConfig::default().limit(1024 * 1024))
+            .service(route_crossings),
+    )
+    .await;
+    let coordinates: Vec<String> = (0..=5000)
+        .map(|i| format!("[{:.5}, 53.089]", 8.80 + i as f64 * 0.00002))
+        .collect();
+    let body = format!(
+        r#"{{"type": "LineString", "coordinates": [{}]}}"#,
+        coordinates.join(", ")
+    );
+    assert!(body.len() > 32 * 1024);
+    let req = test::TestRequest::post()
+        .uri("/route")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let res: RouteResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.levels[0].visits.len(), 1);
+    assert_eq!(res.levels[0].visits[0].name, "Schwachhausen");
+}
+
+#[tokio::test]
+async fn geojson_features() {
+    let path = "./tests/data/schwachhausen.pbf";
+    let rtree = build_rtree(path.into(), &[10]).expect("could not build rtree");
+    let boundaries = osm_admin_lookup::boundaries(&"8.822,53.089".parse().unwrap(), &rtree);
+    let mut out = vec![];
+    let query = geo_types::Point::new(8.822, 53.089);
+    write_geojson(&mut out, boundaries, &[query]).unwrap();
+    let collection: geojson::FeatureCollection = String::from_utf8(out).unwrap().parse().unwrap();
+    assert_eq!(collection.features.len(), 2);
+
+    let boundary = &collection.features[0];
+    assert_eq!(boundary.property("name").unwrap(), "Schwachhausen");
+    assert_eq!(boundary.property("id").unwrap(), 1130741);
+    assert_eq!(boundary.property("admin_level").unwrap(), 10);
+    let tags = boundary.property("tags").unwrap();
+    assert_eq!(tags["boundary"], "administrative");
+    assert!(tags.get("name").is_none());
+
+    let point = &collection.features[1];
+    assert_eq!(point.property("query").unwrap(), true);
+    let geometry = point.geometry.as_ref().unwrap();
+    assert_eq!(geometry.value, geojson::Value::Point(vec![8.822, 53.089]));
+}